@@ -0,0 +1,613 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::account::Account;
+use rori::config::{Config, ConfigError, ConfigLoader};
+use rori::daemon::DaemonBackend;
+use rori::dbus_daemon::DBusBackend;
+use rori::device::{DeviceCommand, DeviceUpdate, ANSWER_TIMEOUT_SECS};
+use rori::endpoint::Endpoint;
+use rori::event::{Event, EventBus};
+use rori::handler::RoriMessageHandler;
+use rori::outbox::{Outbox, OUTBOX_FILE};
+use rori::stt::{self, Transcription};
+use rori::trust::{TrustCommand, TrustDecision, TrustRequest, TrustStore};
+use serde::Serialize;
+use serde_json;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The subcommand succeeded (for status: the client is logged)
+pub const EXIT_OK: i32 = 0;
+/// The subcommand failed (for status: the client is not logged)
+pub const EXIT_FAILURE: i32 = 1;
+/// Incorrect arguments
+pub const EXIT_USAGE: i32 = 2;
+/// No configuration, or an invalid one
+pub const EXIT_NOT_CONFIGURED: i32 = 3;
+/// The daemon or RORI can't be reached
+pub const EXIT_UNREACHABLE: i32 = 4;
+
+/**
+ * Interface shown to the user
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interface {
+    /// QML window
+    Qml,
+    /// In the terminal
+    Terminal,
+    /// Headless
+    None,
+}
+
+/**
+ * One-shot actions, done without starting the client
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subcommand {
+    /// Send a user entry to RORI
+    Say(String),
+    /// Send an interaction with a custom datatype to RORI
+    Send { datatype: String, body: String },
+    /// List Ring accounts of the daemon
+    AccountsList,
+    /// Register a new username on RORI
+    Register(String),
+    /// Link this device to an existing username
+    Link(String),
+    /// Show if the client is logged to RORI
+    Status,
+    /// Add or remove devices linked to the username
+    Devices(DeviceCommand),
+    /// List, accept or discard trust requests
+    Trust(TrustCommand),
+    /// List messages waiting to be sent
+    OutboxList,
+    /// Cancel a message waiting to be sent
+    OutboxCancel(u64),
+    /// Print the text recognized in a WAV file
+    Transcribe(String),
+    /// Validate the configuration
+    ConfigCheck,
+}
+
+/**
+ * Options given on the command line
+ */
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Interface to show
+    pub ui: Interface,
+    /// Configuration file to use instead of the user one
+    pub config: Option<String>,
+    /// key=value overriding the configuration
+    pub set: Vec<String>,
+    /// RORI server, used to create the configuration
+    pub server: Option<String>,
+    /// Username, used to create the configuration
+    pub username: Option<String>,
+    /// Subcommand to run instead of the client
+    pub command: Option<Subcommand>,
+    /// Print results of subcommands as JSON
+    pub json: bool,
+}
+
+impl Options {
+    /**
+     * Parse command line arguments
+     * @param args without the program name
+     * @return the options, or an error message
+     */
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut options = Options {
+            ui: if cfg!(feature = "qml") { Interface::Qml } else { Interface::None },
+            config: None,
+            set: Vec::new(),
+            server: None,
+            username: None,
+            command: None,
+            json: false,
+        };
+        let mut datatype = None;
+        let mut positional: Vec<String> = Vec::new();
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "--headless" => options.ui = Interface::None,
+                "--ui" => options.ui = Options::interface(&Options::value(&arg, args.next())?)?,
+                "--config" => options.config = Some(Options::value(&arg, args.next())?),
+                "--set" => options.set.push(Options::value(&arg, args.next())?),
+                "--profile" => {
+                    let profile = Options::value(&arg, args.next())?;
+                    options.set.push(format!("profile={}", profile));
+                },
+                "--server" => options.server = Some(Options::value(&arg, args.next())?),
+                "--username" => options.username = Some(Options::value(&arg, args.next())?),
+                "--json" => options.json = true,
+                "--datatype" => datatype = Some(Options::value(&arg, args.next())?),
+                _ if arg.starts_with("--") => return Err(format!("Unknown argument: {}", arg)),
+                _ => positional.push(arg)
+            }
+        }
+        options.command = Options::subcommand(&positional, datatype)?;
+        Ok(options)
+    }
+
+    fn value(arg: &str, value: Option<String>) -> Result<String, String> {
+        value.ok_or(format!("Missing value for {}", arg))
+    }
+
+    fn interface(name: &str) -> Result<Interface, String> {
+        match name {
+            "qml" if cfg!(feature = "qml") => Ok(Interface::Qml),
+            "qml" => Err(String::from("Built without the QML interface")),
+            "tui" => Ok(Interface::Terminal),
+            "none" => Ok(Interface::None),
+            _ => Err(format!("Unknown interface: {}", name))
+        }
+    }
+
+    fn subcommand(positional: &[String], datatype: Option<String>) -> Result<Option<Subcommand>, String> {
+        if positional.is_empty() {
+            return match datatype {
+                Some(_) => Err(String::from("--datatype is only used by send")),
+                None => Ok(None)
+            };
+        }
+        let rest = positional[1..].join(" ");
+        let command = match (&*positional[0], positional.len()) {
+            ("say", len) if len > 1 => Subcommand::Say(rest),
+            ("send", len) if len > 1 => Subcommand::Send {
+                datatype: datatype.clone().unwrap_or(String::from("text/plain")),
+                body: rest,
+            },
+            ("accounts", 2) if positional[1] == "list" => Subcommand::AccountsList,
+            ("register", 2) => Subcommand::Register(rest),
+            ("link", 2) => Subcommand::Link(rest),
+            ("status", 1) => Subcommand::Status,
+            ("devices", _) => match DeviceCommand::parse(&format!("/devices {}", rest)) {
+                Some(Ok(command)) => Subcommand::Devices(command),
+                _ => return Err(format!("Incorrect command: {}", positional.join(" ")))
+            },
+            ("outbox", 1) => Subcommand::OutboxList,
+            ("outbox", 2) if positional[1] == "list" => Subcommand::OutboxList,
+            ("outbox", 3) if positional[1] == "cancel" => match positional[2].parse::<u64>() {
+                Ok(id) => Subcommand::OutboxCancel(id),
+                Err(_) => return Err(format!("Invalid message id: {}", positional[2]))
+            },
+            ("trust", _) => match TrustCommand::parse(&format!("/trust {}", rest)) {
+                Some(Ok(command)) => Subcommand::Trust(command),
+                _ => return Err(format!("Incorrect command: {}", positional.join(" ")))
+            },
+            ("transcribe", 2) => Subcommand::Transcribe(rest),
+            ("config", 2) if positional[1] == "check" => Subcommand::ConfigCheck,
+            _ => return Err(format!("Incorrect command: {}", positional.join(" ")))
+        };
+        match command {
+            Subcommand::Send { .. } => {},
+            _ if datatype.is_some() => return Err(String::from("--datatype is only used by send")),
+            _ => {}
+        }
+        Ok(Some(command))
+    }
+
+    /**
+     * @return the help message
+     */
+    pub fn usage() -> &'static str {
+        "Usage: rori_linux_client [--ui qml|tui|none] [--headless] [--config FILE] [--profile NAME]\n\
+         \x20                        [--set KEY=VALUE]... [--server ADDRESS --username NAME]\n\
+         \x20      rori_linux_client [--config FILE] [--profile NAME] [--set KEY=VALUE]... [--json] COMMAND\n\
+         \n\
+         --ui qml|tui|none    interface to show (default: qml)\n\
+         --headless           run without interface, same as --ui none\n\
+         --config FILE        configuration file (default: $XDG_CONFIG_HOME/rori/config.json)\n\
+         --profile NAME       use the identity of a profile of the configuration\n\
+         --set KEY=VALUE      override a configuration key (e.g. api.bind=localhost:3001)\n\
+         --server ADDRESS     RORI to connect, to create the configuration\n\
+         --username NAME      username to use, to create the configuration\n\
+         --json               print the result of COMMAND as JSON\n\
+         \n\
+         Commands:\n\
+         say TEXT                       send TEXT to RORI\n\
+         send [--datatype TYPE] BODY    send BODY with the datatype TYPE (default: text/plain)\n\
+         accounts list                  list the Ring accounts of the daemon\n\
+         register NAME                  register NAME on RORI\n\
+         link NAME                      link this device to NAME\n\
+         status                         show if the client is logged\n\
+         devices add [RING_ID]          add a device, or link this one\n\
+         devices remove RING_ID         remove a device\n\
+         trust [list]                   list trust requests\n\
+         trust accept|discard RING_ID   answer a trust request\n\
+         outbox [list]                  list messages waiting to be sent\n\
+         outbox cancel ID               cancel a message waiting to be sent\n\
+         transcribe FILE                print the text recognized in a 16 kHz WAV file\n\
+         config check                   validate the configuration\n\
+         \n\
+         Exit codes: 0 success, 1 failure (or not logged), 2 incorrect arguments,\n\
+         3 no or invalid configuration, 4 daemon or RORI unreachable"
+    }
+}
+
+/**
+ * Result of a sent interaction
+ */
+#[derive(Serialize)]
+struct Sent {
+    datatype: String,
+    body: String,
+    interaction_id: u64,
+}
+
+/**
+ * Result of config check
+ */
+#[derive(Serialize)]
+struct ConfigCheck {
+    sources: Vec<String>,
+    errors: Vec<ConfigError>,
+    config: Option<Config>,
+}
+
+/**
+ * Result of status
+ */
+#[derive(Serialize)]
+struct Status {
+    account: Account,
+    rori_server: String,
+    rori_reachable: bool,
+    registered_name: String,
+    logged: bool,
+}
+
+impl Subcommand {
+    /**
+     * Run the subcommand and print its result
+     * @param self
+     * @param options given on the command line
+     * @return the exit code
+     */
+    pub fn run(&self, options: &Options) -> i32 {
+        let loader = ConfigLoader::standard(options.config.as_ref().map(|c| &**c), &options.set);
+        if *self == Subcommand::ConfigCheck {
+            return Subcommand::check_config(&loader, options.json);
+        }
+        let config = match loader.load() {
+            Ok(config) => config,
+            Err(errors) => {
+                Subcommand::print_errors(&errors);
+                return EXIT_NOT_CONFIGURED;
+            }
+        };
+        match *self {
+            Subcommand::OutboxList | Subcommand::OutboxCancel(_) => {
+                let mut outbox = Outbox::load(&config.data_file(OUTBOX_FILE).to_string_lossy());
+                return Subcommand::outbox(&mut outbox, self, options.json);
+            },
+            Subcommand::Transcribe(ref file) => return Subcommand::transcribe(&config, file, options.json),
+            _ => {}
+        }
+        let backend: Arc<dyn DaemonBackend> = Arc::new(DBusBackend::with_timeout(config.timeouts.daemon_ms));
+        if *self == Subcommand::AccountsList {
+            if let Err(e) = backend.get_account_list() {
+                eprintln!("Cannot reach the daemon: {}", e);
+                return EXIT_UNREACHABLE;
+            }
+            let accounts = Endpoint::get_account_list(&backend);
+            if options.json {
+                Subcommand::print_json(&accounts);
+            } else {
+                for account in accounts {
+                    println!("{}", account);
+                }
+            }
+            return EXIT_OK;
+        }
+
+        if !config.is_configured() {
+            eprintln!("Not configured yet, run the client once");
+            return EXIT_NOT_CONFIGURED;
+        }
+        let rori_server = config.rori_server.clone();
+        let endpoint = match Endpoint::init(backend, &config.ring_id, &rori_server, &config.rori_ring_id) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                eprintln!("{}", e);
+                return EXIT_UNREACHABLE;
+            }
+        };
+
+        match *self {
+            Subcommand::Say(ref text) => {
+                let datatype = if endpoint.is_a_command(text) { "rori/command" } else { "text/plain" };
+                Subcommand::send(&endpoint, datatype, text, options.json)
+            },
+            Subcommand::Send { ref datatype, ref body } => {
+                Subcommand::send(&endpoint, datatype, body, options.json)
+            },
+            Subcommand::Register(ref name) | Subcommand::Link(ref name) => {
+                let register = *self == Subcommand::Register(name.clone());
+                if Endpoint::get_ring_id(&rori_server, &String::from("rori")) == "" {
+                    eprintln!("Cannot connect to RORI at {}", rori_server);
+                    return EXIT_UNREACHABLE;
+                }
+                let registered = Endpoint::get_ring_id(&rori_server, name) != "";
+                if register && registered {
+                    eprintln!("{} is already registered, use link", name);
+                    return EXIT_FAILURE;
+                }
+                if !register && !registered {
+                    eprintln!("{} is not registered, use register", name);
+                    return EXIT_FAILURE;
+                }
+                let order = format!("/{} {}", if register { "register" } else { "link" }, name);
+                Subcommand::send(&endpoint, "rori/command", &order, options.json)
+            },
+            Subcommand::Status => {
+                let rori_reachable = Endpoint::get_ring_id(&rori_server, &String::from("rori")) != "";
+                let registered_name = Endpoint::get_username_from_api(&rori_server, &endpoint.account.ring_id);
+                let status = Status {
+                    logged: registered_name != "" && registered_name == endpoint.account.alias,
+                    account: endpoint.account.clone(),
+                    rori_server: rori_server.clone(),
+                    rori_reachable,
+                    registered_name,
+                };
+                if options.json {
+                    Subcommand::print_json(&status);
+                } else {
+                    println!("Account: {}", status.account);
+                    println!("RORI: {} ({})", status.rori_server,
+                             if status.rori_reachable { "reachable" } else { "unreachable" });
+                    println!("Logged: {}", status.logged);
+                }
+                if !status.rori_reachable {
+                    EXIT_UNREACHABLE
+                } else if status.logged {
+                    EXIT_OK
+                } else {
+                    EXIT_FAILURE
+                }
+            },
+            Subcommand::Devices(ref command) => Subcommand::devices(endpoint, command, options.json),
+            Subcommand::Trust(ref command) => {
+                let mut endpoint = endpoint;
+                let trust_file = match config.prepare_data_dir() {
+                    Ok(dir) => dir.join("trust.json").to_string_lossy().into_owned(),
+                    Err(e) => {
+                        eprintln!("Decisions won't be remembered: {}", e);
+                        String::new()
+                    }
+                };
+                endpoint.set_trust_store(Arc::new(Mutex::new(TrustStore::load(&trust_file))));
+                Subcommand::trust(&endpoint, &config.profile, command, options.json)
+            },
+            Subcommand::AccountsList | Subcommand::ConfigCheck
+                | Subcommand::OutboxList | Subcommand::OutboxCancel(_) | Subcommand::Transcribe(_) => EXIT_OK,
+        }
+    }
+
+    fn outbox(outbox: &mut Outbox, command: &Subcommand, json: bool) -> i32 {
+        if let Subcommand::OutboxCancel(id) = *command {
+            if outbox.remove(id) {
+                return EXIT_OK;
+            }
+            eprintln!("Unknown message: {}", id);
+            return EXIT_FAILURE;
+        }
+        let entries = outbox.list();
+        if json {
+            Subcommand::print_json(&entries);
+        } else {
+            for entry in entries {
+                println!("{}\t{}\t{}", entry.id, entry.datatype, entry.body);
+            }
+        }
+        EXIT_OK
+    }
+
+    fn transcribe(config: &Config, file: &str, json: bool) -> i32 {
+        let recognizer = match stt::recognizer(&config.stt) {
+            Some(recognizer) => recognizer,
+            None => {
+                eprintln!("Speech recognition is disabled, set stt.engine");
+                return EXIT_NOT_CONFIGURED;
+            }
+        };
+        if !Path::new(file).is_file() {
+            eprintln!("No such file: {}", file);
+            return EXIT_USAGE;
+        }
+        match recognizer.transcribe(Path::new(file)) {
+            Ok(text) => {
+                if json {
+                    Subcommand::print_json(&Transcription { text });
+                } else {
+                    println!("{}", text);
+                }
+                EXIT_OK
+            },
+            Err(e) => {
+                eprintln!("Can't transcribe {}: {}", file, e);
+                EXIT_FAILURE
+            }
+        }
+    }
+
+    fn trust(endpoint: &Endpoint, profile: &str, command: &TrustCommand, json: bool) -> i32 {
+        let (from, decision) = match *command {
+            TrustCommand::List => {
+                let requests: Vec<TrustRequest> = endpoint.trust_requests().into_iter()
+                    .map(|from| TrustRequest { profile: String::from(profile), from })
+                    .collect();
+                if json {
+                    Subcommand::print_json(&requests);
+                } else {
+                    for request in requests {
+                        println!("{}", request.from);
+                    }
+                }
+                return EXIT_OK;
+            },
+            TrustCommand::Accept(ref from) => (from, TrustDecision::Accepted),
+            TrustCommand::Discard(ref from) => (from, TrustDecision::Discarded),
+        };
+        if !endpoint.trust_requests().contains(from) {
+            eprintln!("No trust request from {}", from);
+            return EXIT_FAILURE;
+        }
+        match endpoint.answer_trust_request(from, decision) {
+            Ok(_) => EXIT_OK,
+            Err(e) => {
+                eprintln!("{}", e);
+                EXIT_FAILURE
+            }
+        }
+    }
+
+    fn devices(mut endpoint: Endpoint, command: &DeviceCommand, json: bool) -> i32 {
+        let bus = EventBus::new();
+        let events = bus.subscribe();
+        endpoint.register_handler(Box::new(RoriMessageHandler::new(bus.clone())));
+        let mut listener = match endpoint.backend().listen() {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Cannot listen signals from the daemon: {}", e);
+                return EXIT_UNREACHABLE;
+            }
+        };
+        if !endpoint.send_device_command(command) {
+            eprintln!("Cannot send the command to RORI");
+            return EXIT_FAILURE;
+        }
+        if !command.is_confirmed() {
+            // RORI answers with a message, said by the running client
+            let update = DeviceUpdate::Sent(command.to_rori_command(""));
+            if json {
+                Subcommand::print_json(&update);
+            } else {
+                println!("{}", update);
+            }
+            return EXIT_OK;
+        }
+        // Wait for RORI to confirm the link
+        let deadline = Instant::now() + Duration::from_secs(ANSWER_TIMEOUT_SECS);
+        while Instant::now() < deadline {
+            if let Some(signal) = listener.next_signal(100) {
+                endpoint.handle_signal(&signal, &bus);
+            }
+            while let Ok(event) = events.try_recv() {
+                if let Event::DeviceUpdate(_, update) = event {
+                    if json {
+                        Subcommand::print_json(&update);
+                    }
+                    return match update {
+                        DeviceUpdate::Failed(reason) => {
+                            eprintln!("{}", reason);
+                            EXIT_FAILURE
+                        },
+                        _ => {
+                            if !json {
+                                println!("{}", update);
+                            }
+                            EXIT_OK
+                        }
+                    };
+                }
+            }
+        }
+        eprintln!("No answer from RORI");
+        EXIT_UNREACHABLE
+    }
+
+    fn send(endpoint: &Endpoint, datatype: &str, body: &str, json: bool) -> i32 {
+        let interaction_id = endpoint.send_interaction_to_rori(body, datatype);
+        if interaction_id == 0 {
+            eprintln!("Cannot send {} to RORI", datatype);
+            return EXIT_FAILURE;
+        }
+        if json {
+            Subcommand::print_json(&Sent {
+                datatype: String::from(datatype),
+                body: String::from(body),
+                interaction_id,
+            });
+        } else {
+            println!("{}", interaction_id);
+        }
+        EXIT_OK
+    }
+
+    fn print_json<T: Serialize>(value: &T) {
+        println!("{}", serde_json::to_string_pretty(value).unwrap_or(String::new()));
+    }
+
+    fn print_errors(errors: &[ConfigError]) {
+        eprintln!("Invalid configuration:");
+        for error in errors {
+            eprintln!("  {}", error);
+        }
+    }
+
+    fn check_config(loader: &ConfigLoader, json: bool) -> i32 {
+        let result = loader.load();
+        if json {
+            Subcommand::print_json(&ConfigCheck {
+                sources: loader.sources().clone(),
+                errors: result.as_ref().err().cloned().unwrap_or(Vec::new()),
+                config: result.as_ref().ok().cloned(),
+            });
+        } else {
+            for source in loader.sources() {
+                println!("Loaded: {}", source);
+            }
+        }
+        match result {
+            Ok(ref config) if config.is_configured() => {
+                if !json {
+                    println!("Configuration is valid");
+                }
+                EXIT_OK
+            },
+            Ok(_) => {
+                if !json {
+                    println!("Configuration is valid, but no account is configured");
+                }
+                EXIT_NOT_CONFIGURED
+            },
+            Err(ref errors) => {
+                if !json {
+                    Subcommand::print_errors(errors);
+                }
+                EXIT_NOT_CONFIGURED
+            }
+        }
+    }
+}
@@ -28,86 +28,157 @@
 extern crate bodyparser;
 extern crate dbus;
 extern crate env_logger;
+extern crate hyper;
 extern crate iron;
+extern crate libc;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "qml")]
 #[macro_use]
 extern crate qmlrs;
 extern crate reqwest;
 extern crate router;
 extern crate serde;
 extern crate serde_json;
+extern crate signal_hook;
 #[macro_use]
 extern crate serde_derive;
 extern crate time;
 
+pub mod cli;
 pub mod rori;
+#[cfg(feature = "qml")]
 pub mod sharedprop;
+pub mod tui;
 
+use cli::{Interface, Options};
 use rori::api::API;
 use rori::account::Account;
+use rori::alarm::{AlarmRunner, AlarmStore};
+use rori::config::{self, Config, ConfigLoader};
+use rori::daemon::DaemonBackend;
+use rori::dbus_daemon::DBusBackend;
 use rori::endpoint::Endpoint;
-use serde_json::{Value, from_str};
+use rori::endpoints::EndpointSet;
+use rori::event::{Event, EventBus};
+use rori::reload::{self, Reloader};
+use rori::outbox::{Outbox, OUTBOX_FILE};
+use rori::shutdown::{self, ShutdownToken};
+use rori::trust::TrustStore;
+use rori::speech::{Priority, SpeechQueue};
+use rori::stt;
+use rori::tts_cache::CACHE_DIR;
+use serde_json::{Map, Value, from_str};
+#[cfg(feature = "qml")]
 use sharedprop::SharedProp;
+use tui::Tui;
 use std::io::prelude::*;
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::Path;
+use std::env;
+use std::process;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::thread;
 
 /**
- * ConfigFile structure
- * TBD
+ * Load the configuration from every layer. Exit if it's invalid
+ * @param options given on the command line
+ * @return the configuration
  */
-#[derive(Serialize, Deserialize)]
-pub struct ConfigFile {
-    ring_id: String,
-    rori_server: String,
-    rori_ring_id: String,
-    username: String,
+fn load_config(options: &Options) -> Config {
+    match ConfigLoader::standard(options.config.as_ref().map(|c| &**c), &options.set).load() {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("Invalid configuration:");
+            for error in errors {
+                eprintln!("  {}", error);
+            }
+            process::exit(cli::EXIT_NOT_CONFIGURED);
+        }
+    }
 }
 
 /**
- * Generate a config file
+ * Wait for the next user entry
+ * @param events from the EventBus
+ * @param shutdown to stop waiting
+ * @return the entry, None if the client is stopping
  */
-fn create_config_file(rori_text: &Arc<Mutex<String>>, user_text: &Arc<Mutex<String>>) {
+fn next_user_entry(events: &Receiver<Event>, shutdown: &ShutdownToken) -> Option<String> {
+    while !shutdown.is_requested() {
+        match events.recv_timeout(Duration::from_millis(500)) {
+            Ok(Event::UserUtterance(text, _)) => {
+                if text != "" {
+                    return Some(text);
+                }
+            },
+            Err(RecvTimeoutError::Disconnected) => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/**
+ * Generate a config file from the spoken dialogue
+ * @param backend daemon to use
+ * @param speech to say things
+ * @param events to read user entries
+ * @param shutdown to stop waiting
+ * @param config current configuration, to know the active profile
+ * @param path of the config file
+ */
+fn create_config_file(backend: &Arc<dyn DaemonBackend>, speech: &SpeechQueue, events: &Receiver<Event>, shutdown: &ShutdownToken, config: &Config, path: &Path) {
     let to_say = String::from("RORI needs a few things to begin...\nFirst, what is the address of the RORI you want to connect?");
-    Endpoint::mimic(&to_say, &rori_text);
+    speech.push(&to_say, Priority::Chat);
     let mut rori_server = String::new();
     let mut rori_ring_id = String::new();
-    let mut done = false;
-    while !done {
-        let user_entry = user_text.lock().unwrap().clone();
-        if user_entry != "" {
-            rori_server = user_entry;
-            rori_ring_id = Endpoint::get_ring_id(&rori_server, &String::from("rori"));
-            *user_text.lock().unwrap() = String::new();
-            println!("{:?}!", rori_ring_id);
-            if rori_ring_id == "" {
-                let to_say = String::from("Cannot connect to this RORI, choose another address?");
-                Endpoint::mimic(&to_say, &rori_text);
-            } else {
-                done = true;
-            }
+    while rori_ring_id == "" {
+        rori_server = match next_user_entry(events, shutdown) {
+            Some(entry) => entry,
+            None => return
+        };
+        rori_ring_id = Endpoint::get_ring_id(&rori_server, &String::from("rori"));
+        println!("{:?}!", rori_ring_id);
+        if rori_ring_id == "" {
+            let to_say = String::from("Cannot connect to this RORI, choose another address?");
+            speech.push(&to_say, Priority::Chat);
         }
     }
 
     let to_say = String::from("Under what username?");
-    Endpoint::mimic(&to_say, &rori_text);
-    let mut username = String::new();
-    done = false;
-    while !done {
-        let user_entry = user_text.lock().unwrap().clone();
-        if user_entry != "" {
-            username = user_entry;
-            *user_text.lock().unwrap() = String::new();
-            done = true;
-        }
+    speech.push(&to_say, Priority::Chat);
+    let username = match next_user_entry(events, shutdown) {
+        Some(entry) => entry,
+        None => return
+    };
+
+    if let Err(e) = write_config_file(backend, &rori_server, &rori_ring_id, &username, &config.profile, path) {
+        error!("{}", e);
     }
+}
 
-    let accounts = Endpoint::get_account_list();
+/**
+ * Write the account in a config file without asking anything. Other keys of the file are kept
+ * @param backend daemon to use
+ * @param rori_server address of RORI
+ * @param rori_ring_id ring id of RORI. Retrieved from rori_server if empty
+ * @param username to use. A ring account is created if needed
+ * @param profile where the account is written. Empty for the top level
+ * @param path of the config file
+ */
+fn write_config_file(backend: &Arc<dyn DaemonBackend>, rori_server: &str, rori_ring_id: &str, username: &str, profile: &str, path: &Path) -> Result<(), String> {
+    let mut rori_ring_id = String::from(rori_ring_id);
+    if rori_ring_id == "" {
+        rori_ring_id = Endpoint::get_ring_id(&String::from(rori_server), &String::from("rori"));
+    }
+    if rori_ring_id == "" {
+        return Err(format!("Cannot connect to RORI at {}", rori_server));
+    }
+
+    let accounts = Endpoint::get_account_list(backend);
     let mut chosen_acc = Account::null();
     for account in accounts {
         if account.alias == username {
@@ -116,11 +187,11 @@ fn create_config_file(rori_text: &Arc<Mutex<String>>, user_text: &Arc<Mutex<Stri
     }
     if chosen_acc.id == "" {
         // Create ring account
-        Endpoint::add_account(&*username, "", false);
+        Endpoint::add_account(backend, username, "", false);
         // Let some time for the daemon
         let three_secs = Duration::from_millis(3000);
         thread::sleep(three_secs);
-        let accounts = Endpoint::get_account_list();
+        let accounts = Endpoint::get_account_list(backend);
         for account in accounts {
             if account.alias == username {
                 chosen_acc = account;
@@ -129,95 +200,235 @@ fn create_config_file(rori_text: &Arc<Mutex<String>>, user_text: &Arc<Mutex<Stri
     }
 
     if chosen_acc.id == "" {
-        return;
+        return Err(format!("Cannot create a ring account for {}", username));
     }
 
-    let config = ConfigFile {
-        ring_id: chosen_acc.id,
-        rori_server: rori_server,
-        rori_ring_id: rori_ring_id,
-        username: username,
+    let mut values = match File::open(path) {
+        Ok(mut file) => {
+            let mut content = String::new();
+            let _ = file.read_to_string(&mut content);
+            match from_str(&content) {
+                Ok(Value::Object(values)) => values,
+                _ => return Err(format!("{} is not a valid JSON object", path.display()))
+            }
+        },
+        Err(_) => Map::new()
     };
-    let config = serde_json::to_string_pretty(&config).unwrap_or(String::new());
-    let mut file = File::create("config.json").ok().expect("config.json found.");
-    let _ = file.write_all(config.as_bytes());
+    let mut identity = Map::new();
+    identity.insert(String::from("ring_id"), Value::String(chosen_acc.id));
+    identity.insert(String::from("rori_server"), Value::String(String::from(rori_server)));
+    identity.insert(String::from("rori_ring_id"), Value::String(rori_ring_id));
+    identity.insert(String::from("username"), Value::String(String::from(username)));
+    if profile == "" {
+        values.extend(identity);
+    } else {
+        let profiles = values.entry(String::from("profiles")).or_insert(Value::Object(Map::new()));
+        match *profiles {
+            Value::Object(ref mut profiles) => {
+                profiles.insert(String::from(profile), Value::Object(identity));
+            },
+            _ => return Err(format!("profiles in {} is not an object", path.display()))
+        }
+    }
+    let config = serde_json::to_string_pretty(&Value::Object(values)).unwrap_or(String::new());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+    }
+    let mut file = File::create(path).map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+    file.write_all(config.as_bytes()).map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+/**
+ * Show the QML interface, until the window is closed
+ * @param shared_prop state shown by the interface
+ */
+#[cfg(feature = "qml")]
+fn run_ui(shared_prop: SharedProp) {
+    let mut engine = qmlrs::Engine::new();
+    engine.load_local_file("ui/rori.qml");
+    engine.set_property("sharedprop", shared_prop);
+    engine.exec();
 }
 
 fn main() {
     // Init logging
     env_logger::init();
 
-    let shared_prop = SharedProp {
-        rori_text: Arc::new(Mutex::new(String::new())),
-        user_text: Arc::new(Mutex::new(String::new())),
-        api_text: Arc::new(Mutex::new(String::new())),
-        is_listening: Arc::new(Mutex::new(false)),
-        logged: Arc::new(Mutex::new(false)),
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, Options::usage());
+            process::exit(cli::EXIT_USAGE);
+        }
     };
-    let rori_text = shared_prop.rori_text.clone();
-    let user_text = shared_prop.user_text.clone();
-    let api_text = shared_prop.api_text.clone();
-    let api_listening = shared_prop.is_listening.clone();
-    let user_logged = shared_prop.logged.clone();
-    let stop = Arc::new(AtomicBool::new(false));
-    let stop_cloned = stop.clone();
+    if let Some(ref command) = options.command {
+        process::exit(command.run(&options));
+    }
+    let headless = options.ui == Interface::None;
+    let user_file = config::user_config_file(options.config.as_ref().map(|c| &**c));
+    let mut config = load_config(&options);
+
+    // First run without the spoken dialogue
+    if !config.is_configured() {
+        match (options.server.clone(), options.username.clone()) {
+            (Some(server), Some(username)) => {
+                let backend: Arc<dyn DaemonBackend> = Arc::new(DBusBackend::with_timeout(config.timeouts.daemon_ms));
+                if let Err(e) = write_config_file(&backend, &server, "", &username, &config.profile, &user_file) {
+                    eprintln!("{}", e);
+                    process::exit(cli::EXIT_FAILURE);
+                }
+                config = load_config(&options);
+            },
+            _ => {
+                if headless {
+                    eprintln!("No account configured. Use --server and --username to create {}.", user_file.display());
+                    process::exit(cli::EXIT_NOT_CONFIGURED);
+                }
+            }
+        }
+    }
+    let data_dir = match config.prepare_data_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("Can't prepare {}: {}", config.data_dir().display(), e);
+            process::exit(cli::EXIT_FAILURE);
+        }
+    };
+
+    let bus = EventBus::new();
+    #[cfg(feature = "qml")]
+    let shared_prop = match options.ui {
+        Interface::Qml => Some(SharedProp::new(bus.clone())),
+        _ => None
+    };
+    let tui = match options.ui {
+        Interface::Terminal => Some(Tui::new(bus.clone())),
+        _ => None
+    };
+    let events = bus.subscribe();
+    let log_events = bus.subscribe();
+    thread::spawn(move || {
+        for event in log_events {
+            debug!("Event: {:?}", event);
+        }
+    });
+    let api_bus = bus.clone();
+    let alarms_file = data_dir.join("alarms.json");
+    let alarms = Arc::new(Mutex::new(AlarmStore::load(&alarms_file.to_string_lossy(),
+                                                      config.alarm_grace_secs as i64)));
+    let api_alarms = alarms.clone();
+    let shutdown = ShutdownToken::new();
+    let stt_loop = stt::start(&config.stt, bus.clone(), shutdown.clone());
+    if let Err(e) = shutdown.listen_signals() {
+        error!("Can't handle signals: {}", e);
+    }
+    let reloader = Arc::new(Mutex::new(Reloader::new(options.config.clone(), options.set.clone(),
+                                                     config.clone(), bus.clone(), alarms.clone(),
+                                                     data_dir.clone())));
+    if let Err(e) = reload::listen_sighup(reloader.clone()) {
+        error!("Can't handle SIGHUP: {}", e);
+    }
+    reload::listen_profile_commands(reloader.clone(), &bus);
+    // Workers started by the signals thread, joined at shutdown
+    let workers = Arc::new(Mutex::new(Vec::new()));
+    let thread_workers = workers.clone();
+    let thread_reloader = reloader.clone();
+    let shutdown_cloned = shutdown.clone();
+    let thread_config = config.clone();
+    let thread_options = options.clone();
     let handle_signals = thread::spawn(move || {
+        let mut config = thread_config;
+        let backend: Arc<dyn DaemonBackend> = Arc::new(DBusBackend::with_timeout(config.timeouts.daemon_ms));
+        // Every sentence is said by this worker, without blocking endpoints
+        let speech_cache = data_dir.join(CACHE_DIR);
+        let speech = SpeechQueue::new(&config.tts, &speech_cache.to_string_lossy(), bus.clone());
+        let say_loop = speech.start(shutdown_cloned.clone(), Duration::from_millis(config.timeouts.say_interval_ms));
+        thread_workers.lock().unwrap().push(("speech", say_loop));
         // if not config, create it
-        if !Path::new("config.json").exists() {
-            create_config_file(&rori_text, &user_text);
+        if !config.is_configured() {
+            create_config_file(&backend, &speech, &events, &shutdown_cloned, &config, &user_file);
+            config = load_config(&thread_options);
         }
 
-        if !Path::new("config.json").exists() {
-            error!("No config file found");
+        if !config.is_configured() {
+            error!("No account configured");
             return;
         }
 
-        // This script load config from config.json
-        let mut file = File::open("config.json").ok()
-            .expect("Config file not found");
-        let mut config = String::new();
-        file.read_to_string(&mut config).ok()
-            .expect("failed to read!");
-        let config: Value = from_str(&*config).ok()
-                            .expect("Incorrect config file. Please check config.json");
-
         let to_say = String::from("Connection...");
-        Endpoint::mimic(&to_say, &rori_text);
-        let shared_endpoint : Arc<Mutex<Endpoint>> = Arc::new(Mutex::new(
-            Endpoint::init(config["ring_id"].as_str().unwrap_or(""),
-                           config["rori_server"].as_str().unwrap_or(""),
-                           config["rori_ring_id"].as_str().unwrap_or(""))
-            .ok().expect("Can't initialize ConfigurationEndpoint"))
-        );
-        let stop_say = stop_cloned.clone();
-        let say_endpoint = shared_endpoint.clone();
-        let say_rori_text = rori_text.clone();
-        let say_loop = thread::spawn(move || {
-            let fivehundrems = Duration::from_millis(500);
-            loop {
-                Endpoint::process_say(say_endpoint.clone(), &say_rori_text);
-                if stop_say.load(Ordering::SeqCst) {
-                    break;
-                }
-                thread::sleep(fivehundrems);
+        speech.set_tts(&config.tts);
+        speech.push(&to_say, Priority::Chat);
+        let endpoint = match Endpoint::init(backend.clone(), &config.ring_id, &config.rori_server, &config.rori_ring_id) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                error!("Can't initialize the endpoint: {}", e);
+                shutdown_cloned.request();
+                return;
             }
-        });
-        Endpoint::login(shared_endpoint.clone(), &user_logged, rori_text.clone());
-        Endpoint::handle_signals(shared_endpoint, stop_cloned, rori_text, user_text, user_logged);
-        let _ = say_loop.join();
+        };
+        let shared_endpoint : Arc<Mutex<Endpoint>> = Arc::new(Mutex::new(endpoint));
+        let endpoints = Arc::new(Mutex::new(EndpointSet::new(shared_endpoint.clone())));
+        let runner;
+        {
+            let mut endpoint = shared_endpoint.lock().unwrap();
+            endpoint.profile = config.profile.clone();
+            endpoint.set_speech(speech.clone(), "");
+            let trust_file = data_dir.join("trust.json");
+            endpoint.set_trust_store(Arc::new(Mutex::new(TrustStore::load(&trust_file.to_string_lossy()))));
+            endpoint.process_trust_requests();
+            let outbox_file = data_dir.join(OUTBOX_FILE);
+            endpoint.set_outbox(Arc::new(Mutex::new(Outbox::load(&outbox_file.to_string_lossy()))));
+            reload::register_handlers(&endpoint, &config, &bus, &alarms, &data_dir);
+            runner = Arc::new(Mutex::new(reload::alarm_runner(&config, endpoint.speech(), &data_dir)));
+            thread_workers.lock().unwrap().push(("alarms", AlarmRunner::start(alarms, runner.clone(), shutdown_cloned.clone())));
+        }
+        Endpoint::login(shared_endpoint, &bus);
+        // Connect other profiles once the active one is logged
+        thread_reloader.lock().unwrap().attach(endpoints.clone(), runner, config.clone());
+        EndpointSet::handle_signals(endpoints, backend, &bus, shutdown_cloned, events);
     });
 
-    let api_thread = thread::spawn(move || {
-        let mut api = API::new(api_text, api_listening);
-        api.start();
-    });
+    let api = API::new(api_bus, api_alarms, reloader.clone());
+    reloader.lock().unwrap().attach_api(api);
 
-    let mut engine = qmlrs::Engine::new();
-    engine.load_local_file("ui/rori.qml");
-    engine.set_property("sharedprop", shared_prop);
-    engine.exec();
+    let mut threads = vec![("signals", handle_signals), ("config watcher", reload::watch(reloader.clone(), shutdown.clone()))];
+    if let Some(mut tui) = tui {
+        let tui_shutdown = shutdown.clone();
+        threads.push(("tui", thread::spawn(move || tui.run(tui_shutdown))));
+    }
+    if let Some(stt_loop) = stt_loop {
+        threads.push(("speech recognition", stt_loop));
+    }
+
+    // Stop everything when a shutdown is requested, from a signal or from the UI
+    let coordinator_shutdown = shutdown.clone();
+    let shutdown_timeout = Duration::from_secs(config.timeouts.shutdown_secs);
+    let coordinator = thread::spawn(move || {
+        coordinator_shutdown.wait();
+        info!("Stopping...");
+        // No new request once stopping, the address is freed
+        reloader.lock().unwrap().close_api();
+        let deadline = Instant::now() + shutdown_timeout;
+        let mut stopped = shutdown::join_all(threads, shutdown_timeout);
+        // Every worker is known once the signals thread stopped
+        let workers: Vec<_> = workers.lock().unwrap().drain(..).collect();
+        stopped = shutdown::join_all(workers, deadline.saturating_duration_since(Instant::now())) && stopped;
+        if !stopped {
+            error!("Some threads did not stop properly");
+            process::exit(1);
+        }
+        process::exit(0);
+    });
 
-    stop.store(false, Ordering::SeqCst);
-    let _ = handle_signals.join();
-    let _ = api_thread.join();
+    #[cfg(feature = "qml")]
+    {
+        if let Some(shared_prop) = shared_prop {
+            run_ui(shared_prop);
+            shutdown.request();
+        }
+    }
+    if headless {
+        info!("Running without interface");
+    }
+    let _ = coordinator.join();
 }
@@ -30,7 +30,7 @@ use std::fmt;
 /**
  * Represent a RING account, just here to store informations.
  **/
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Account {
     pub id: String,
     pub ring_id: String,
@@ -0,0 +1,847 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::command::CommandPolicy;
+use rori::endpoint::Endpoint;
+use rori::handler::DatatypeHandler;
+use rori::interaction::Interaction;
+use rori::music::MusicController;
+use rori::shutdown::ShutdownToken;
+use rori::speech::{Priority, SpeechQueue};
+use serde_json;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use time::{self, Timespec};
+
+const WEEKDAYS: [&str; 7] = ["sunday", "monday", "tuesday", "wednesday", "thursday", "friday", "saturday"];
+
+/**
+ * When an alarm rings. Days are numbered from 0 (sunday) to 6 (saturday)
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmRule {
+    /// Ring once, at a unix timestamp
+    Once { at: i64 },
+    /// Ring every day
+    Daily { hour: i32, minute: i32 },
+    /// Ring on some days of the week
+    Weekly { days: Vec<i32>, hour: i32, minute: i32 },
+}
+
+/**
+ * What to do when an alarm rings
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmAction {
+    /// Say a sentence
+    Say(String),
+    /// Control the music ("start", "stop", ...)
+    Music(String),
+    /// Run a command allowed by the CommandPolicy
+    Command(String),
+}
+
+/**
+ * A scheduled alarm
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Alarm {
+    pub id: u64,
+    pub rule: AlarmRule,
+    /// Body received from RORI
+    pub label: String,
+    /// Actions to run instead of the default ones
+    #[serde(default)]
+    pub actions: Vec<AlarmAction>,
+    /// Next time the alarm rings (unix timestamp)
+    pub next: i64,
+}
+
+impl Alarm {
+    /**
+     * Human readable description of the alarm
+     * @param self
+     * @return a sentence like "every monday at 07:30"
+     */
+    pub fn describe(&self) -> String {
+        match self.rule {
+            AlarmRule::Once { at } => {
+                let tm = time::at(Timespec::new(at, 0));
+                tm.strftime("%A at %H:%M").map(|t| t.to_string()).unwrap_or_default()
+            },
+            AlarmRule::Daily { hour, minute } => format!("every day at {:02}:{:02}", hour, minute),
+            AlarmRule::Weekly { ref days, hour, minute } => {
+                let days: Vec<&str> = days.iter().map(|d| WEEKDAYS[*d as usize]).collect();
+                format!("every {} at {:02}:{:02}", days.join(", "), hour, minute)
+            }
+        }
+    }
+}
+
+/**
+ * Get a local time as a unix timestamp
+ * @param now current unix timestamp
+ * @param day_offset number of days after today
+ * @param hour
+ * @param minute
+ * @return the unix timestamp
+ */
+fn local_time(now: i64, day_offset: i64, hour: i32, minute: i32) -> i64 {
+    // Work from noon to be safe with daylight saving time changes
+    let mut today = time::at(Timespec::new(now, 0));
+    today.tm_hour = 12;
+    today.tm_min = 0;
+    today.tm_sec = 0;
+    today.tm_nsec = 0;
+    let noon = today.to_timespec().sec + day_offset * 86400;
+    let mut day = time::at(Timespec::new(noon, 0));
+    day.tm_hour = hour;
+    day.tm_min = minute;
+    day.tm_sec = 0;
+    day.tm_nsec = 0;
+    // The offset at noon can differ at this hour on the day of the change
+    day.tm_isdst = -1;
+    day.to_timespec().sec
+}
+
+/**
+ * @param now current unix timestamp
+ * @param day_offset number of days after today
+ * @return the day of the week (0 = sunday)
+ */
+fn weekday(now: i64, day_offset: i64) -> i32 {
+    time::at(Timespec::new(local_time(now, day_offset, 12, 0), 0)).tm_wday
+}
+
+impl AlarmRule {
+    /**
+     * Check a rule read from the disk, which can be edited by hand
+     * @param self
+     * @return why the rule is invalid, if it is
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        let (hour, minute) = match *self {
+            AlarmRule::Once { .. } => return Ok(()),
+            AlarmRule::Daily { hour, minute } => (hour, minute),
+            AlarmRule::Weekly { ref days, hour, minute } => {
+                if days.is_empty() {
+                    return Err(String::from("no day"));
+                }
+                if let Some(day) = days.iter().find(|d| **d < 0 || **d > 6) {
+                    return Err(format!("invalid day: {} (0 = sunday to 6 = saturday)", day));
+                }
+                (hour, minute)
+            }
+        };
+        if hour < 0 || hour > 23 || minute < 0 || minute > 59 {
+            return Err(format!("invalid time: {}:{}", hour, minute));
+        }
+        Ok(())
+    }
+
+    /**
+     * Compute when the alarm rings next
+     * @param self
+     * @param now current unix timestamp
+     * @return the next unix timestamp, or None if the alarm will not ring anymore
+     */
+    pub fn next_after(&self, now: i64) -> Option<i64> {
+        match *self {
+            AlarmRule::Once { at } => if at > now { Some(at) } else { None },
+            AlarmRule::Daily { hour, minute } => {
+                (0..2).map(|d| local_time(now, d, hour, minute)).find(|t| *t > now)
+            },
+            AlarmRule::Weekly { ref days, hour, minute } => {
+                (0..8).filter(|d| days.contains(&weekday(now, *d)))
+                      .map(|d| local_time(now, d, hour, minute))
+                      .find(|t| *t > now)
+            }
+        }
+    }
+
+    /**
+     * Parse an alarm from RORI. Supported formats:
+     * "7:40", "tomorrow 7h", "monday at 7:30pm", "in 10 minutes", "in 1h30",
+     * "every day at 7:40", "every weekday 7:00", "every monday, friday at 9:00"
+     * @param text to parse
+     * @param now current unix timestamp
+     * @return the rule or why it can't be parsed
+     */
+    pub fn parse(text: &str, now: i64) -> Result<AlarmRule, String> {
+        let text = text.to_lowercase().replace(',', " ");
+        let words: Vec<&str> = text.split_whitespace()
+                                   .filter(|w| !["at", "on", "and", "the"].contains(w))
+                                   .collect();
+        if words.is_empty() {
+            return Err(String::from("empty alarm"));
+        }
+        if words[0] == "in" {
+            let seconds = parse_duration(&words[1..])?;
+            return Ok(AlarmRule::Once { at: now + seconds });
+        }
+
+        let mut recurring = false;
+        let mut tomorrow = false;
+        let mut days: Vec<i32> = Vec::new();
+        let mut hour_minute = None;
+        for word in words {
+            match word {
+                "every" => recurring = true,
+                "daily" | "everyday" => {
+                    recurring = true;
+                    days = (0..7).collect();
+                },
+                "day" | "days" => days = (0..7).collect(),
+                "weekday" | "weekdays" => days.extend(1..6),
+                "weekend" | "weekends" => days.extend(&[0, 6]),
+                "tomorrow" => tomorrow = true,
+                _ => {
+                    if let Some(day) = parse_weekday(word) {
+                        days.push(day);
+                    } else if let Some(hm) = parse_time(word) {
+                        hour_minute = Some(hm);
+                    } else {
+                        return Err(format!("unknown word: {}", word));
+                    }
+                }
+            }
+        }
+        let (hour, minute) = match hour_minute {
+            Some(hm) => hm,
+            None => return Err(String::from("no time found"))
+        };
+        days.sort();
+        days.dedup();
+
+        if recurring {
+            if days.is_empty() || days.len() == 7 {
+                return Ok(AlarmRule::Daily { hour, minute });
+            }
+            return Ok(AlarmRule::Weekly { days, hour, minute });
+        }
+        let first_day = if tomorrow { 1 } else { 0 };
+        let at = (first_day..first_day + 8)
+                    .filter(|d| days.is_empty() || days.contains(&weekday(now, *d)))
+                    .map(|d| local_time(now, d, hour, minute))
+                    .find(|t| *t > now);
+        match at {
+            Some(at) => Ok(AlarmRule::Once { at }),
+            None => Err(String::from("can't find a date"))
+        }
+    }
+}
+
+/**
+ * @param word like "monday", "mon" or "mondays"
+ * @return the day of the week
+ */
+fn parse_weekday(word: &str) -> Option<i32> {
+    let word = word.trim_end_matches('s');
+    if word.len() < 3 {
+        return None;
+    }
+    WEEKDAYS.iter().position(|d| d.starts_with(word) || *d == word)
+                   .map(|d| d as i32)
+}
+
+/**
+ * @param word like "7:40", "07h40", "7h", "7am" or "7:30pm". "12am" is midnight
+ * @return (hour, minute)
+ */
+fn parse_time(word: &str) -> Option<(i32, i32)> {
+    let (word, offset) = if word.ends_with("pm") {
+        (&word[..word.len() - 2], Some(12))
+    } else if word.ends_with("am") {
+        (&word[..word.len() - 2], Some(0))
+    } else {
+        (word, None)
+    };
+    let mut parts = word.splitn(2, |c| c == ':' || c == 'h');
+    let hour: i32 = parts.next()?.parse().ok()?;
+    let minute: i32 = match parts.next() {
+        Some("") | None => 0,
+        Some(minute) => minute.parse().ok()?
+    };
+    let hour = match offset {
+        // 12 hour clock: 12am is 00:xx, 12pm is 12:xx
+        Some(offset) if hour >= 1 && hour <= 12 => hour % 12 + offset,
+        Some(_) => return None,
+        None => hour
+    };
+    if hour > 23 || minute > 59 || hour < 0 || minute < 0 {
+        return None;
+    }
+    Some((hour, minute))
+}
+
+/**
+ * @param words like ["10", "minutes"], ["1h30"] or ["2", "hours", "5", "min"]
+ * @return the duration in seconds
+ */
+fn parse_duration(words: &[&str]) -> Result<i64, String> {
+    let mut total = 0;
+    let mut pending: Option<i64> = None;
+    for word in words {
+        let digits: String = word.chars().take_while(|c| c.is_digit(10)).collect();
+        let unit = &word[digits.len()..];
+        if !digits.is_empty() {
+            if pending.is_some() {
+                return Err(format!("missing unit before {}", word));
+            }
+            pending = Some(digits.parse().map_err(|_| format!("invalid number: {}", word))?);
+        }
+        if unit.is_empty() {
+            continue;
+        }
+        let value = match pending.take() {
+            Some(value) => value,
+            None => return Err(format!("missing number before {}", word))
+        };
+        // 1h30 means 1 hour and 30 minutes
+        let (unit, rest) = match unit.find(|c: char| c.is_digit(10)) {
+            Some(idx) => (&unit[..idx], &unit[idx..]),
+            None => (unit, "")
+        };
+        total += value * match unit {
+            "s" | "sec" | "secs" | "second" | "seconds" => 1,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60,
+            "h" | "hour" | "hours" => 3600,
+            _ => return Err(format!("unknown unit: {}", unit))
+        };
+        if !rest.is_empty() {
+            let minutes: i64 = rest.parse().map_err(|_| format!("invalid number: {}", rest))?;
+            total += minutes * 60;
+        }
+    }
+    if let Some(minutes) = pending {
+        // "in 10" means 10 minutes
+        total += minutes * 60;
+    }
+    if total <= 0 {
+        return Err(String::from("invalid duration"));
+    }
+    Ok(total)
+}
+
+/**
+ * Alarms saved on the disk
+ */
+pub struct AlarmStore {
+    alarms: Vec<Alarm>,
+    next_id: u64,
+    path: String,
+}
+
+impl AlarmStore {
+    /**
+     * Load alarms from a file. Alarms missed for more than grace seconds
+     * are rescheduled (or dropped if they don't repeat).
+     * @param path of the file
+     * @param grace seconds
+     * @return the AlarmStore
+     */
+    pub fn load(path: &str, grace: i64) -> AlarmStore {
+        let mut alarms: Vec<Alarm> = match File::open(path) {
+            Ok(mut file) => {
+                let mut content = String::new();
+                let _ = file.read_to_string(&mut content);
+                serde_json::from_str(&content).unwrap_or_else(|e| {
+                    error!("Can't read alarms from {}: {}", path, e);
+                    Vec::new()
+                })
+            },
+            Err(_) => Vec::new()
+        };
+        alarms.retain(|alarm| match alarm.rule.validate() {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Alarm {} ignored ({}): {}", alarm.id, alarm.label, e);
+                false
+            }
+        });
+        let now = time::get_time().sec;
+        alarms.retain(|alarm| {
+            if alarm.next >= now - grace {
+                return true;
+            }
+            warn!("Alarm {} missed ({})", alarm.id, alarm.label);
+            alarm.rule.next_after(now).is_some()
+        });
+        for alarm in alarms.iter_mut() {
+            if alarm.next < now - grace {
+                alarm.next = alarm.rule.next_after(now).unwrap_or(alarm.next);
+            }
+        }
+        let next_id = alarms.iter().map(|a| a.id).max().unwrap_or(0) + 1;
+        info!("{} alarms loaded from {}", alarms.len(), path);
+        AlarmStore {
+            alarms,
+            next_id,
+            path: String::from(path),
+        }
+    }
+
+    /**
+     * Write alarms on the disk
+     * @param self
+     */
+    pub fn save(&self) {
+        let content = serde_json::to_string_pretty(&self.alarms).unwrap_or(String::from("[]"));
+        let tmp = format!("{}.tmp", self.path);
+        let written = File::create(&tmp).and_then(|mut file| file.write_all(content.as_bytes()));
+        if written.and_then(|_| fs::rename(&tmp, &self.path)).is_err() {
+            error!("Can't save alarms to {}", self.path);
+        }
+    }
+
+    /**
+     * Schedule a new alarm
+     * @param self
+     * @param rule of the alarm
+     * @param label body from RORI
+     * @param actions to run instead of the default ones
+     * @return the new alarm, if it will ring
+     */
+    pub fn add(&mut self, rule: AlarmRule, label: &str, actions: Vec<AlarmAction>) -> Option<Alarm> {
+        let next = rule.next_after(time::get_time().sec)?;
+        let alarm = Alarm {
+            id: self.next_id,
+            rule,
+            label: String::from(label),
+            actions,
+            next,
+        };
+        self.next_id += 1;
+        self.alarms.push(alarm.clone());
+        self.save();
+        Some(alarm)
+    }
+
+    /**
+     * @param self
+     * @return alarms, sorted by next ring
+     */
+    pub fn list(&self) -> Vec<Alarm> {
+        let mut alarms = self.alarms.clone();
+        alarms.sort_by_key(|a| a.next);
+        alarms
+    }
+
+    /**
+     * Remove an alarm
+     * @param self
+     * @param id of the alarm
+     * @return if the alarm existed
+     */
+    pub fn cancel(&mut self, id: u64) -> bool {
+        let len = self.alarms.len();
+        self.alarms.retain(|a| a.id != id);
+        let removed = self.alarms.len() != len;
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    /**
+     * Get alarms which must ring, and schedule their next ring
+     * @param self
+     * @param now current unix timestamp
+     * @return alarms to ring
+     */
+    pub fn take_due(&mut self, now: i64) -> Vec<Alarm> {
+        let due: Vec<Alarm> = self.alarms.iter().filter(|a| a.next <= now).cloned().collect();
+        if due.is_empty() {
+            return due;
+        }
+        self.alarms.retain(|a| a.next > now || a.rule.next_after(now).is_some());
+        for alarm in self.alarms.iter_mut() {
+            if alarm.next <= now {
+                alarm.next = alarm.rule.next_after(now).unwrap_or(alarm.next);
+            }
+        }
+        self.save();
+        due
+    }
+}
+
+/**
+ * Execute alarm actions
+ */
+#[derive(Clone)]
+pub struct AlarmRunner {
+    default_actions: Vec<AlarmAction>,
+    speech: SpeechQueue,
+    commands: CommandPolicy,
+    music: MusicController,
+}
+
+impl AlarmRunner {
+    /**
+     * @param default_actions used for alarms without their own actions
+     * @param speech queue of sentences to say
+     * @param commands policy used for Command actions
+     * @param music controller used for Music actions
+     * @return an AlarmRunner
+     */
+    pub fn new(default_actions: Vec<AlarmAction>, speech: SpeechQueue, commands: CommandPolicy, music: MusicController) -> AlarmRunner {
+        AlarmRunner {
+            default_actions,
+            speech,
+            commands,
+            music,
+        }
+    }
+
+    /**
+     * Ring an alarm
+     * @param self
+     * @param alarm to ring
+     */
+    pub fn ring(&self, alarm: &Alarm) {
+        info!("Alarm {} rings ({})", alarm.id, alarm.label);
+        let actions = if alarm.actions.is_empty() { &self.default_actions } else { &alarm.actions };
+        for action in actions {
+            match *action {
+                AlarmAction::Say(ref sentence) => {
+                    self.speech.push(sentence, Priority::Alarm);
+                },
+                AlarmAction::Music(ref order) => {
+                    if let Err(e) = self.music.execute(order) {
+                        error!("Alarm music {} failed: {}", order, e);
+                    }
+                },
+                AlarmAction::Command(ref body) => {
+                    match self.commands.resolve(body) {
+                        Ok((name, argv, spec)) => {
+                            self.commands.execute(&name, argv, &spec);
+                        },
+                        Err(reason) => {
+                            warn!("Alarm command {} refused: {}", body, reason);
+                            self.commands.audit(body, &[], &*format!("denied: {}", reason));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /**
+     * Ring alarms when they are due, until a shutdown is requested
+     * @param store alarms to watch
+     * @param runner to ring alarms, can be replaced while running
+     * @param shutdown when to quit
+     * @return the thread
+     */
+    pub fn start(store: Arc<Mutex<AlarmStore>>, runner: Arc<Mutex<AlarmRunner>>, shutdown: ShutdownToken) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let one_sec = Duration::from_millis(1000);
+            loop {
+                let due = store.lock().unwrap().take_due(time::get_time().sec);
+                for alarm in due {
+                    runner.lock().unwrap().ring(&alarm);
+                }
+                if shutdown.wait_timeout(one_sec) {
+                    break;
+                }
+            }
+            store.lock().unwrap().save();
+        })
+    }
+}
+
+/**
+ * Handle the alarm datatype. The body is an alarm to schedule, optionally
+ * followed by actions ("7:30 | say Wake up! | music start"), "list" or "cancel <id|all>"
+ */
+pub struct AlarmHandler {
+    store: Arc<Mutex<AlarmStore>>,
+}
+
+impl AlarmHandler {
+    /**
+     * @param store where alarms are saved
+     * @return an AlarmHandler
+     */
+    pub fn new(store: Arc<Mutex<AlarmStore>>) -> AlarmHandler {
+        AlarmHandler {
+            store,
+        }
+    }
+
+    /**
+     * @param text like "say Good morning" or "music start"
+     * @return the action
+     */
+    fn parse_action(text: &str) -> Option<AlarmAction> {
+        let text = text.trim();
+        let (kind, arg) = match text.find(' ') {
+            Some(idx) => (&text[..idx], text[idx + 1..].trim()),
+            None => (text, "")
+        };
+        match kind {
+            "say" => Some(AlarmAction::Say(String::from(arg))),
+            "music" => Some(AlarmAction::Music(String::from(if arg.is_empty() { "start" } else { arg }))),
+            "command" => Some(AlarmAction::Command(String::from(arg))),
+            _ => None
+        }
+    }
+}
+
+impl DatatypeHandler for AlarmHandler {
+    fn datatype(&self) -> &str {
+        "alarm"
+    }
+
+    fn handle(&mut self, interaction: &Interaction, endpoint: &mut Endpoint) {
+        let body = interaction.body.trim();
+        let mut store = self.store.lock().unwrap();
+        if body == "list" {
+            let alarms = store.list();
+            if alarms.is_empty() {
+                endpoint.add_to_say_queue(&String::from("No alarm scheduled"));
+            }
+            for alarm in alarms {
+                endpoint.add_to_say_queue(&format!("Alarm {}: {}", alarm.id, alarm.describe()));
+            }
+            return;
+        }
+        if body.starts_with("cancel") {
+            let arg = body["cancel".len()..].trim();
+            let ids: Vec<u64> = if arg == "all" {
+                store.list().iter().map(|a| a.id).collect()
+            } else {
+                arg.parse().into_iter().collect()
+            };
+            let cancelled = ids.iter().filter(|id| store.cancel(**id)).count();
+            endpoint.add_to_say_queue(&format!("{} alarm(s) cancelled", cancelled));
+            return;
+        }
+
+        let mut parts = body.split('|');
+        let when = parts.next().unwrap_or("");
+        let actions: Vec<AlarmAction> = parts.filter_map(AlarmHandler::parse_action).collect();
+        let rule = match AlarmRule::parse(when, time::get_time().sec) {
+            Ok(rule) => rule,
+            Err(e) => {
+                warn!("Can't parse alarm {}: {}", body, e);
+                endpoint.add_to_say_queue(&format!("I don't understand this alarm: {}", when));
+                return;
+            }
+        };
+        match store.add(rule, body, actions) {
+            Some(alarm) => endpoint.add_to_say_queue(&format!("Alarm set {}", alarm.describe())),
+            None => endpoint.add_to_say_queue(&String::from("This alarm is in the past"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::Once;
+
+    static TIMEZONE: Once = Once::new();
+
+    /// Monday 2019-06-10 08:00 in Paris
+    const MONDAY_8AM: i64 = 1560146400;
+
+    /**
+     * Use the time zone of Paris (with daylight saving time) for local times
+     */
+    fn paris() {
+        TIMEZONE.call_once(|| {
+            env::set_var("TZ", "CET-1CEST,M3.5.0,M10.5.0/3");
+            time::tzset();
+        });
+    }
+
+    fn once(text: &str, now: i64) -> i64 {
+        match AlarmRule::parse(text, now) {
+            Ok(AlarmRule::Once { at }) => at,
+            rule => panic!("{}: unexpected {:?}", text, rule)
+        }
+    }
+
+    #[test]
+    fn parse_one_time_alarms() {
+        paris();
+        // Tomorrow when the time is passed, else today
+        assert_eq!(once("7:40", MONDAY_8AM), 1560231600);
+        assert_eq!(once("09h", MONDAY_8AM), 1560150000);
+        assert_eq!(once("tomorrow 7h", MONDAY_8AM), 1560229200);
+        assert_eq!(once("Monday at 7:30pm", MONDAY_8AM), 1560187800);
+        // Next week when the time is passed today
+        assert_eq!(once("monday 7:30", MONDAY_8AM), 1560749400);
+        assert_eq!(once("on sundays at 10:00", MONDAY_8AM), 1560672000);
+        assert_eq!(once("sun 10am", MONDAY_8AM), 1560672000);
+    }
+
+    #[test]
+    fn parse_midnight_and_noon() {
+        paris();
+        assert_eq!(once("12am", MONDAY_8AM), 1560204000);
+        assert_eq!(once("0:00", MONDAY_8AM), 1560204000);
+        assert_eq!(once("12pm", MONDAY_8AM), 1560160800);
+        assert_eq!(parse_time("12am"), Some((0, 0)));
+        assert_eq!(parse_time("12:30am"), Some((0, 30)));
+        assert_eq!(parse_time("12pm"), Some((12, 0)));
+        assert_eq!(parse_time("1pm"), Some((13, 0)));
+        assert_eq!(parse_time("11:59pm"), Some((23, 59)));
+        assert_eq!(parse_time("0am"), None);
+        assert_eq!(parse_time("13pm"), None);
+        assert_eq!(parse_time("24:00"), None);
+        assert_eq!(parse_time("7:60"), None);
+    }
+
+    #[test]
+    fn parse_relative_alarms() {
+        assert_eq!(AlarmRule::parse("in 10 minutes", 1000), Ok(AlarmRule::Once { at: 1600 }));
+        assert_eq!(AlarmRule::parse("in 1h30", 1000), Ok(AlarmRule::Once { at: 6400 }));
+        assert!(AlarmRule::parse("in", 1000).is_err());
+    }
+
+    #[test]
+    fn parse_recurring_alarms() {
+        assert_eq!(AlarmRule::parse("every day at 7:40", 0), Ok(AlarmRule::Daily { hour: 7, minute: 40 }));
+        assert_eq!(AlarmRule::parse("daily 6:00", 0), Ok(AlarmRule::Daily { hour: 6, minute: 0 }));
+        assert_eq!(AlarmRule::parse("every weekday 7:00", 0),
+                   Ok(AlarmRule::Weekly { days: vec![1, 2, 3, 4, 5], hour: 7, minute: 0 }));
+        assert_eq!(AlarmRule::parse("every monday, friday at 9:00", 0),
+                   Ok(AlarmRule::Weekly { days: vec![1, 5], hour: 9, minute: 0 }));
+        assert_eq!(AlarmRule::parse("every weekend and saturday 10h", 0),
+                   Ok(AlarmRule::Weekly { days: vec![0, 6], hour: 10, minute: 0 }));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(AlarmRule::parse("", MONDAY_8AM).is_err());
+        assert!(AlarmRule::parse("at the", MONDAY_8AM).is_err());
+        assert!(AlarmRule::parse("monday", MONDAY_8AM).is_err());
+        assert!(AlarmRule::parse("banana 7:00", MONDAY_8AM).is_err());
+        assert!(AlarmRule::parse("13pm", MONDAY_8AM).is_err());
+        // Not a prefix of sunday once trimmed
+        assert!(AlarmRule::parse("sss 7:00", MONDAY_8AM).is_err());
+    }
+
+    #[test]
+    fn parse_weekdays() {
+        assert_eq!(parse_weekday("monday"), Some(1));
+        assert_eq!(parse_weekday("mon"), Some(1));
+        assert_eq!(parse_weekday("mondays"), Some(1));
+        assert_eq!(parse_weekday("thurs"), Some(4));
+        assert_eq!(parse_weekday("saturday"), Some(6));
+        assert_eq!(parse_weekday("s"), None);
+        assert_eq!(parse_weekday("ss"), None);
+        assert_eq!(parse_weekday("sss"), None);
+        assert_eq!(parse_weekday("mo"), None);
+        assert_eq!(parse_weekday("mondayx"), None);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration(&["10", "minutes"]), Ok(600));
+        assert_eq!(parse_duration(&["10"]), Ok(600));
+        assert_eq!(parse_duration(&["30s"]), Ok(30));
+        assert_eq!(parse_duration(&["1h30"]), Ok(5400));
+        assert_eq!(parse_duration(&["2", "hours", "5", "min"]), Ok(7500));
+        assert_eq!(parse_duration(&["1", "hour", "and"]).is_err(), true);
+        assert!(parse_duration(&[]).is_err());
+        assert!(parse_duration(&["0", "min"]).is_err());
+        assert!(parse_duration(&["10", "20"]).is_err());
+        assert!(parse_duration(&["minutes"]).is_err());
+        assert!(parse_duration(&["3", "days"]).is_err());
+    }
+
+    #[test]
+    fn next_ring() {
+        paris();
+        assert_eq!(AlarmRule::Once { at: 10 }.next_after(5), Some(10));
+        assert_eq!(AlarmRule::Once { at: 10 }.next_after(10), None);
+        assert_eq!(AlarmRule::Daily { hour: 9, minute: 0 }.next_after(MONDAY_8AM), Some(1560150000));
+        assert_eq!(AlarmRule::Daily { hour: 7, minute: 40 }.next_after(MONDAY_8AM), Some(1560231600));
+        // A ring is never now
+        assert_eq!(AlarmRule::Daily { hour: 8, minute: 0 }.next_after(MONDAY_8AM), Some(MONDAY_8AM + 86400));
+    }
+
+    #[test]
+    fn next_ring_wraps_the_week() {
+        paris();
+        let monday = AlarmRule::Weekly { days: vec![1], hour: 7, minute: 30 };
+        // Sunday 23:00, Saturday 10:00 and Monday after the ring
+        assert_eq!(monday.next_after(1560718800), Some(1560749400));
+        assert_eq!(monday.next_after(1560585600), Some(1560749400));
+        assert_eq!(monday.next_after(MONDAY_8AM), Some(1560749400));
+        let weekend = AlarmRule::Weekly { days: vec![0, 6], hour: 10, minute: 0 };
+        assert_eq!(weekend.next_after(MONDAY_8AM), Some(1560585600));
+    }
+
+    #[test]
+    fn next_ring_across_daylight_saving_time() {
+        paris();
+        // Saturday 2019-03-30 22:00, the night when clocks go forward
+        let before_spring = 1553979600;
+        assert_eq!(AlarmRule::Daily { hour: 1, minute: 30 }.next_after(before_spring), Some(1553992200));
+        assert_eq!(AlarmRule::Daily { hour: 7, minute: 0 }.next_after(before_spring), Some(1554008400));
+        // Saturday 2019-10-26 22:00, the night when clocks go back
+        let before_autumn = 1572120000;
+        assert_eq!(AlarmRule::Daily { hour: 1, minute: 30 }.next_after(before_autumn), Some(1572132600));
+        assert_eq!(AlarmRule::Daily { hour: 7, minute: 0 }.next_after(before_autumn), Some(1572156000));
+    }
+
+    #[test]
+    fn validate_rules() {
+        assert!(AlarmRule::Once { at: 0 }.validate().is_ok());
+        assert!(AlarmRule::Daily { hour: 23, minute: 59 }.validate().is_ok());
+        assert!(AlarmRule::Daily { hour: 24, minute: 0 }.validate().is_err());
+        assert!(AlarmRule::Weekly { days: vec![0, 6], hour: 7, minute: 0 }.validate().is_ok());
+        assert!(AlarmRule::Weekly { days: vec![7], hour: 7, minute: 0 }.validate().is_err());
+        assert!(AlarmRule::Weekly { days: vec![-1], hour: 7, minute: 0 }.validate().is_err());
+        assert!(AlarmRule::Weekly { days: vec![], hour: 7, minute: 0 }.validate().is_err());
+    }
+
+    #[test]
+    fn invalid_alarms_ignored_when_loading() {
+        let path = env::temp_dir().join(format!("rori-alarms-{}.json", std::process::id()));
+        let next = time::get_time().sec + 3600;
+        fs::write(&path, format!("[{{\"id\": 1, \"rule\": {{\"weekly\": {{\"days\": [1, 9], \"hour\": 7, \"minute\": 0}}}}, \"label\": \"edited\", \"next\": {}}},\
+                                  {{\"id\": 2, \"rule\": {{\"daily\": {{\"hour\": 7, \"minute\": 0}}}}, \"label\": \"valid\", \"next\": {}}}]",
+                                 next, next)).unwrap();
+        let store = AlarmStore::load(&path.to_string_lossy(), 60);
+        let alarms = store.list();
+        assert_eq!(alarms.len(), 1);
+        assert_eq!(alarms[0].label, "valid");
+        assert_eq!(alarms[0].describe(), "every day at 07:00");
+        let _ = fs::remove_file(&path);
+    }
+}
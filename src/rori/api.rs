@@ -25,81 +25,337 @@
  * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  **/
 
-// TODO replace this API by a subprocess (https://github.com/Uberi/speech_recognition/issues/411)
+use hyper;
+use hyper::net::{HttpStream, NetworkListener};
 use iron::prelude::*;
-use iron::Handler;
+use iron::{Handler, Protocol};
+use iron::mime::{Mime, SubLevel, TopLevel};
 use iron::status;
+use rori::alarm::AlarmStore;
+use rori::config::ApiConfig;
+use rori::device::{self, DeviceCommand, DeviceUpdate, ANSWER_TIMEOUT_SECS};
+use rori::event::{Event, EventBus, Source};
+use rori::reload::Reloader;
+use rori::stt::{self, Transcription};
+use rori::trust::TrustDecision;
 use router::Router;
+use serde_json;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Delay between two checks for new connections, or for a new socket
+const ACCEPT_POLL_MS: u64 = 100;
+
+/**
+ * The socket of the API, which can be replaced or closed while the server runs.
+ * Iron can't stop listening, so only one server is started, and accepts
+ * connections from the current socket.
+ */
+#[derive(Clone)]
+struct MovableListener {
+    /// Current socket, in non blocking mode. None when the API is closed
+    socket: Arc<Mutex<Option<(String, TcpListener)>>>,
+    /// Only one thread of the server polls the socket, the others wait for it
+    polling: Arc<Mutex<()>>,
+}
+
+impl MovableListener {
+    /**
+     * @return a MovableListener without socket
+     */
+    fn new() -> MovableListener {
+        MovableListener {
+            socket: Arc::new(Mutex::new(None)),
+            polling: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /**
+     * @param self
+     * @return the address listened, if any
+     */
+    fn bind(&self) -> Option<String> {
+        self.socket.lock().unwrap().as_ref().map(|(bind, _)| bind.clone())
+    }
+
+    /**
+     * Listen to another address. The previous socket is closed
+     * @param self
+     * @param bind host:port to listen
+     */
+    fn listen(&self, bind: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(bind)?;
+        listener.set_nonblocking(true)?;
+        *self.socket.lock().unwrap() = Some((String::from(bind), listener));
+        Ok(())
+    }
+
+    /**
+     * Close the socket. Connections already accepted are still answered
+     * @param self
+     */
+    fn close(&self) {
+        *self.socket.lock().unwrap() = None;
+    }
+}
+
+impl NetworkListener for MovableListener {
+    type Stream = HttpStream;
+
+    fn accept(&mut self) -> hyper::Result<HttpStream> {
+        let _polling = self.polling.lock().unwrap();
+        loop {
+            if let Some((_, ref listener)) = *self.socket.lock().unwrap() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        stream.set_nonblocking(false)?;
+                        return Ok(HttpStream(stream));
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
+        }
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        match *self.socket.lock().unwrap() {
+            Some((_, ref listener)) => listener.local_addr(),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "API closed")),
+        }
+    }
+}
 
 /**
  * Publicly accessible to manipulate RORI from HTTP requests
  */
 pub struct API {
-    user_text: Arc<Mutex<String>>,
-    is_listening: Arc<Mutex<bool>>
+    bus: EventBus,
+    alarms: Arc<Mutex<AlarmStore>>,
+    reloader: Arc<Mutex<Reloader>>,
+    listener: MovableListener,
+    /// If the server is started. It runs until the end of the process
+    started: bool,
 }
 
 impl API {
     /**
      * Initializes the API
-     * @param user_text
-     * @param is_listening
+     * @param bus where user entries and listening status are published
+     * @param alarms
+     * @param reloader called by POST /config/reload
      * @return an API structure
      */
-    pub fn new(user_text: Arc<Mutex<String>>, is_listening: Arc<Mutex<bool>>) -> API {
+    pub fn new(bus: EventBus, alarms: Arc<Mutex<AlarmStore>>, reloader: Arc<Mutex<Reloader>>) -> API {
         API {
-            user_text,
-            is_listening
+            bus,
+            alarms,
+            reloader,
+            listener: MovableListener::new(),
+            started: false,
+        }
+    }
+
+    /**
+     * Start, stop or move the API, in background threads
+     * @param self
+     * @param config of the API
+     */
+    pub fn apply(&mut self, config: &ApiConfig) {
+        if !config.enabled {
+            self.close();
+            return;
+        }
+        if self.listener.bind().as_ref() == Some(&config.bind) {
+            return;
+        }
+        // Free the previous address first, it may be listened again
+        self.close();
+        if let Err(e) = self.listener.listen(&config.bind) {
+            error!("Can't start the API on {}: {}", config.bind, e);
+            return;
+        }
+        if !self.started {
+            self.started = self.start();
+            if !self.started {
+                self.listener.close();
+                return;
+            }
+        }
+        info!("API listening on {}", config.bind);
+    }
+
+    /**
+     * Stop answering requests, and free the address
+     * @param self
+     */
+    pub fn close(&mut self) {
+        if let Some(bind) = self.listener.bind() {
+            info!("API closed on {}", bind);
         }
+        self.listener.close();
     }
 
     /**
-     * Launch an API instance
+     * Launch the server, in background threads. It accepts connections from the listener
      * @param self
+     * @return true if the server started
      */
-    pub fn start(&mut self) {
+    fn start(&self) -> bool {
         let mut router = Router::new();
         // Init routes
         let say_handler = SayHandler {
-            user_text: self.user_text.clone()
+            bus: self.bus.clone()
         };
 
         let start_listening_handler = StartListeningHandler {
-            is_listening: self.is_listening.clone()
+            bus: self.bus.clone()
         };
 
         let stop_listening_handler = StopListeningHandler {
-            is_listening: self.is_listening.clone()
+            bus: self.bus.clone()
+        };
+
+        let stop_speaking_handler = StopSpeakingHandler {
+            bus: self.bus.clone()
+        };
+
+        let speech_audio_handler = SpeechAudioHandler {
+            reloader: self.reloader.clone()
+        };
+
+        let transcribe_handler = TranscribeHandler {
+            bus: self.bus.clone(),
+            reloader: self.reloader.clone()
+        };
+
+        let list_alarms_handler = ListAlarmsHandler {
+            alarms: self.alarms.clone()
+        };
+
+        let cancel_alarm_handler = CancelAlarmHandler {
+            alarms: self.alarms.clone()
+        };
+
+        let reload_handler = ReloadHandler {
+            reloader: self.reloader.clone()
+        };
+
+        let list_profiles_handler = ListProfilesHandler {
+            reloader: self.reloader.clone()
+        };
+
+        let switch_profile_handler = SwitchProfileHandler {
+            reloader: self.reloader.clone()
+        };
+
+        let add_device_handler = AddDeviceHandler {
+            bus: self.bus.clone(),
+            reloader: self.reloader.clone()
+        };
+
+        let remove_device_handler = RemoveDeviceHandler {
+            bus: self.bus.clone(),
+            reloader: self.reloader.clone()
+        };
+
+        let list_trust_requests_handler = ListTrustRequestsHandler {
+            reloader: self.reloader.clone()
+        };
+
+        let accept_trust_handler = AnswerTrustHandler {
+            reloader: self.reloader.clone(),
+            decision: TrustDecision::Accepted,
+        };
+
+        let discard_trust_handler = AnswerTrustHandler {
+            reloader: self.reloader.clone(),
+            decision: TrustDecision::Discarded,
+        };
+
+        let undelivered_handler = UndeliveredHandler {
+            reloader: self.reloader.clone()
+        };
+
+        let list_outbox_handler = ListOutboxHandler {
+            reloader: self.reloader.clone()
+        };
+
+        let cancel_outbox_handler = CancelOutboxHandler {
+            reloader: self.reloader.clone()
+        };
+
+        let login_state_handler = LoginStateHandler {
+            reloader: self.reloader.clone()
+        };
+
+        let login_handler = LoginHandler {
+            reloader: self.reloader.clone()
         };
 
         router.post("/say", say_handler, "say");
         router.get("/startListen", start_listening_handler, "start");
         router.get("/stopListen", stop_listening_handler, "stop");
+        router.post("/speech/stop", stop_speaking_handler, "stop_speaking");
+        router.post("/speech/audio", speech_audio_handler, "speech_audio");
+        router.post("/transcribe", transcribe_handler, "transcribe");
+        router.get("/alarms", list_alarms_handler, "alarms");
+        router.delete("/alarms/:id", cancel_alarm_handler, "cancel_alarm");
+        router.post("/config/reload", reload_handler, "reload");
+        router.get("/profiles", list_profiles_handler, "profiles");
+        router.post("/profile", switch_profile_handler, "switch_profile");
+        router.post("/devices", add_device_handler, "add_device");
+        router.delete("/devices/:ring_id", remove_device_handler, "remove_device");
+        router.get("/trust", list_trust_requests_handler, "trust_requests");
+        router.post("/trust/:from/accept", accept_trust_handler, "accept_trust");
+        router.post("/trust/:from/discard", discard_trust_handler, "discard_trust");
+        router.get("/messages/undelivered", undelivered_handler, "undelivered");
+        router.get("/outbox", list_outbox_handler, "outbox");
+        router.delete("/outbox/:id", cancel_outbox_handler, "cancel_outbox");
+        router.get("/login", login_state_handler, "login_state");
+        router.post("/login", login_handler, "login");
         // Start router
-        Iron::new(router).http("localhost:3000").unwrap();
+        match Iron::new(router).listen(self.listener.clone(), Protocol::http()) {
+            Ok(mut listening) => {
+                // Don't join the threads of the server when dropped, they never end
+                let _ = listening.close();
+                true
+            },
+            Err(e) => {
+                error!("Can't start the API: {}", e);
+                false
+            }
+        }
     }
 }
 
 /**
- * fill user text for interface
+ * Send what the user said
  */
 struct SayHandler {
-    user_text: Arc<Mutex<String>>
+    bus: EventBus
 }
 
 impl Handler for SayHandler {
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
         let json_body = req.get::<bodyparser::Json>();
-        match json_body {
+        let user_say = match json_body {
             Ok(Some(json_body)) => {
-                let user_say = json_body["say"].to_string();
-                *self.user_text.lock().unwrap() = String::from(&user_say[1..(user_say.len()-1)]);
+                match json_body["say"].as_str() {
+                    Some(say) => String::from(say),
+                    None => return Ok(Response::with((status::BadRequest, "Missing say")))
+                }
             },
             _ => return Ok(Response::with((status::NotFound, "Can't get body")))
-        }
-        info!("POST /say: {}", *self.user_text.lock().unwrap());
-       
+        };
+        info!("POST /say: {}", user_say);
+        self.bus.publish(Event::UserUtterance(user_say, Source::Api));
+
         Ok(Response::with(status::Ok))
     }
 }
@@ -108,12 +364,12 @@ impl Handler for SayHandler {
  * Show listening status
  */
 struct StartListeningHandler {
-    is_listening: Arc<Mutex<bool>>
+    bus: EventBus
 }
 
 impl Handler for StartListeningHandler {
     fn handle(&self, _: &mut Request) -> IronResult<Response> {
-        *self.is_listening.lock().unwrap() = true;       
+        self.bus.publish(Event::ListeningChanged(true));
         Ok(Response::with(status::Ok))
     }
 }
@@ -122,12 +378,411 @@ impl Handler for StartListeningHandler {
  * Stop showing listening status
  */
 struct StopListeningHandler {
-    is_listening: Arc<Mutex<bool>>
+    bus: EventBus
 }
 
 impl Handler for StopListeningHandler {
     fn handle(&self, _: &mut Request) -> IronResult<Response> {
-        *self.is_listening.lock().unwrap() = false;       
+        self.bus.publish(Event::ListeningChanged(false));
         Ok(Response::with(status::Ok))
     }
-}
\ No newline at end of file
+}
+
+/**
+ * Stop RORI talking, and forget queued sentences
+ */
+struct StopSpeakingHandler {
+    bus: EventBus
+}
+
+impl Handler for StopSpeakingHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        info!("POST /speech/stop");
+        self.bus.publish(Event::StopSpeaking);
+        Ok(Response::with(status::Ok))
+    }
+}
+
+/**
+ * Render a sentence with the voice of RORI, and return the WAV file
+ */
+struct SpeechAudioHandler {
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for SpeechAudioHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let text = match req.get::<bodyparser::Json>() {
+            Ok(Some(json_body)) => match json_body["text"].as_str() {
+                Some(text) if text != "" => String::from(text),
+                _ => return Ok(Response::with((status::BadRequest, "Missing text")))
+            },
+            _ => return Ok(Response::with((status::BadRequest, "Can't get body")))
+        };
+        info!("POST /speech/audio: {}", text);
+        // Rendering can be long, don't block the reloader
+        let cache = self.reloader.lock().unwrap().speech_cache();
+        let audio = cache.audio(&text).and_then(|path| fs::read(&path).map_err(|e| e.to_string()));
+        match audio {
+            Ok(audio) => Ok(Response::with((status::Ok, Mime(TopLevel::Audio, SubLevel::Ext(String::from("wav")), vec![]), audio))),
+            Err(e) => {
+                error!("Can't render \"{}\": {}", text, e);
+                Ok(Response::with((status::InternalServerError, e)))
+            }
+        }
+    }
+}
+
+/**
+ * Transcribe a WAV file sent as body. With ?send=true, the text is also sent
+ * to RORI like a spoken entry
+ */
+struct TranscribeHandler {
+    bus: EventBus,
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for TranscribeHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let recognizer = match self.reloader.lock().unwrap().recognizer() {
+            Some(recognizer) => recognizer,
+            None => return Ok(Response::with((status::ServiceUnavailable, "Speech recognition disabled (stt.engine)")))
+        };
+        let send = req.url.query().map_or(false, |query| query.split('&').any(|param| param == "send=true"));
+        let mut audio = Vec::new();
+        if req.body.read_to_end(&mut audio).is_err() || audio.is_empty() {
+            return Ok(Response::with((status::BadRequest, "Can't get body")));
+        }
+        match stt::transcribe_audio(&*recognizer, &audio) {
+            Ok(text) => {
+                info!("POST /transcribe: {}", text);
+                if send && text != "" {
+                    self.bus.publish(Event::UserUtterance(text.clone(), Source::Voice));
+                }
+                let body = serde_json::to_string(&Transcription { text: text.clone() }).unwrap_or(String::new());
+                Ok(Response::with((status::Ok, body)))
+            },
+            Err(e) => {
+                error!("Can't transcribe: {}", e);
+                Ok(Response::with((status::InternalServerError, e)))
+            }
+        }
+    }
+}
+
+/**
+ * List scheduled alarms
+ */
+struct ListAlarmsHandler {
+    alarms: Arc<Mutex<AlarmStore>>
+}
+
+impl Handler for ListAlarmsHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let alarms = self.alarms.lock().unwrap().list();
+        let body = serde_json::to_string(&alarms).unwrap_or(String::from("[]"));
+        Ok(Response::with((status::Ok, body)))
+    }
+}
+
+/**
+ * Cancel an alarm
+ */
+struct CancelAlarmHandler {
+    alarms: Arc<Mutex<AlarmStore>>
+}
+
+impl Handler for CancelAlarmHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let id = req.extensions.get::<Router>().and_then(|r| r.find("id"))
+                    .and_then(|id| id.parse::<u64>().ok());
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(Response::with((status::BadRequest, "Invalid alarm id")))
+        };
+        if self.alarms.lock().unwrap().cancel(id) {
+            info!("DELETE /alarms/{}", id);
+            Ok(Response::with(status::Ok))
+        } else {
+            Ok(Response::with((status::NotFound, "Unknown alarm")))
+        }
+    }
+}
+
+/**
+ * Reload the configuration
+ */
+struct ReloadHandler {
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for ReloadHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        info!("POST /config/reload");
+        let report = self.reloader.lock().unwrap().reload();
+        let code = if report.errors.is_empty() { status::Ok } else { status::BadRequest };
+        let body = serde_json::to_string(&report).unwrap_or(String::new());
+        Ok(Response::with((code, body)))
+    }
+}
+
+/**
+ * List profiles
+ */
+struct ListProfilesHandler {
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for ListProfilesHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let (active, profiles) = self.reloader.lock().unwrap().profiles();
+        let mut body = serde_json::Map::new();
+        body.insert(String::from("active"), serde_json::Value::String(active));
+        body.insert(String::from("profiles"), serde_json::Value::from(profiles));
+        Ok(Response::with((status::Ok, serde_json::Value::Object(body).to_string())))
+    }
+}
+
+/**
+ * Switch to another profile
+ */
+struct SwitchProfileHandler {
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for SwitchProfileHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let json_body = req.get::<bodyparser::Json>();
+        let profile = match json_body {
+            Ok(Some(json_body)) => {
+                match json_body["profile"].as_str() {
+                    Some(profile) => String::from(profile),
+                    None => return Ok(Response::with((status::BadRequest, "Missing profile")))
+                }
+            },
+            _ => return Ok(Response::with((status::NotFound, "Can't get body")))
+        };
+        info!("POST /profile: {}", profile);
+        let report = self.reloader.lock().unwrap().switch_profile(&profile);
+        let code = if report.errors.is_empty() { status::Ok } else { status::BadRequest };
+        let body = serde_json::to_string(&report).unwrap_or(String::new());
+        Ok(Response::with((code, body)))
+    }
+}
+
+/**
+ * Show the login state of each profile
+ */
+struct LoginStateHandler {
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for LoginStateHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let states = self.reloader.lock().unwrap().login_states();
+        let body = serde_json::to_string(&states).unwrap_or(String::new());
+        Ok(Response::with((status::Ok, body)))
+    }
+}
+
+/**
+ * Log in again profiles which are not logged
+ */
+struct LoginHandler {
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for LoginHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        info!("POST /login");
+        let reloader = self.reloader.lock().unwrap();
+        reloader.login_again();
+        let body = serde_json::to_string(&reloader.login_states()).unwrap_or(String::new());
+        Ok(Response::with((status::Ok, body)))
+    }
+}
+
+/**
+ * Send a device command to RORI, and wait for the confirmation of a link
+ * @param bus where answers are published
+ * @param reloader to reach the endpoint
+ * @param command to send
+ * @return the result as JSON
+ */
+fn device_request(bus: &EventBus, reloader: &Arc<Mutex<Reloader>>, command: DeviceCommand) -> IronResult<Response> {
+    let events = bus.subscribe();
+    let profile = match reloader.lock().unwrap().device_command(&command) {
+        Ok(profile) => profile,
+        Err(e) => return Ok(Response::with((status::ServiceUnavailable, e)))
+    };
+    let update = if command.is_confirmed() {
+        match device::wait_update(&events, &profile, Duration::from_secs(ANSWER_TIMEOUT_SECS)) {
+            Ok(update) => update,
+            Err(e) => return Ok(Response::with((status::GatewayTimeout, e)))
+        }
+    } else {
+        DeviceUpdate::Sent(command.to_rori_command(""))
+    };
+    let code = match update {
+        DeviceUpdate::Sent(_) => status::Accepted,
+        DeviceUpdate::Linked => status::Ok,
+        DeviceUpdate::Failed(_) => status::BadRequest,
+    };
+    let body = serde_json::to_string(&update).unwrap_or(String::new());
+    Ok(Response::with((code, body)))
+}
+
+/**
+ * Add a device by its ring id, or link this device without body
+ */
+struct AddDeviceHandler {
+    bus: EventBus,
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for AddDeviceHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let ring_id = match req.get::<bodyparser::Json>() {
+            Ok(Some(json_body)) => json_body["ring_id"].as_str().map(String::from),
+            _ => None
+        };
+        info!("POST /devices: {}", ring_id.as_ref().map(|r| &**r).unwrap_or("this device"));
+        device_request(&self.bus, &self.reloader, DeviceCommand::Add(ring_id))
+    }
+}
+
+/**
+ * Remove a device
+ */
+struct RemoveDeviceHandler {
+    bus: EventBus,
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for RemoveDeviceHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let ring_id = match req.extensions.get::<Router>().and_then(|r| r.find("ring_id")) {
+            Some(ring_id) => String::from(ring_id),
+            None => return Ok(Response::with((status::BadRequest, "Invalid ring id")))
+        };
+        info!("DELETE /devices/{}", ring_id);
+        device_request(&self.bus, &self.reloader, DeviceCommand::Remove(ring_id))
+    }
+}
+
+/**
+ * List trust requests waiting for the user
+ */
+struct ListTrustRequestsHandler {
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for ListTrustRequestsHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let requests = self.reloader.lock().unwrap().trust_requests();
+        let body = serde_json::to_string(&requests).unwrap_or(String::new());
+        Ok(Response::with((status::Ok, body)))
+    }
+}
+
+/**
+ * Accept or discard a trust request
+ */
+struct AnswerTrustHandler {
+    reloader: Arc<Mutex<Reloader>>,
+    decision: TrustDecision,
+}
+
+impl Handler for AnswerTrustHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let from = match req.extensions.get::<Router>().and_then(|r| r.find("from")) {
+            Some(from) => String::from(from),
+            None => return Ok(Response::with((status::BadRequest, "Invalid ring id")))
+        };
+        info!("POST /trust/{}: {:?}", from, self.decision);
+        match self.reloader.lock().unwrap().answer_trust_request(&from, self.decision) {
+            Ok(_) => Ok(Response::with(status::Ok)),
+            Err(e) => Ok(Response::with((status::NotFound, e)))
+        }
+    }
+}
+
+/**
+ * List messages not received by RORI
+ */
+struct UndeliveredHandler {
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for UndeliveredHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let undelivered = self.reloader.lock().unwrap().undelivered();
+        let body = serde_json::to_string(&undelivered).unwrap_or(String::new());
+        Ok(Response::with((status::Ok, body)))
+    }
+}
+
+/**
+ * List messages waiting to be sent
+ */
+struct ListOutboxHandler {
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for ListOutboxHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let entries = self.reloader.lock().unwrap().outbox().list();
+        let body = serde_json::to_string(&entries).unwrap_or(String::new());
+        Ok(Response::with((status::Ok, body)))
+    }
+}
+
+/**
+ * Cancel a message waiting to be sent
+ */
+struct CancelOutboxHandler {
+    reloader: Arc<Mutex<Reloader>>
+}
+
+impl Handler for CancelOutboxHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let id = req.extensions.get::<Router>().and_then(|r| r.find("id"))
+                    .and_then(|id| id.parse::<u64>().ok());
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(Response::with((status::BadRequest, "Invalid message id")))
+        };
+        if self.reloader.lock().unwrap().outbox().remove(id) {
+            info!("DELETE /outbox/{}", id);
+            Ok(Response::with(status::Ok))
+        } else {
+            Ok(Response::with((status::NotFound, "Unknown message")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn moved_listener_frees_its_address() {
+        let mut listener = MovableListener::new();
+        listener.listen("127.0.0.1:0").unwrap();
+        let first = listener.local_addr().unwrap();
+        let mut acceptor = listener.clone();
+        let accepted = thread::spawn(move || acceptor.accept().is_ok());
+        TcpStream::connect(first).unwrap();
+        assert!(accepted.join().unwrap());
+
+        listener.listen("127.0.0.1:0").unwrap();
+        assert!(listener.local_addr().unwrap() != first);
+        // Nothing listens on the previous address anymore
+        assert!(TcpStream::connect(first).is_err());
+
+        listener.close();
+        assert_eq!(listener.bind(), None);
+        assert!(listener.local_addr().is_err());
+    }
+}
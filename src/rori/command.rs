@@ -0,0 +1,421 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use libc;
+use rori::endpoint::Endpoint;
+use rori::handler::DatatypeHandler;
+use rori::interaction::Interaction;
+use serde_json;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use time;
+
+/**
+ * A command which can be launched by RORI
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CommandSpec {
+    /// Program to execute (searched in the restricted PATH)
+    pub program: String,
+    /// Arguments. {0}, {1}... are replaced by the arguments given by RORI,
+    /// {args} by all the arguments
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Ask the user before running the command
+    #[serde(default)]
+    pub confirm: bool,
+    /// Override the default timeout (in seconds)
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+/**
+ * Describe what RORI is allowed to execute on this device
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandPolicy {
+    /// Named commands RORI can run
+    pub allow: HashMap<String, CommandSpec>,
+    /// Programs and arguments always refused
+    pub deny: Vec<String>,
+    /// Directory where commands are executed
+    pub working_dir: String,
+    /// PATH given to commands. No other environment variable is inherited
+    pub path: String,
+    /// Default timeout in seconds
+    pub timeout: u64,
+    /// Maximum CPU time in seconds
+    pub max_cpu_seconds: u64,
+    /// Maximum address space in MiB
+    pub max_memory_mb: u64,
+    /// Where executed commands are logged
+    pub audit_log: String,
+}
+
+impl Default for CommandPolicy {
+    fn default() -> CommandPolicy {
+        CommandPolicy {
+            allow: HashMap::new(),
+            deny: vec![String::from("rm"), String::from("sudo"), String::from("su"),
+                       String::from("dd"), String::from("mkfs"), String::from("shutdown")],
+            working_dir: String::from("."),
+            path: String::from("/usr/local/bin:/usr/bin:/bin"),
+            timeout: 30,
+            max_cpu_seconds: 30,
+            max_memory_mb: 1024,
+            audit_log: String::from("commands.log"),
+        }
+    }
+}
+
+/**
+ * Line written in the audit log
+ */
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    time: String,
+    name: &'a str,
+    argv: &'a [String],
+    status: &'a str,
+}
+
+impl CommandPolicy {
+    /**
+     * Transform a body from RORI into the command to execute
+     * @param self
+     * @param body "name arg1 arg2"
+     * @return (name, argv, spec) or the reason of the refusal
+     */
+    pub fn resolve(&self, body: &str) -> Result<(String, Vec<String>, CommandSpec), String> {
+        let words: Vec<&str> = body.split_whitespace().collect();
+        if words.is_empty() {
+            return Err(String::from("empty command"));
+        }
+        let name = words[0];
+        let args = &words[1..];
+        let spec = match self.allow.get(name) {
+            Some(spec) => spec.clone(),
+            None => return Err(format!("{} is not allowed", name))
+        };
+        let mut argv = vec![spec.program.clone()];
+        for template in &spec.args {
+            argv.push(CommandPolicy::expand(template, args)?);
+        }
+        let program = Path::new(&spec.program).file_name()
+                        .and_then(|p| p.to_str()).unwrap_or("");
+        if self.deny.iter().any(|d| d == name || d == program) {
+            return Err(format!("{} is denied", name));
+        }
+        if let Some(arg) = argv[1..].iter().find(|a| self.deny.contains(a)) {
+            return Err(format!("{} is denied", arg));
+        }
+        Ok((String::from(name), argv, spec))
+    }
+
+    /**
+     * Replace placeholders in an argument template
+     * @param template like "--volume={0}"
+     * @param args given by RORI
+     * @return the argument or an error if an argument is missing
+     */
+    fn expand(template: &str, args: &[&str]) -> Result<String, String> {
+        let mut result = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break
+            };
+            result.push_str(&rest[..start]);
+            let placeholder = &rest[start + 1..end];
+            if placeholder == "args" {
+                result.push_str(&*args.join(" "));
+            } else if let Ok(idx) = placeholder.parse::<usize>() {
+                match args.get(idx) {
+                    Some(arg) => result.push_str(arg),
+                    None => return Err(format!("missing argument {}", idx))
+                }
+            } else {
+                result.push_str(&rest[start..end + 1]);
+            }
+            rest = &rest[end + 1..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    /**
+     * Append an entry to the audit log
+     * @param self
+     * @param name of the command
+     * @param argv executed
+     * @param status result
+     */
+    pub fn audit(&self, name: &str, argv: &[String], status: &str) {
+        info!("command {}: {:?} -> {}", name, argv, status);
+        let entry = AuditEntry {
+            time: time::now().rfc3339().to_string(),
+            name,
+            argv,
+            status,
+        };
+        let line = serde_json::to_string(&entry).unwrap_or(String::new());
+        match OpenOptions::new().create(true).append(true).open(&self.audit_log) {
+            Ok(mut file) => {
+                let _ = writeln!(file, "{}", line);
+            },
+            Err(e) => error!("Can't write audit log {}: {}", self.audit_log, e)
+        }
+    }
+
+    /**
+     * Execute a command in a restricted environment, in a new thread
+     * @param self
+     * @param name of the command
+     * @param argv to execute
+     * @param spec of the command
+     * @return the thread waiting the command
+     */
+    pub fn execute(&self, name: &str, argv: Vec<String>, spec: &CommandSpec) -> thread::JoinHandle<()> {
+        let policy = self.clone();
+        let name = String::from(name);
+        let timeout = Duration::from_secs(spec.timeout.unwrap_or(self.timeout));
+        thread::spawn(move || {
+            let status = match policy.run(&argv, timeout) {
+                Ok(status) => status,
+                Err(e) => format!("failed to start: {}", e)
+            };
+            policy.audit(&name, &argv, &*status);
+        })
+    }
+
+    /**
+     * Run a command and wait for it
+     * @param self
+     * @param argv to execute
+     * @param timeout before killing the command
+     * @return the exit status
+     */
+    fn run(&self, argv: &[String], timeout: Duration) -> io::Result<String> {
+        let mut cmd = Command::new(&argv[0]);
+        cmd.args(&argv[1..])
+           .env_clear()
+           .env("PATH", &self.path)
+           .current_dir(&self.working_dir)
+           .stdin(Stdio::null());
+        let cpu = self.max_cpu_seconds as libc::rlim_t;
+        let memory = (self.max_memory_mb * 1024 * 1024) as libc::rlim_t;
+        unsafe {
+            cmd.pre_exec(move || {
+                // New process group, so a timeout kills every child
+                libc::setsid();
+                let cpu_limit = libc::rlimit { rlim_cur: cpu, rlim_max: cpu };
+                if cpu > 0 && libc::setrlimit(libc::RLIMIT_CPU, &cpu_limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let memory_limit = libc::rlimit { rlim_cur: memory, rlim_max: memory };
+                if memory > 0 && libc::setrlimit(libc::RLIMIT_AS, &memory_limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn()?;
+        let start = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(match status.code() {
+                    Some(code) => format!("exit {}", code),
+                    None => String::from("killed by signal")
+                });
+            }
+            if start.elapsed() > timeout {
+                unsafe {
+                    libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+                }
+                let _ = child.wait();
+                return Ok(String::from("timeout"));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+/**
+ * A command waiting for the confirmation of the user
+ */
+struct PendingCommand {
+    name: String,
+    argv: Vec<String>,
+    spec: CommandSpec,
+    asked: Instant,
+}
+
+/**
+ * Handle the command datatype with a CommandPolicy
+ */
+pub struct CommandHandler {
+    policy: CommandPolicy,
+    pending: Option<PendingCommand>,
+}
+
+impl CommandHandler {
+    /**
+     * @param policy to apply
+     * @return a CommandHandler
+     */
+    pub fn new(policy: CommandPolicy) -> CommandHandler {
+        CommandHandler {
+            policy,
+            pending: None,
+        }
+    }
+}
+
+impl DatatypeHandler for CommandHandler {
+    fn datatype(&self) -> &str {
+        "command"
+    }
+
+    fn handle(&mut self, interaction: &Interaction, endpoint: &mut Endpoint) {
+        let (name, argv, spec) = match self.policy.resolve(&interaction.body) {
+            Ok(command) => command,
+            Err(reason) => {
+                warn!("Refused command {}: {}", interaction.body, reason);
+                self.policy.audit(&interaction.body, &[], &*format!("denied: {}", reason));
+                return;
+            }
+        };
+        if spec.confirm {
+            endpoint.add_to_say_queue(&format!("Do you want me to run {}?", name));
+            self.pending = Some(PendingCommand {
+                name,
+                argv,
+                spec,
+                asked: Instant::now(),
+            });
+        } else {
+            self.policy.execute(&name, argv, &spec);
+        }
+    }
+
+    fn on_user_text(&mut self, text: &str, _endpoint: &mut Endpoint) -> bool {
+        let expired = match self.pending {
+            Some(ref pending) => pending.asked.elapsed() > Duration::from_secs(60),
+            None => return false
+        };
+        if expired {
+            let pending = self.pending.take().unwrap();
+            self.policy.audit(&pending.name, &pending.argv, "not confirmed");
+            return false;
+        }
+        let answer = text.trim().to_lowercase();
+        if ["yes", "y", "ok", "sure", "confirm"].contains(&&*answer) {
+            let pending = self.pending.take().unwrap();
+            self.policy.execute(&pending.name, pending.argv, &pending.spec);
+            true
+        } else if ["no", "n", "cancel", "stop"].contains(&&*answer) {
+            let pending = self.pending.take().unwrap();
+            self.policy.audit(&pending.name, &pending.argv, "declined");
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(program: &str, args: &[&str]) -> CommandSpec {
+        CommandSpec {
+            program: String::from(program),
+            args: args.iter().map(|a| String::from(*a)).collect(),
+            confirm: false,
+            timeout: None,
+        }
+    }
+
+    fn policy(commands: Vec<(&str, CommandSpec)>) -> CommandPolicy {
+        let mut policy = CommandPolicy::default();
+        for (name, spec) in commands {
+            policy.allow.insert(String::from(name), spec);
+        }
+        policy
+    }
+
+    #[test]
+    fn denylist_wins_over_allowlist() {
+        let policy = policy(vec![("clean", spec("/bin/rm", &["-r", "{0}"])),
+                                 ("sudo", spec("/usr/bin/id", &[])),
+                                 ("run", spec("/usr/bin/env", &["{0}"])),
+                                 ("list", spec("ls", &["{0}"]))]);
+        assert_eq!(policy.resolve("clean /tmp").unwrap_err(), "clean is denied");
+        assert_eq!(policy.resolve("sudo").unwrap_err(), "sudo is denied");
+        assert_eq!(policy.resolve("run dd").unwrap_err(), "dd is denied");
+        assert_eq!(policy.resolve("unknown").unwrap_err(), "unknown is not allowed");
+        assert_eq!(policy.resolve("  ").unwrap_err(), "empty command");
+        let (name, argv, _) = policy.resolve("list /tmp").unwrap();
+        assert_eq!(name, "list");
+        assert_eq!(argv, vec!["ls", "/tmp"]);
+    }
+
+    #[test]
+    fn templates_expanded() {
+        let args = ["50", "up"];
+        assert_eq!(CommandPolicy::expand("--volume={0}", &args), Ok(String::from("--volume=50")));
+        assert_eq!(CommandPolicy::expand("{1}-{0}", &args), Ok(String::from("up-50")));
+        assert_eq!(CommandPolicy::expand("{args}", &args), Ok(String::from("50 up")));
+        assert_eq!(CommandPolicy::expand("{name} {0", &args), Ok(String::from("{name} {0")));
+        assert_eq!(CommandPolicy::expand("plain", &[]), Ok(String::from("plain")));
+        assert_eq!(CommandPolicy::expand("{args}", &[]), Ok(String::new()));
+        assert_eq!(CommandPolicy::expand("{2}", &args), Err(String::from("missing argument 2")));
+        // Braces in arguments are not expanded again
+        assert_eq!(CommandPolicy::expand("{0}", &["{1}", "x"]), Ok(String::from("{1}")));
+    }
+
+    #[test]
+    fn shell_metacharacters_stay_in_one_argument() {
+        let policy = policy(vec![("say", spec("/bin/sh", &["-c", "[ \"$#\" = 1 ] && [ \"$1\" = \"$0\" ]",
+                                                          "hi; rm -rf / && $(reboot) | `id` > x", "{args}"]))]);
+        let (_, argv, _) = policy.resolve("say hi; rm -rf / && $(reboot) | `id` > x").unwrap();
+        assert_eq!(argv.len(), 5);
+        assert_eq!(argv[4], "hi; rm -rf / && $(reboot) | `id` > x");
+        // The shell receives the text as one literal argument
+        assert_eq!(policy.run(&argv, Duration::from_secs(5)).unwrap(), "exit 0");
+    }
+}
@@ -0,0 +1,869 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::alarm::AlarmAction;
+use rori::command::CommandPolicy;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Value};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+/// Loaded before every other file
+pub const SYSTEM_CONFIG: &str = "/etc/rori/config.json";
+/// Old location, in the working directory
+pub const LEGACY_CONFIG: &str = "config.json";
+/// Prefix of environment variables. RORI_API__BIND sets api.bind
+pub const ENV_PREFIX: &str = "RORI_";
+/// Data files moved from the working directory to the data directory
+const LEGACY_DATA_FILES: [&str; 2] = ["alarms.json", "commands.log"];
+
+/**
+ * The HTTP API
+ */
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ApiConfig {
+    /// Start the API
+    pub enabled: bool,
+    /// host:port to listen
+    pub bind: String,
+}
+
+/**
+ * How sentences are said
+ */
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TtsConfig {
+    /// mimic, espeak-ng, festival, piper or none (only shown)
+    pub engine: String,
+    /// Voice given to the engine, empty for its default. The model path for piper
+    pub voice: String,
+    /// Speed, 1.0 is the normal speed of the engine
+    pub rate: f32,
+    /// Pitch, 1.0 is the normal pitch of the engine
+    pub pitch: f32,
+    /// Volume, 1.0 is the normal volume of the engine
+    pub volume: f32,
+    /// Keep rendered sentences in the data directory, to say them again without the engine
+    pub cache: bool,
+}
+
+/**
+ * How the voice of the user is recognized
+ */
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SttConfig {
+    /// whisper or none (the microphone is not used)
+    pub engine: String,
+    /// whisper.cpp command line, run for each utterance
+    pub command: String,
+    /// Path of the model given to the engine
+    pub model: String,
+    /// Spoken language, like "en", or "auto"
+    pub language: String,
+    /// Capture device given to arecord, empty for the default one
+    pub device: String,
+}
+
+/**
+ * Optional datatype handlers. text/plain, text/ssml and rori/message are always handled
+ */
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HandlersConfig {
+    pub music: bool,
+    pub command: bool,
+    pub alarm: bool,
+}
+
+/**
+ * Delays used by the client
+ */
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TimeoutsConfig {
+    /// Max time to wait for an answer of the daemon
+    pub daemon_ms: u64,
+    /// Delay between two checks of the say queue
+    pub say_interval_ms: u64,
+    /// Max time to wait for threads when stopping
+    pub shutdown_secs: u64,
+}
+
+/**
+ * An identity on a RORI. Empty values are taken from the top level
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub ring_id: String,
+    pub rori_server: String,
+    pub rori_ring_id: String,
+    pub username: String,
+    /// Beginning of user entries for this profile, when connected with `connect`. Default: the name
+    pub wake_word: String,
+}
+
+/**
+ * Everything configurable. See ConfigLoader for where it comes from
+ */
+#[derive(Serialize, Clone, Debug)]
+pub struct Config {
+    /// Id of the Ring account used by the client
+    pub ring_id: String,
+    /// Address of RORI
+    pub rori_server: String,
+    /// Ring id of RORI
+    pub rori_ring_id: String,
+    /// Username registered on RORI
+    pub username: String,
+    /// Active profile. Its values replace the 4 previous keys. Empty for none
+    pub profile: String,
+    /// Identities which can be used instead of the top level one
+    pub profiles: HashMap<String, Profile>,
+    /// Profiles connected at the same time as the active one
+    pub connect: Vec<String>,
+    /// Top level identity, before the active profile is applied
+    #[serde(skip_serializing)]
+    base: Profile,
+    /// Where alarms and logs are stored. Empty for $XDG_DATA_HOME/rori
+    pub data_dir: String,
+    pub api: ApiConfig,
+    pub tts: TtsConfig,
+    pub stt: SttConfig,
+    pub handlers: HandlersConfig,
+    pub timeouts: TimeoutsConfig,
+    /// Preferred MPRIS player
+    pub music_player: Option<String>,
+    pub commands: CommandPolicy,
+    /// Done when an alarm rings without its own actions
+    pub alarm_actions: Vec<AlarmAction>,
+    /// Alarms missed by less than this delay still ring at startup
+    pub alarm_grace_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            ring_id: String::new(),
+            rori_server: String::new(),
+            rori_ring_id: String::new(),
+            username: String::new(),
+            profile: String::new(),
+            profiles: HashMap::new(),
+            connect: Vec::new(),
+            base: Profile::default(),
+            data_dir: String::new(),
+            api: ApiConfig {
+                enabled: true,
+                bind: String::from("localhost:3000"),
+            },
+            tts: TtsConfig {
+                engine: String::from("mimic"),
+                voice: String::new(),
+                rate: 1.,
+                pitch: 1.,
+                volume: 1.,
+                cache: true,
+            },
+            stt: SttConfig {
+                engine: String::from("none"),
+                command: String::from("whisper-cli"),
+                model: String::new(),
+                language: String::from("en"),
+                device: String::new(),
+            },
+            handlers: HandlersConfig {
+                music: true,
+                command: true,
+                alarm: true,
+            },
+            timeouts: TimeoutsConfig {
+                daemon_ms: 2000,
+                say_interval_ms: 500,
+                shutdown_secs: 5,
+            },
+            music_player: None,
+            commands: CommandPolicy::default(),
+            alarm_actions: vec![
+                AlarmAction::Say(String::from("Hei! Good morning here! It's time to begin new tests!")),
+                AlarmAction::Music(String::from("start")),
+            ],
+            alarm_grace_secs: 600,
+        }
+    }
+}
+
+impl Config {
+    /**
+     * @param self
+     * @return true if a Ring account is configured
+     */
+    pub fn is_configured(&self) -> bool {
+        self.ring_id != ""
+    }
+
+    /**
+     * @param self
+     * @return names of profiles, sorted
+     */
+    pub fn profile_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.profiles.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /**
+     * Replace the top level identity by the one of the active profile.
+     * An unknown profile has no account yet, so it is created at the first run
+     * @param self
+     */
+    fn apply_profile(&mut self) {
+        self.base = Profile {
+            ring_id: self.ring_id.clone(),
+            rori_server: self.rori_server.clone(),
+            rori_ring_id: self.rori_ring_id.clone(),
+            username: self.username.clone(),
+            wake_word: String::new(),
+        };
+        if self.profile == "" {
+            return;
+        }
+        match self.resolve_profile(&self.profile) {
+            Some(profile) => {
+                self.ring_id = profile.ring_id;
+                self.rori_server = profile.rori_server;
+                self.rori_ring_id = profile.rori_ring_id;
+                self.username = profile.username;
+            },
+            None => {
+                self.ring_id = String::new();
+                self.username = String::new();
+            }
+        }
+    }
+
+    /**
+     * @param self
+     * @param name of a profile, empty for the top level identity
+     * @return the identity of the profile, completed by the top level one
+     */
+    pub fn resolve_profile(&self, name: &str) -> Option<Profile> {
+        if name == "" {
+            return Some(self.base.clone());
+        }
+        let mut profile = self.profiles.get(name)?.clone();
+        let complete = |value: &mut String, base: &String| {
+            if value == "" {
+                *value = base.clone();
+            }
+        };
+        complete(&mut profile.ring_id, &self.base.ring_id);
+        complete(&mut profile.rori_server, &self.base.rori_server);
+        complete(&mut profile.rori_ring_id, &self.base.rori_ring_id);
+        complete(&mut profile.username, &self.base.username);
+        complete(&mut profile.wake_word, &String::from(name));
+        Some(profile)
+    }
+
+    /**
+     * @param self
+     * @return the directory containing data files
+     */
+    pub fn data_dir(&self) -> PathBuf {
+        if self.data_dir != "" {
+            return PathBuf::from(&self.data_dir);
+        }
+        xdg_dir("XDG_DATA_HOME", ".local/share").join("rori")
+    }
+
+    /**
+     * @param self
+     * @param path relative to the data directory, or absolute
+     * @return the path of the file
+     */
+    pub fn data_file(&self, path: &str) -> PathBuf {
+        self.data_dir().join(path)
+    }
+
+    /**
+     * Create the data directory and move data files from the working directory
+     * @param self
+     * @return the data directory
+     */
+    pub fn prepare_data_dir(&self) -> io::Result<PathBuf> {
+        let dir = self.data_dir();
+        fs::create_dir_all(&dir)?;
+        for name in LEGACY_DATA_FILES.iter() {
+            let old = Path::new(name);
+            let new = dir.join(name);
+            if !old.exists() || new.exists() {
+                continue;
+            }
+            // rename fails between two filesystems
+            if fs::rename(old, &new).is_err() {
+                fs::copy(old, &new)?;
+                fs::remove_file(old)?;
+            }
+            info!("{} moved to {}", name, new.display());
+        }
+        Ok(dir)
+    }
+
+    /**
+     * Check values which are correctly typed but unusable
+     * @param self
+     * @return (key, problem) for each incorrect value
+     */
+    fn validate(&self) -> Vec<(String, String)> {
+        let mut errors = Vec::new();
+        let mut error = |key: &str, message: &str| errors.push((String::from(key), String::from(message)));
+        let port = self.api.bind.rsplit(':').next().unwrap_or("");
+        if !self.api.bind.contains(':') || port.parse::<u16>().is_err() {
+            error("api.bind", "expected host:port, like localhost:3000");
+        }
+        if !["mimic", "espeak-ng", "festival", "piper", "none"].contains(&&*self.tts.engine) {
+            error("tts.engine", "expected mimic, espeak-ng, festival, piper or none");
+        }
+        if self.tts.engine == "piper" && self.tts.voice == "" {
+            error("tts.voice", "required by piper, path of the .onnx model");
+        }
+        for &(key, value) in &[("tts.rate", self.tts.rate), ("tts.pitch", self.tts.pitch), ("tts.volume", self.tts.volume)] {
+            if !(value > 0. && value <= 4.) {
+                error(key, "expected a number greater than 0 and up to 4, 1 is normal");
+            }
+        }
+        if !["whisper", "none"].contains(&&*self.stt.engine) {
+            error("stt.engine", "expected whisper or none");
+        }
+        if self.stt.engine == "whisper" && self.stt.model == "" {
+            error("stt.model", "required by whisper, path of the ggml model");
+        }
+        if self.timeouts.daemon_ms == 0 {
+            error("timeouts.daemon_ms", "must be greater than 0");
+        }
+        if self.timeouts.say_interval_ms == 0 {
+            error("timeouts.say_interval_ms", "must be greater than 0");
+        }
+        if self.is_configured() && self.rori_server == "" {
+            error("rori_server", "required when ring_id is set");
+        }
+        if self.is_configured() && self.rori_ring_id == "" {
+            error("rori_ring_id", "required when ring_id is set");
+        }
+        for name in self.connect.iter().filter(|name| **name != self.profile) {
+            match self.resolve_profile(name) {
+                Some(ref profile) if profile.ring_id == "" || profile.ring_id == self.ring_id => {
+                    error("connect", &format!("{} needs its own ring_id", name));
+                },
+                Some(_) => {},
+                None => error("connect", &format!("unknown profile {}", name))
+            }
+        }
+        errors
+    }
+}
+
+/**
+ * A bad value in the configuration
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigError {
+    /// Dotted path of the key (e.g. api.bind). Empty for unreadable files
+    pub key: String,
+    /// File or variable giving the value
+    pub source: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key == "" {
+            write!(f, "{}: {}", self.source, self.message)
+        } else {
+            write!(f, "{}: {} (from {})", self.key, self.message, self.source)
+        }
+    }
+}
+
+/**
+ * Merge configuration layers. Later layers override earlier ones, objects are merged key by key.
+ * The standard order is: defaults, system file, legacy file, user file,
+ * environment variables, then --set options.
+ */
+pub struct ConfigLoader {
+    values: Value,
+    // Source of each dotted key
+    origins: HashMap<String, String>,
+    sources: Vec<String>,
+    // Files read, or to read if they are created
+    files: Vec<PathBuf>,
+    errors: Vec<ConfigError>,
+}
+
+impl ConfigLoader {
+    /**
+     * @return a loader with only default values
+     */
+    pub fn new() -> ConfigLoader {
+        ConfigLoader {
+            values: Value::Object(Map::new()),
+            origins: HashMap::new(),
+            sources: Vec::new(),
+            files: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /**
+     * Load every layer in the standard order
+     * @param config_file replaces the user and legacy files if given
+     * @param overrides key=value from the command line
+     * @return the loader
+     */
+    pub fn standard(config_file: Option<&str>, overrides: &[String]) -> ConfigLoader {
+        let mut loader = ConfigLoader::new();
+        loader.add_file(Path::new(SYSTEM_CONFIG));
+        match config_file {
+            Some(file) => loader.add_file(Path::new(file)),
+            None => {
+                if Path::new(LEGACY_CONFIG).exists() {
+                    warn!("{} in the working directory is deprecated, move it to {}",
+                          LEGACY_CONFIG, user_config_file(None).display());
+                }
+                loader.add_file(Path::new(LEGACY_CONFIG));
+                loader.add_file(&user_config_file(None));
+            }
+        }
+        loader.add_env();
+        for assignment in overrides {
+            loader.add_override("--set", assignment);
+        }
+        loader
+    }
+
+    /**
+     * Add a JSON file. Ignored if missing
+     * @param self
+     * @param path of the file
+     */
+    pub fn add_file(&mut self, path: &Path) {
+        self.files.push(path.to_path_buf());
+        let source = path.display().to_string();
+        let mut content = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                if let Err(e) = file.read_to_string(&mut content) {
+                    self.error("", &source, &e.to_string());
+                    return;
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                self.error("", &source, &e.to_string());
+                return;
+            }
+        }
+        match serde_json::from_str::<Value>(&content) {
+            Ok(Value::Object(values)) => self.merge(Value::Object(values), &source),
+            Ok(_) => self.error("", &source, "expected a JSON object"),
+            Err(e) => self.error("", &source, &format!("invalid JSON: {}", e)),
+        }
+    }
+
+    /**
+     * Add RORI_* environment variables. __ separates nested keys
+     * @param self
+     */
+    pub fn add_env(&mut self) {
+        self.add_vars(env::vars().collect());
+    }
+
+    /**
+     * Add variables of configuration keys, like RORI_API__BIND for api.bind.
+     * Other variables, even RORI_* ones, are ignored
+     * @param self
+     * @param vars (name, value) of variables
+     */
+    fn add_vars(&mut self, vars: Vec<(String, String)>) {
+        let mut vars: Vec<(String, String)> = vars.into_iter()
+            .filter(|&(ref name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        vars.sort();
+        for (name, value) in vars {
+            let key = name[ENV_PREFIX.len()..].to_lowercase().replace("__", ".");
+            if default_value(&key).is_none() {
+                debug!("${} ignored, {} is not a configuration key", name, key);
+                continue;
+            }
+            self.set(&format!("${}", name), &key, &value);
+        }
+    }
+
+    /**
+     * Add a key=value assignment
+     * @param self
+     * @param source shown in errors
+     * @param assignment dotted.key=value. Value is parsed as JSON, else used as a string
+     */
+    pub fn add_override(&mut self, source: &str, assignment: &str) {
+        match assignment.find('=') {
+            Some(idx) => {
+                let source = format!("{} {}", source, assignment);
+                self.set(&source, &assignment[..idx], &assignment[idx + 1..]);
+            },
+            None => self.error(assignment, source, "expected key=value"),
+        }
+    }
+
+    /**
+     * @param self
+     * @return files and variables loaded, in order
+     */
+    pub fn sources(&self) -> &Vec<String> {
+        &self.sources
+    }
+
+    /**
+     * @param self
+     * @return every file which can change the configuration, even missing ones
+     */
+    pub fn files(&self) -> &Vec<PathBuf> {
+        &self.files
+    }
+
+    /**
+     * Build the configuration from every layer
+     * @param self
+     * @return the configuration, or every error found
+     */
+    pub fn load(&self) -> Result<Config, Vec<ConfigError>> {
+        let mut errors: Vec<(String, String)> = Vec::new();
+        let default = Config::default();
+        let empty = Map::new();
+        let root = self.values.as_object().unwrap_or(&empty);
+        check_keys(root, "", &["ring_id", "rori_server", "rori_ring_id", "username", "profile", "profiles", "connect", "data_dir",
+                               "api", "tts", "stt", "handlers", "timeouts", "music_player",
+                               "commands", "alarm_actions", "alarm_grace_secs"], &mut errors);
+        let api = section(root, "api", &["enabled", "bind"], &mut errors);
+        let tts = section(root, "tts", &["engine", "voice", "rate", "pitch", "volume", "cache"], &mut errors);
+        let stt = section(root, "stt", &["engine", "command", "model", "language", "device"], &mut errors);
+        let handlers = section(root, "handlers", &["music", "command", "alarm"], &mut errors);
+        let timeouts = section(root, "timeouts", &["daemon_ms", "say_interval_ms", "shutdown_secs"], &mut errors);
+        let mut config = Config {
+            ring_id: field(root, "", "ring_id", default.ring_id, &mut errors),
+            rori_server: field(root, "", "rori_server", default.rori_server, &mut errors),
+            rori_ring_id: field(root, "", "rori_ring_id", default.rori_ring_id, &mut errors),
+            username: field(root, "", "username", default.username, &mut errors),
+            profile: field(root, "", "profile", default.profile, &mut errors),
+            profiles: field(root, "", "profiles", default.profiles, &mut errors),
+            connect: field(root, "", "connect", default.connect, &mut errors),
+            base: Profile::default(),
+            data_dir: field(root, "", "data_dir", default.data_dir, &mut errors),
+            api: ApiConfig {
+                enabled: field(&api, "api", "enabled", default.api.enabled, &mut errors),
+                bind: field(&api, "api", "bind", default.api.bind, &mut errors),
+            },
+            tts: TtsConfig {
+                engine: field(&tts, "tts", "engine", default.tts.engine, &mut errors),
+                voice: field(&tts, "tts", "voice", default.tts.voice, &mut errors),
+                rate: field(&tts, "tts", "rate", default.tts.rate, &mut errors),
+                pitch: field(&tts, "tts", "pitch", default.tts.pitch, &mut errors),
+                volume: field(&tts, "tts", "volume", default.tts.volume, &mut errors),
+                cache: field(&tts, "tts", "cache", default.tts.cache, &mut errors),
+            },
+            stt: SttConfig {
+                engine: field(&stt, "stt", "engine", default.stt.engine, &mut errors),
+                command: field(&stt, "stt", "command", default.stt.command, &mut errors),
+                model: field(&stt, "stt", "model", default.stt.model, &mut errors),
+                language: field(&stt, "stt", "language", default.stt.language, &mut errors),
+                device: field(&stt, "stt", "device", default.stt.device, &mut errors),
+            },
+            handlers: HandlersConfig {
+                music: field(&handlers, "handlers", "music", default.handlers.music, &mut errors),
+                command: field(&handlers, "handlers", "command", default.handlers.command, &mut errors),
+                alarm: field(&handlers, "handlers", "alarm", default.handlers.alarm, &mut errors),
+            },
+            timeouts: TimeoutsConfig {
+                daemon_ms: field(&timeouts, "timeouts", "daemon_ms", default.timeouts.daemon_ms, &mut errors),
+                say_interval_ms: field(&timeouts, "timeouts", "say_interval_ms", default.timeouts.say_interval_ms, &mut errors),
+                shutdown_secs: field(&timeouts, "timeouts", "shutdown_secs", default.timeouts.shutdown_secs, &mut errors),
+            },
+            music_player: field(root, "", "music_player", default.music_player, &mut errors),
+            commands: field(root, "", "commands", default.commands, &mut errors),
+            alarm_actions: field(root, "", "alarm_actions", default.alarm_actions, &mut errors),
+            alarm_grace_secs: field(root, "", "alarm_grace_secs", default.alarm_grace_secs, &mut errors),
+        };
+        config.apply_profile();
+        if errors.is_empty() {
+            errors = config.validate();
+        }
+        let mut all_errors = self.errors.clone();
+        for (key, message) in errors {
+            all_errors.push(ConfigError {
+                source: self.origin(&key),
+                key,
+                message,
+            });
+        }
+        if all_errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(all_errors)
+        }
+    }
+
+    /**
+     * @param self
+     * @param key dotted key
+     * @return the source of the value, or of its nearest parent
+     */
+    fn origin(&self, key: &str) -> String {
+        let mut key = key;
+        loop {
+            if let Some(source) = self.origins.get(key) {
+                return source.clone();
+            }
+            match key.rfind('.') {
+                Some(idx) => key = &key[..idx],
+                None => return String::from("default value")
+            }
+        }
+    }
+
+    /**
+     * Set a key. Its value is parsed as JSON, unless the key is a string,
+     * so a ring id made of digits stays a string
+     * @param self
+     * @param source shown in errors
+     * @param key dotted key
+     * @param value to parse
+     */
+    fn set(&mut self, source: &str, key: &str, value: &str) {
+        let value = match default_value(key) {
+            Some(Value::String(_)) | Some(Value::Null) => Value::String(String::from(value)),
+            _ => serde_json::from_str(value).unwrap_or(Value::String(String::from(value)))
+        };
+        let mut nested = value;
+        for part in key.rsplit('.') {
+            let mut object = Map::new();
+            object.insert(String::from(part), nested);
+            nested = Value::Object(object);
+        }
+        self.merge(nested, source);
+    }
+
+    fn merge(&mut self, values: Value, source: &str) {
+        self.sources.push(String::from(source));
+        merge_into(&mut self.values, values, "", source, &mut self.origins);
+    }
+
+    fn error(&mut self, key: &str, source: &str, message: &str) {
+        self.errors.push(ConfigError {
+            key: String::from(key),
+            source: String::from(source),
+            message: String::from(message),
+        });
+    }
+}
+
+/**
+ * Where the user configuration is
+ * @param config_file given on the command line
+ * @return config_file, else $XDG_CONFIG_HOME/rori/config.json
+ */
+pub fn user_config_file(config_file: Option<&str>) -> PathBuf {
+    match config_file {
+        Some(file) => PathBuf::from(file),
+        None => xdg_dir("XDG_CONFIG_HOME", ".config").join("rori").join("config.json")
+    }
+}
+
+/**
+ * @param var XDG variable
+ * @param fallback relative to $HOME if the variable is not set
+ * @return the directory
+ */
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    match env::var(var) {
+        Ok(ref dir) if Path::new(dir).is_absolute() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(fallback)
+    }
+}
+
+/**
+ * @param key dotted key
+ * @return the default value of the key, None if it is not known
+ */
+fn default_value(key: &str) -> Option<Value> {
+    let parts: Vec<&str> = key.split('.').collect();
+    if let ["profiles", _, field] = parts.as_slice() {
+        // Every profile has the keys of Profile
+        return serde_json::to_value(Profile::default()).ok()?.get(*field).cloned();
+    }
+    let defaults = serde_json::to_value(Config::default()).ok()?;
+    parts.iter().try_fold(&defaults, |value, part| value.get(*part)).cloned()
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix == "" {
+        String::from(key)
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn merge_into(target: &mut Value, values: Value, prefix: &str, source: &str,
+              origins: &mut HashMap<String, String>) {
+    let target = match *target {
+        Value::Object(ref mut target) => target,
+        _ => return
+    };
+    if let Value::Object(values) = values {
+        for (key, value) in values {
+            let full_key = join_key(prefix, &key);
+            let both_objects = value.is_object() && target.get(&key).map_or(false, |v| v.is_object());
+            if both_objects {
+                // Keys not overridden keep their source
+                origins.entry(full_key.clone()).or_insert_with(|| String::from(source));
+                merge_into(target.get_mut(&key).unwrap(), value, &full_key, source, origins);
+            } else {
+                origins.retain(|k, _| !k.starts_with(&format!("{}.", full_key)));
+                origins.insert(full_key, String::from(source));
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+fn check_keys(object: &Map<String, Value>, prefix: &str, known: &[&str], errors: &mut Vec<(String, String)>) {
+    for key in object.keys() {
+        if !known.contains(&&**key) {
+            errors.push((join_key(prefix, key), format!("unknown key, expected one of: {}", known.join(", "))));
+        }
+    }
+}
+
+fn section(root: &Map<String, Value>, key: &str, known: &[&str], errors: &mut Vec<(String, String)>) -> Map<String, Value> {
+    match root.get(key) {
+        Some(&Value::Object(ref object)) => {
+            check_keys(object, key, known, errors);
+            object.clone()
+        },
+        None | Some(&Value::Null) => Map::new(),
+        Some(_) => {
+            errors.push((String::from(key), String::from("expected an object")));
+            Map::new()
+        }
+    }
+}
+
+fn field<T: DeserializeOwned>(object: &Map<String, Value>, prefix: &str, key: &str, default: T,
+                              errors: &mut Vec<(String, String)>) -> T {
+    match object.get(key) {
+        None | Some(&Value::Null) => default,
+        Some(value) => serde_json::from_value(value.clone()).unwrap_or_else(|e| {
+            errors.push((join_key(prefix, key), e.to_string()));
+            default
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|&(name, value)| (String::from(name), String::from(value))).collect()
+    }
+
+    #[test]
+    fn unknown_variables_ignored() {
+        let mut loader = ConfigLoader::new();
+        loader.add_vars(vars(&[("RORI_FOO", "bar"), ("RORI_API__FOO", "1"), ("HOME", "/root")]));
+        assert!(loader.load().is_ok());
+        assert!(loader.sources().is_empty());
+    }
+
+    #[test]
+    fn string_variables_kept_as_strings() {
+        let mut loader = ConfigLoader::new();
+        loader.add_vars(vars(&[("RORI_RING_ID", "1234"), ("RORI_RORI_SERVER", "127.0.0.1:8080"),
+                               ("RORI_RORI_RING_ID", "0123"), ("RORI_TTS__VOICE", "true"),
+                               ("RORI_MUSIC_PLAYER", "42")]));
+        let config = loader.load().unwrap();
+        assert_eq!(config.ring_id, "1234");
+        assert_eq!(config.rori_ring_id, "0123");
+        assert_eq!(config.tts.voice, "true");
+        assert_eq!(config.music_player, Some(String::from("42")));
+    }
+
+    #[test]
+    fn other_variables_parsed_as_json() {
+        let mut loader = ConfigLoader::new();
+        loader.add_vars(vars(&[("RORI_API__ENABLED", "false"), ("RORI_TIMEOUTS__DAEMON_MS", "100"),
+                               ("RORI_TTS__RATE", "1.5"), ("RORI_CONNECT", "[]")]));
+        let config = loader.load().unwrap();
+        assert!(!config.api.enabled);
+        assert_eq!(config.timeouts.daemon_ms, 100);
+        assert_eq!(config.tts.rate, 1.5);
+
+        let mut loader = ConfigLoader::new();
+        loader.add_vars(vars(&[("RORI_API__ENABLED", "maybe")]));
+        let errors = loader.load().unwrap_err();
+        assert_eq!(errors[0].key, "api.enabled");
+        assert_eq!(errors[0].source, "$RORI_API__ENABLED");
+    }
+
+    #[test]
+    fn overrides_keep_strings() {
+        let mut loader = ConfigLoader::new();
+        loader.add_override("--set", "profiles.work.ring_id=42");
+        loader.add_override("--set", "username=007");
+        loader.add_override("--set", "alarm_grace_secs=30");
+        let config = loader.load().unwrap();
+        assert_eq!(config.profiles["work"].ring_id, "42");
+        assert_eq!(config.username, "007");
+        assert_eq!(config.alarm_grace_secs, 30);
+    }
+
+    #[test]
+    fn profile_selected_by_override() {
+        let mut loader = ConfigLoader::new();
+        loader.add_override("--set", "ring_id=base");
+        loader.add_override("--set", "profiles.work.ring_id=work_ring_id");
+        loader.add_override("--set", "profiles.work.rori_server=rori.work");
+        loader.add_override("--set", "profiles.work.rori_ring_id=0123");
+        loader.add_override("--set", "profile=work");
+        let config = loader.load().unwrap();
+        assert_eq!(config.profile, "work");
+        assert_eq!(config.ring_id, "work_ring_id");
+        assert_eq!(config.rori_server, "rori.work");
+        assert_eq!(config.rori_ring_id, "0123");
+    }
+}
@@ -0,0 +1,147 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::delivery::MessageStatus;
+use std::collections::HashMap;
+
+/**
+ * Signals emitted by the daemon and used by the client
+ */
+#[derive(Debug, Clone)]
+pub enum DaemonSignal {
+    IncomingAccountMessage {
+        account_id: String,
+        author_ring_id: String,
+        payloads: HashMap<String, String>,
+    },
+    IncomingTrustRequest {
+        account_id: String,
+        from: String,
+    },
+    AccountsChanged,
+    /// Status of a message sent by an account
+    MessageStatusChanged {
+        account_id: String,
+        message_id: u64,
+        status: MessageStatus,
+    },
+    RegistrationStateChanged {
+        account_id: String,
+        state: String,
+        code: i32,
+        detail: String,
+    },
+}
+
+/**
+ * Blocking source of daemon signals. Created by DaemonBackend::listen
+ * and used from the thread which handles signals.
+ */
+pub trait SignalListener {
+    /**
+     * Wait for the next signal
+     * @param self
+     * @param timeout_ms maximum time to wait
+     * @return the signal, or None if nothing was received before the timeout
+     */
+    fn next_signal(&mut self, timeout_ms: u32) -> Option<DaemonSignal>;
+}
+
+/**
+ * Calls made by the client to the Ring daemon.
+ * DBusBackend talks to a real ring-daemon, MockBackend keeps everything in memory.
+ */
+pub trait DaemonBackend: Send + Sync {
+    /**
+     * Add a RING account
+     * @param self
+     * @param details of the account (Account.alias, Account.type, ...)
+     * @return the new account id
+     */
+    fn add_account(&self, details: HashMap<String, String>) -> Result<String, &'static str>;
+
+    /**
+     * @param self
+     * @return current account ids
+     */
+    fn get_account_list(&self) -> Result<Vec<String>, &'static str>;
+
+    /**
+     * @param self
+     * @param account_id
+     * @return details of the account (Account.enable, Account.alias, Account.username, ...)
+     */
+    fn get_account_details(&self, account_id: &str) -> Result<HashMap<String, String>, &'static str>;
+
+    /**
+     * Register (or unregister) an account
+     * @param self
+     * @param account_id
+     * @param enable
+     */
+    fn send_register(&self, account_id: &str, enable: bool) -> Result<(), &'static str>;
+
+    /**
+     * Send a message
+     * @param self
+     * @param account_id sender
+     * @param to peer ring id
+     * @param payloads datatype -> body
+     * @return the interaction id
+     */
+    fn send_text_message(&self, account_id: &str, to: &str, payloads: HashMap<String, String>) -> Result<u64, &'static str>;
+
+    /**
+     * @param self
+     * @param account_id
+     * @return ring ids of peers waiting for an answer to their trust request
+     */
+    fn get_trust_requests(&self, account_id: &str) -> Result<Vec<String>, &'static str>;
+
+    /**
+     * Accept a trust request, the peer becomes a contact
+     * @param self
+     * @param account_id
+     * @param from ring id of the peer
+     */
+    fn accept_trust_request(&self, account_id: &str, from: &str) -> Result<(), &'static str>;
+
+    /**
+     * Decline a trust request
+     * @param self
+     * @param account_id
+     * @param from ring id of the peer
+     */
+    fn discard_trust_request(&self, account_id: &str, from: &str) -> Result<(), &'static str>;
+
+    /**
+     * Start listening signals from the daemon
+     * @param self
+     * @return a SignalListener
+     */
+    fn listen(&self) -> Result<Box<dyn SignalListener>, &'static str>;
+}
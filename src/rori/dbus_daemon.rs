@@ -0,0 +1,274 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use dbus::{Connection, ConnectionItem, BusType, Message};
+use dbus::arg::{Array, Dict};
+use rori::daemon::{DaemonBackend, DaemonSignal, SignalListener};
+use rori::delivery::MessageStatus;
+use std::collections::HashMap;
+
+/**
+ * Talk to the ring-daemon over the session bus
+ */
+pub struct DBusBackend {
+    ring_dbus: &'static str,
+    configuration_path: &'static str,
+    configuration_iface: &'static str,
+    timeout_ms: i32,
+}
+
+impl DBusBackend {
+    /**
+     * @return a DBusBackend for cx.ring.Ring
+     */
+    pub fn new() -> DBusBackend {
+        DBusBackend::with_timeout(2000)
+    }
+
+    /**
+     * @param timeout_ms max time to wait for a reply of the daemon
+     * @return a DBusBackend for cx.ring.Ring
+     */
+    pub fn with_timeout(timeout_ms: u64) -> DBusBackend {
+        DBusBackend {
+            ring_dbus: "cx.ring.Ring",
+            configuration_path: "/cx/ring/Ring/ConfigurationManager",
+            configuration_iface: "cx.ring.Ring.ConfigurationManager",
+            timeout_ms: timeout_ms.min(i32::max_value() as u64) as i32,
+        }
+    }
+
+    /**
+     * Call a method of the ConfigurationManager and wait for the reply
+     * @param self
+     * @param method to call
+     * @param append add arguments to the message
+     * @return the reply
+     */
+    fn call<F>(&self, method: &str, append: F) -> Result<Message, &'static str>
+        where F: FnOnce(Message) -> Message
+    {
+        let dbus_msg = match Message::new_method_call(self.ring_dbus, self.configuration_path,
+                                                      self.configuration_iface, method) {
+            Ok(msg) => msg,
+            Err(_) => {
+                error!("{} fails. Please verify daemon's API.", method);
+                return Err("Invalid method call");
+            }
+        };
+        let dbus = match Connection::get_private(BusType::Session) {
+            Ok(dbus) => dbus,
+            Err(e) => {
+                error!("Cannot connect to the session bus: {}", e);
+                return Err("Cannot connect to the session bus");
+            }
+        };
+        match dbus.send_with_reply_and_block(append(dbus_msg), self.timeout_ms) {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                error!("{} fails: {}. Is the ring-daemon launched?", method, e);
+                Err("No reply from the daemon")
+            }
+        }
+    }
+}
+
+impl DaemonBackend for DBusBackend {
+    fn add_account(&self, details: HashMap<String, String>) -> Result<String, &'static str> {
+        let details = Dict::new(details.iter());
+        let response = self.call("addAccount", |msg| msg.append1(details))?;
+        // addAccount returns one argument, which is a string.
+        match response.get1::<&str>() {
+            Some(account) => Ok(account.to_string()),
+            None => Err("addAccount returned nothing")
+        }
+    }
+
+    fn get_account_list(&self) -> Result<Vec<String>, &'static str> {
+        let response = self.call("getAccountList", |msg| msg)?;
+        // getAccountList returns one argument, which is an array of strings.
+        let accounts: Array<&str, _> = match response.get1() {
+            Some(array) => array,
+            None => return Err("getAccountList returned nothing")
+        };
+        Ok(accounts.map(|account| account.to_string()).collect())
+    }
+
+    fn get_account_details(&self, account_id: &str) -> Result<HashMap<String, String>, &'static str> {
+        let response = self.call("getAccountDetails", |msg| msg.append1(account_id))?;
+        let details: Dict<&str, &str, _> = match response.get1() {
+            Some(details) => details,
+            None => return Err("getAccountDetails returned nothing")
+        };
+        Ok(details.map(|(key, value)| (key.to_string(), value.to_string())).collect())
+    }
+
+    fn send_register(&self, account_id: &str, enable: bool) -> Result<(), &'static str> {
+        self.call("sendRegister", |msg| msg.append2(account_id, enable))?;
+        Ok(())
+    }
+
+    fn send_text_message(&self, account_id: &str, to: &str, payloads: HashMap<String, String>) -> Result<u64, &'static str> {
+        let payloads = Dict::new(payloads.iter());
+        let response = self.call("sendTextMessage", |msg| msg.append3(account_id, to, payloads))?;
+        // sendTextMessage returns one argument, which is a u64.
+        match response.get1() {
+            Some(interaction_id) => Ok(interaction_id),
+            None => Err("sendTextMessage returned nothing")
+        }
+    }
+
+    fn get_trust_requests(&self, account_id: &str) -> Result<Vec<String>, &'static str> {
+        let response = self.call("getTrustRequests", |msg| msg.append1(account_id))?;
+        // getTrustRequests returns one argument, which is an array of dicts with "from" and "received".
+        let requests: Array<Dict<&str, &str, _>, _> = match response.get1() {
+            Some(requests) => requests,
+            None => return Err("getTrustRequests returned nothing")
+        };
+        Ok(requests.filter_map(|request| {
+            request.filter(|&(key, _)| key == "from").map(|(_, from)| from.to_string()).next()
+        }).collect())
+    }
+
+    fn accept_trust_request(&self, account_id: &str, from: &str) -> Result<(), &'static str> {
+        let response = self.call("acceptTrustRequest", |msg| msg.append2(account_id, from))?;
+        match response.get1() {
+            Some(true) => Ok(()),
+            _ => Err("acceptTrustRequest failed")
+        }
+    }
+
+    fn discard_trust_request(&self, account_id: &str, from: &str) -> Result<(), &'static str> {
+        let response = self.call("discardTrustRequest", |msg| msg.append2(account_id, from))?;
+        match response.get1() {
+            Some(true) => Ok(()),
+            _ => Err("discardTrustRequest failed")
+        }
+    }
+
+    fn listen(&self) -> Result<Box<dyn SignalListener>, &'static str> {
+        // Use another dbus connection to listen signals.
+        let dbus_listener = match Connection::get_private(BusType::Session) {
+            Ok(dbus) => dbus,
+            Err(e) => {
+                error!("Cannot connect to the session bus: {}", e);
+                return Err("Cannot connect to the session bus");
+            }
+        };
+        let members = ["incomingAccountMessage", "incomingTrustRequest", "accountsChanged",
+                       "registrationStateChanged", "accountMessageStatusChanged"];
+        for member in members.iter() {
+            let rule = format!("interface={},member={}", self.configuration_iface, member);
+            if dbus_listener.add_match(&*rule).is_err() {
+                error!("Cannot listen {}", member);
+                return Err("Cannot add match rule");
+            }
+        }
+        Ok(Box::new(DBusSignalListener {
+            conn: dbus_listener,
+            configuration_iface: self.configuration_iface,
+        }))
+    }
+}
+
+/**
+ * Parse signals from a private dbus connection
+ */
+struct DBusSignalListener {
+    conn: Connection,
+    configuration_iface: &'static str,
+}
+
+impl DBusSignalListener {
+    /**
+     * Transform a dbus message in a DaemonSignal
+     * @param self
+     * @param msg to parse
+     * @return the signal if it's interesting
+     */
+    fn parse(&self, msg: &Message) -> Option<DaemonSignal> {
+        let interface = msg.interface()?;
+        if &*interface != self.configuration_iface { return None };
+        let member = msg.member()?;
+        match &*member {
+            "incomingAccountMessage" => {
+                // incomingAccountMessage return four arguments
+                let (account_id, _msg_id, author_ring_id, payloads) = msg.get4::<&str, &str, &str, Dict<&str, &str, _>>();
+                Some(DaemonSignal::IncomingAccountMessage {
+                    account_id: account_id?.to_string(),
+                    author_ring_id: author_ring_id?.to_string(),
+                    payloads: payloads?.map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+                })
+            },
+            "incomingTrustRequest" => {
+                // incomingTrustRequest return four arguments
+                let (account_id, from, _, _) = msg.get4::<&str, &str, Array<u8, _>, u64>();
+                Some(DaemonSignal::IncomingTrustRequest {
+                    account_id: account_id?.to_string(),
+                    from: from?.to_string(),
+                })
+            },
+            "accountsChanged" => Some(DaemonSignal::AccountsChanged),
+            "accountMessageStatusChanged" => {
+                // accountMessageStatusChanged return four arguments
+                let (account_id, message_id, _to, status) = msg.get4::<&str, u64, &str, i32>();
+                Some(DaemonSignal::MessageStatusChanged {
+                    account_id: account_id?.to_string(),
+                    message_id: message_id?,
+                    status: MessageStatus::from_daemon(status?),
+                })
+            },
+            "registrationStateChanged" => {
+                let (account_id, state, code, detail) = msg.get4::<&str, &str, i32, &str>();
+                Some(DaemonSignal::RegistrationStateChanged {
+                    account_id: account_id?.to_string(),
+                    state: state?.to_string(),
+                    code: code.unwrap_or(0),
+                    detail: detail.unwrap_or("").to_string(),
+                })
+            },
+            _ => None
+        }
+    }
+}
+
+impl SignalListener for DBusSignalListener {
+    fn next_signal(&mut self, timeout_ms: u32) -> Option<DaemonSignal> {
+        for item in self.conn.iter(timeout_ms as i32) {
+            match item {
+                ConnectionItem::Signal(ref msg) => {
+                    if let Some(signal) = self.parse(msg) {
+                        return Some(signal);
+                    }
+                },
+                ConnectionItem::Nothing => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+}
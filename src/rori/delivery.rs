@@ -0,0 +1,304 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Sends of a message before giving up
+pub const MAX_SEND_ATTEMPTS: u32 = 3;
+/// First delay before sending a failed message again, doubled at each attempt
+const RETRY_DELAY_SECS: u64 = 5;
+/// Undelivered messages kept to be shown
+const MAX_UNDELIVERED: usize = 50;
+
+/**
+ * Status of a sent message, from the daemon
+ */
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStatus {
+    Unknown,
+    Sending,
+    /// Received by the device of the peer
+    Sent,
+    /// Read by the peer
+    Delivered,
+    Failure,
+}
+
+impl MessageStatus {
+    /**
+     * @param status given by the daemon (MessageStatus of ring-daemon)
+     * @return the status
+     */
+    pub fn from_daemon(status: i32) -> MessageStatus {
+        match status {
+            1 => MessageStatus::Sending,
+            2 => MessageStatus::Sent,
+            3 => MessageStatus::Delivered,
+            4 => MessageStatus::Failure,
+            _ => MessageStatus::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for MessageStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MessageStatus::Unknown => write!(f, "unknown"),
+            MessageStatus::Sending => write!(f, "sending"),
+            MessageStatus::Sent => write!(f, "sent"),
+            MessageStatus::Delivered => write!(f, "delivered"),
+            MessageStatus::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+/**
+ * A message sent to RORI, followed until RORI gets it
+ */
+#[derive(Serialize, Debug, Clone)]
+pub struct TrackedMessage {
+    /// Interaction id of the last send
+    pub id: u64,
+    pub datatype: String,
+    pub body: String,
+    pub status: MessageStatus,
+    /// Number of sends
+    pub attempts: u32,
+    #[serde(skip)]
+    retry_at: Option<Instant>,
+}
+
+/**
+ * A message not received by RORI, with the profile which sent it
+ */
+#[derive(Serialize, Debug, Clone)]
+pub struct Undelivered {
+    /// Empty for the top level identity
+    pub profile: String,
+    #[serde(flatten)]
+    pub message: TrackedMessage,
+}
+
+/**
+ * Follow messages sent by an Endpoint, and decide when to send them again
+ */
+pub struct DeliveryTracker {
+    pending: Vec<TrackedMessage>,
+    undelivered: Vec<TrackedMessage>,
+}
+
+impl DeliveryTracker {
+    /**
+     * @return a DeliveryTracker without message
+     */
+    pub fn new() -> DeliveryTracker {
+        DeliveryTracker {
+            pending: Vec::new(),
+            undelivered: Vec::new(),
+        }
+    }
+
+    /**
+     * Follow a sent message
+     * @param self
+     * @param id interaction id given by the daemon
+     * @param datatype of the message
+     * @param body of the message
+     * @param attempts number of sends, including this one
+     */
+    pub fn track(&mut self, id: u64, datatype: &str, body: &str, attempts: u32) {
+        self.pending.push(TrackedMessage {
+            id,
+            datatype: String::from(datatype),
+            body: String::from(body),
+            status: MessageStatus::Sending,
+            attempts,
+            retry_at: None,
+        });
+    }
+
+    /**
+     * Update the status of a message
+     * @param self
+     * @param id interaction id
+     * @param status new status
+     * @return the message if it will never be delivered
+     */
+    pub fn update(&mut self, id: u64, status: MessageStatus) -> Option<TrackedMessage> {
+        let idx = self.pending.iter().position(|m| m.id == id)?;
+        debug!("Message {}: {}", id, status);
+        match status {
+            MessageStatus::Sent | MessageStatus::Delivered => {
+                self.pending.remove(idx);
+                None
+            },
+            MessageStatus::Failure => self.fail(idx),
+            _ => {
+                self.pending[idx].status = status;
+                None
+            }
+        }
+    }
+
+    /**
+     * Retry a failed message later, or give up
+     * @param self
+     * @param idx of the message in pending
+     * @return the message if it will never be delivered
+     */
+    fn fail(&mut self, idx: usize) -> Option<TrackedMessage> {
+        let message = &mut self.pending[idx];
+        message.status = MessageStatus::Failure;
+        if message.attempts < MAX_SEND_ATTEMPTS {
+            let delay = RETRY_DELAY_SECS << (message.attempts - 1).min(16);
+            warn!("Message {} failed, sending it again in {}s", message.id, delay);
+            message.retry_at = Some(Instant::now() + Duration::from_secs(delay));
+            return None;
+        }
+        let message = self.pending.remove(idx);
+        warn!("Message {} failed {} times: {}", message.id, message.attempts, message.body);
+        self.undelivered.push(message.clone());
+        if self.undelivered.len() > MAX_UNDELIVERED {
+            self.undelivered.remove(0);
+        }
+        Some(message)
+    }
+
+    /**
+     * A message could not be sent again: retry later, or give up
+     * @param self
+     * @param message which could not be sent
+     * @return the message if it will never be delivered
+     */
+    pub fn resend_failed(&mut self, mut message: TrackedMessage) -> Option<TrackedMessage> {
+        message.attempts += 1;
+        self.pending.push(message);
+        let idx = self.pending.len() - 1;
+        self.fail(idx)
+    }
+
+    /**
+     * Take failed messages to send again. Messages still sending are kept:
+     * RORI may get them later, and must not get them twice
+     * @param self
+     * @return messages to send again
+     */
+    pub fn take_due(&mut self) -> Vec<TrackedMessage> {
+        let now = Instant::now();
+        let (due, pending) = self.pending.drain(..)
+            .partition(|m| m.status == MessageStatus::Failure && m.retry_at.map(|when| when <= now).unwrap_or(false));
+        self.pending = pending;
+        due
+    }
+
+    /**
+     * @param self
+     * @return messages not received by RORI yet, then messages given up
+     */
+    pub fn undelivered(&self) -> Vec<TrackedMessage> {
+        self.pending.iter().chain(self.undelivered.iter()).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Make the retry of every failed message due
+     * @param tracker to change
+     */
+    fn retry_now(tracker: &mut DeliveryTracker) {
+        for message in tracker.pending.iter_mut() {
+            if message.retry_at.is_some() {
+                message.retry_at = Some(Instant::now());
+            }
+        }
+    }
+
+    #[test]
+    fn received_messages_forgotten() {
+        let mut tracker = DeliveryTracker::new();
+        tracker.track(1, "text/plain", "hello", 1);
+        tracker.track(2, "text/plain", "next", 1);
+        assert!(tracker.update(1, MessageStatus::Sent).is_none());
+        assert!(tracker.update(2, MessageStatus::Delivered).is_none());
+        assert!(tracker.undelivered().is_empty());
+    }
+
+    #[test]
+    fn sending_messages_never_sent_again() {
+        let mut tracker = DeliveryTracker::new();
+        tracker.track(1, "text/plain", "hello", 1);
+        tracker.update(1, MessageStatus::Sending);
+        retry_now(&mut tracker);
+        assert!(tracker.take_due().is_empty());
+        let undelivered = tracker.undelivered();
+        assert_eq!(undelivered.len(), 1);
+        assert_eq!(undelivered[0].status, MessageStatus::Sending);
+    }
+
+    #[test]
+    fn failed_messages_sent_again_then_given_up() {
+        let mut tracker = DeliveryTracker::new();
+        tracker.track(1, "text/plain", "hello", 1);
+        assert!(tracker.update(1, MessageStatus::Failure).is_none());
+        // Not before the delay
+        assert!(tracker.take_due().is_empty());
+        retry_now(&mut tracker);
+        let due = tracker.take_due();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 1);
+
+        tracker.track(2, "text/plain", "hello", 2);
+        assert!(tracker.update(2, MessageStatus::Failure).is_none());
+        tracker.track(3, "text/plain", "hello", MAX_SEND_ATTEMPTS);
+        let lost = tracker.update(3, MessageStatus::Failure);
+        assert_eq!(lost.map(|m| m.body), Some(String::from("hello")));
+        assert_eq!(tracker.undelivered().len(), 2);
+    }
+
+    #[test]
+    fn failed_resends_reported() {
+        let mut tracker = DeliveryTracker::new();
+        tracker.track(1, "text/plain", "hello", MAX_SEND_ATTEMPTS - 2);
+        tracker.update(1, MessageStatus::Failure);
+        retry_now(&mut tracker);
+        let message = tracker.take_due().remove(0);
+        // The daemon can't be reached: retried later
+        assert!(tracker.resend_failed(message).is_none());
+        retry_now(&mut tracker);
+        let message = tracker.take_due().remove(0);
+        assert_eq!(message.attempts, MAX_SEND_ATTEMPTS - 1);
+        // Last attempt
+        let lost = tracker.resend_failed(message);
+        assert_eq!(lost.map(|m| m.attempts), Some(MAX_SEND_ATTEMPTS));
+        assert_eq!(tracker.undelivered()[0].status, MessageStatus::Failure);
+    }
+}
@@ -0,0 +1,136 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::event::Event;
+use std::fmt;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Max time to wait for RORI to answer a device command
+pub const ANSWER_TIMEOUT_SECS: u64 = 10;
+
+/**
+ * Device management asked by the user
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceCommand {
+    /// Add a device by its ring id, or link the current device if None
+    Add(Option<String>),
+    Remove(String),
+}
+
+impl DeviceCommand {
+    /**
+     * Parse "/devices add [RING_ID]" or "/devices remove RING_ID".
+     * RORI can't list the devices of a username
+     * @param text entered by the user
+     * @return None if not a device command, else the command or an error for the user
+     */
+    pub fn parse(text: &str) -> Option<Result<DeviceCommand, String>> {
+        let mut words = text.split_whitespace();
+        if words.next() != Some("/devices") {
+            return None;
+        }
+        let args: Vec<&str> = words.collect();
+        Some(match args.as_slice() {
+            ["add"] => Ok(DeviceCommand::Add(None)),
+            ["add", ring_id] => Ok(DeviceCommand::Add(Some(String::from(*ring_id)))),
+            ["remove", ring_id] => Ok(DeviceCommand::Remove(String::from(*ring_id))),
+            _ => Err(String::from("Usage: /devices add [RING_ID] | remove RING_ID"))
+        })
+    }
+
+    /**
+     * @param self
+     * @param username registered on RORI
+     * @return the command to send to RORI
+     */
+    pub fn to_rori_command(&self, username: &str) -> String {
+        match *self {
+            DeviceCommand::Add(None) => format!("/link {}", username),
+            DeviceCommand::Add(Some(ref ring_id)) => format!("/add_device {}", ring_id),
+            DeviceCommand::Remove(ref ring_id) => format!("/rm_device {}", ring_id),
+        }
+    }
+
+    /**
+     * Only a link is confirmed by RORI, with {"registered": true}. RORI answers
+     * other commands with a message for the user, said like any other message.
+     * @param self
+     * @return true if the answer of RORI is published as an Event::DeviceUpdate
+     */
+    pub fn is_confirmed(&self) -> bool {
+        *self == DeviceCommand::Add(None)
+    }
+}
+
+/**
+ * Result of a device command
+ */
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "result", content = "value", rename_all = "snake_case")]
+pub enum DeviceUpdate {
+    /// The command was sent, RORI answers with a message for the user
+    Sent(String),
+    /// RORI linked this device to the username
+    Linked,
+    /// RORI refused to link this device
+    Failed(String),
+}
+
+impl fmt::Display for DeviceUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeviceUpdate::Sent(ref command) => write!(f, "{} sent to RORI", command),
+            DeviceUpdate::Linked => write!(f, "Device linked"),
+            DeviceUpdate::Failed(ref reason) => write!(f, "Device management failed: {}", reason),
+        }
+    }
+}
+
+/**
+ * Wait for RORI to confirm a link
+ * @param events subscribed before sending the command
+ * @param profile of the endpoint which sent the command
+ * @param timeout max time to wait
+ * @return the answer, or an error if RORI didn't answer
+ */
+pub fn wait_update(events: &Receiver<Event>, profile: &str, timeout: Duration) -> Result<DeviceUpdate, String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(String::from("No answer from RORI"));
+        }
+        match events.recv_timeout(deadline - now) {
+            Ok(Event::DeviceUpdate(ref from, ref update)) if from == profile => return Ok(update.clone()),
+            Ok(_) => {},
+            Err(RecvTimeoutError::Timeout) => return Err(String::from("No answer from RORI")),
+            Err(RecvTimeoutError::Disconnected) => return Err(String::from("Client stopped")),
+        }
+    }
+}
@@ -25,52 +25,90 @@
  * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  **/
 
-use dbus::{Connection, ConnectionItem, BusType, Message};
-use dbus::arg::{Array, Dict};
 use reqwest;
 use rori::account::Account;
+use rori::config::{Config, TtsConfig};
+use rori::daemon::{DaemonBackend, DaemonSignal};
+use rori::delivery::{DeliveryTracker, TrackedMessage};
+use rori::device::DeviceCommand;
+use rori::event::{Event, EventBus};
+use rori::handler::{DatatypeHandler, HandlerRegistry};
 use rori::interaction::Interaction;
+use rori::login::{CONFIRMATION_TIMEOUT_SECS, LoginMachine, LoginState};
+use rori::markup::Markup;
+use rori::outbox::{Outbox, OutboxEntry};
+use rori::trust::{TrustCommand, TrustDecision, TrustStore};
+use rori::speech::{self, Priority, SpeechQueue};
 use serde_json::{Value, from_str};
 use std::collections::HashMap;
 use std::io::Read;
-use std::process::Command;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use time;
 
+/// First delay before enabling or registering the account again, doubled at each attempt
+const REREGISTER_DELAY_SECS: u64 = 5;
+/// Max delay between two attempts to register the account
+const MAX_REREGISTER_DELAY_SECS: u64 = 300;
+/// Delay before sending the outbox again when the daemon can't be reached
+const FLUSH_RETRY_SECS: u64 = 30;
+
 /**
  * This class is used to load RORI accounts and handle signals from Ring.
  * Should be one unique instance of this and is used to access the RORI server
  */
 pub struct Endpoint {
     pub account: Account,
+    /// Profile of the account. Empty for the top level identity
+    pub profile: String,
 
     rori_server: String,
     rori_ring_id: String,
-    ring_dbus: &'static str,
-    configuration_path: &'static str,
-    configuration_iface: &'static str,
-    to_say: Arc<Mutex<Vec<String>>>,
+    backend: Arc<dyn DaemonBackend>,
+    speech: SpeechQueue,
+    /// Added before each sentence, to know which RORI speaks
+    speech_prefix: String,
+    handlers: Arc<Mutex<HandlerRegistry>>,
+    login: LoginMachine,
+    trust: Arc<Mutex<TrustStore>>,
+    /// If the account is registered on the network
+    registered: bool,
+    /// When to enable or register the account again, and the number of attempts
+    reregister: Option<(Instant, u32)>,
+    /// Messages written on the disk until they are sent
+    outbox: Arc<Mutex<Outbox>>,
+    /// When to try again to send messages of the outbox
+    flush_at: Option<Instant>,
+    delivery: Arc<Mutex<DeliveryTracker>>,
 }
 
 impl Endpoint {
     /**
      * Init the RORI server, the database and retrieve the RING account linked
+     * @param backend daemon to use
      * @param ring_id to retrieve
      * @return a Manager if success, else an error
      */
-    pub fn init(ring_id: &str, rori_server: &str, rori_ring_id: &str) -> Result<Endpoint, &'static str> {
+    pub fn init(backend: Arc<dyn DaemonBackend>, ring_id: &str, rori_server: &str, rori_ring_id: &str) -> Result<Endpoint, &'static str> {
         let mut manager = Endpoint {
             account: Account::null(),
+            profile: String::new(),
 
             rori_server: String::from(rori_server),
             rori_ring_id: String::from(rori_ring_id),
-            ring_dbus: "cx.ring.Ring",
-            configuration_path: "/cx/ring/Ring/ConfigurationManager",
-            configuration_iface: "cx.ring.Ring.ConfigurationManager",
-            to_say: Arc::new(Mutex::new(Vec::new()))
+            backend: backend,
+            speech: SpeechQueue::new(&Config::default().tts, "", EventBus::new()),
+            speech_prefix: String::new(),
+            handlers: Arc::new(Mutex::new(HandlerRegistry::new())),
+            login: LoginMachine::new(),
+            trust: Arc::new(Mutex::new(TrustStore::load(""))),
+            registered: true,
+            reregister: None,
+            outbox: Arc::new(Mutex::new(Outbox::load(""))),
+            flush_at: None,
+            delivery: Arc::new(Mutex::new(DeliveryTracker::new())),
         };
-        manager.account = Endpoint::build_account(ring_id);
+        manager.account = Endpoint::build_account(&manager.backend, ring_id);
         if !manager.account.enabled {
             info!("{} was not enabled. Enable it", ring_id);
             manager.enable_account();
@@ -83,191 +121,390 @@ impl Endpoint {
         Ok(manager)
     }
 
-    pub fn login(manager: Arc<Mutex<Endpoint>>, user_logged: &Arc<Mutex<bool>>, rori_text: Arc<Mutex<String>>,) {
+    /**
+     * Log in to RORI from the beginning: register or link the account if needed
+     * @param manager
+     * @param bus where login states are published
+     */
+    pub fn login(manager: Arc<Mutex<Endpoint>>, bus: &EventBus) {
+        manager.lock().unwrap().login.reset();
+        Endpoint::try_login(manager, bus);
+    }
+
+    /**
+     * Try again a login when its retry is due. Called regularly
+     * @param manager
+     * @param bus where login states are published
+     */
+    pub fn check_login(manager: Arc<Mutex<Endpoint>>, bus: &EventBus) {
+        let due = manager.lock().unwrap().login.due();
+        match due {
+            Some(Ok(reason)) => {
+                info!("Trying to log in again: {}", reason);
+                Endpoint::try_login(manager, bus);
+            },
+            Some(Err(reason)) => {
+                manager.lock().unwrap().set_login_state(LoginState::Failed { reason }, bus);
+            },
+            None => {}
+        }
+    }
+
+    fn try_login(manager: Arc<Mutex<Endpoint>>, bus: &EventBus) {
+        let (rori_server, username, ring_id) = {
+            let mut m = manager.lock().unwrap();
+            if m.rori_server == "" || m.account.ring_id == "" {
+                m.set_login_state(LoginState::Unconfigured, bus);
+                return;
+            }
+            m.set_login_state(LoginState::Resolving, bus);
+            (m.rori_server.clone(), m.account.alias.clone(), m.account.ring_id.clone())
+        };
         // 1. get if ring_id already match to username (=logged)
-        let rori_server = manager.lock().unwrap().rori_server.clone();
-        let username = manager.lock().unwrap().account.alias.clone();
-        let ring_id = manager.lock().unwrap().account.ring_id.clone();
-        let current_username = Endpoint::get_username_from_api(&rori_server, &ring_id);
-        if current_username == username  {
-            *rori_text.lock().unwrap() = String::new();
-            *user_logged.lock().unwrap() = true;
-            info!("{} logged, setting types", username);
-            manager.lock().unwrap().send_interaction_to_rori("/set_types music command alarm", "rori/command");
-            return;
-        } else if current_username != "" {
-            panic!("{} found for current client, but {} wanted. Please check config", current_username, username);
+        match Endpoint::resolve_name(&rori_server, &ring_id) {
+            Ok(Some(ref current_username)) if *current_username == username => {
+                bus.publish(Event::RoriSpeech(String::new()));
+                info!("{} logged, setting types", username);
+                let mut m = manager.lock().unwrap();
+                m.set_login_state(LoginState::LoggedIn, bus);
+                m.announce_datatypes();
+                return;
+            },
+            Ok(Some(current_username)) => {
+                let reason = format!("{} found for current client, but {} wanted. Please check config",
+                                     current_username, username);
+                manager.lock().unwrap().set_login_state(LoginState::Failed { reason }, bus);
+                return;
+            },
+            Ok(None) => {},
+            Err(e) => {
+                manager.lock().unwrap().retry_login(&format!("Cannot reach {}: {}", rori_server, e));
+                return;
+            }
         }
         // 2. if not, get if username already registered
-        let rori_server = manager.lock().unwrap().rori_server.clone();
-        let acc_linked = manager.lock().unwrap().account.clone();
-        let username_registered = Endpoint::get_ring_id(&rori_server, &acc_linked.alias) != "";
+        let username_registered = match Endpoint::resolve_addr(&rori_server, &username) {
+            Ok(addr) => addr.is_some(),
+            Err(e) => {
+                manager.lock().unwrap().retry_login(&format!("Cannot reach {}: {}", rori_server, e));
+                return;
+            }
+        };
+        let mut m = manager.lock().unwrap();
         if username_registered {
             // 3. if already registered, /link
             info!("{} needs to be linked", username);
-            manager.lock().unwrap().send_interaction_to_rori(&*format!("/link {}", acc_linked.alias), "rori/command");
-            manager.lock().unwrap().add_to_say_queue(&String::from("Linking with another device..."));
+            m.set_login_state(LoginState::Linking, bus);
+            m.send_interaction_to_rori(&*format!("/link {}", username), "rori/command");
+            m.add_to_say_queue(&String::from("Linking with another device..."));
         } else {
             // 4. else /register
             info!("registering {}...", username);
-            manager.lock().unwrap().send_interaction_to_rori(&*format!("/register {}", acc_linked.alias), "rori/command");
-            manager.lock().unwrap().add_to_say_queue(&String::from("Waiting registering confirmation..."));
+            m.set_login_state(LoginState::Registering, bus);
+            m.send_interaction_to_rori(&*format!("/register {}", username), "rori/command");
+            m.add_to_say_queue(&String::from("Waiting registering confirmation..."));
         }
+        m.set_login_state(LoginState::AwaitingConfirmation, bus);
+        m.login.retry_in(Duration::from_secs(CONFIRMATION_TIMEOUT_SECS), "No confirmation from RORI");
     }
 
     /**
-     * Listen from interresting signals from dbus and call handlers
+     * Use decisions shared with other endpoints
      * @param self
+     * @param trust store of decisions
      */
-    pub fn handle_signals(manager: Arc<Mutex<Endpoint>>, stop: Arc<AtomicBool>, rori_text: Arc<Mutex<String>>, user_text: Arc<Mutex<String>>, user_logged: Arc<Mutex<bool>>) {
-        // Use another dbus connection to listen signals.
-        let dbus_listener = Connection::get_private(BusType::Session).unwrap();
-        dbus_listener.add_match("interface=cx.ring.Ring.ConfigurationManager,member=incomingAccountMessage").unwrap();
-        dbus_listener.add_match("interface=cx.ring.Ring.ConfigurationManager,member=incomingTrustRequest").unwrap();
-        dbus_listener.add_match("interface=cx.ring.Ring.ConfigurationManager,member=accountsChanged").unwrap();
-        dbus_listener.add_match("interface=cx.ring.Ring.ConfigurationManager,member=registrationStateChanged").unwrap();
-        let rori_ring_id = manager.lock().unwrap().rori_ring_id.clone();
-        // For each signals, call handlers.
-        for i in dbus_listener.iter(100) {
+    pub fn set_trust_store(&mut self, trust: Arc<Mutex<TrustStore>>) {
+        self.trust = trust;
+    }
 
-            let mut m = manager.lock().unwrap();
-            m.handle_accounts_signals(&i);
-            m.handle_registration_changed(&i);
-            if let Some((account_id, interaction)) = m.handle_interactions(&i) {
+    /**
+     * @param self
+     * @return decisions taken for trust requests
+     */
+    pub fn trust_store(&self) -> Arc<Mutex<TrustStore>> {
+        self.trust.clone()
+    }
+
+    /**
+     * Answer a trust request if RORI sent it or if the peer is known,
+     * else ask the user
+     * @param self
+     * @param from ring id of the peer
+     */
+    fn on_trust_request(&mut self, from: &str) {
+        let decision = if from == self.rori_ring_id {
+            Some(TrustDecision::Accepted)
+        } else {
+            self.trust.lock().unwrap().get(from)
+        };
+        let decision = match decision {
+            Some(decision) => decision,
+            None => {
+                self.add_to_say_queue(&format!("Trust request from {}. Answer with /trust accept {} or /trust discard {}",
+                                               from, from, from));
+                return;
+            }
+        };
+        info!("Trust request from {} {:?} automatically", from, decision);
+        if let Err(e) = self.answer_trust_request(from, decision) {
+            error!("Can't answer the trust request from {}: {}", from, e);
+        }
+    }
+
+    /**
+     * Handle trust requests received while the client was stopped
+     * @param self
+     */
+    pub fn process_trust_requests(&mut self) {
+        for from in self.trust_requests() {
+            self.on_trust_request(&from);
+        }
+    }
+
+    /**
+     * @param self
+     * @return ring ids of peers waiting for an answer
+     */
+    pub fn trust_requests(&self) -> Vec<String> {
+        self.backend.get_trust_requests(&self.account.id).unwrap_or_else(|e| {
+            error!("Can't get trust requests: {}", e);
+            Vec::new()
+        })
+    }
+
+    /**
+     * Accept or discard a trust request, and remember the decision
+     * @param self
+     * @param from ring id of the peer
+     * @param decision to apply
+     * @return an error if the daemon refused
+     */
+    pub fn answer_trust_request(&self, from: &str, decision: TrustDecision) -> Result<(), &'static str> {
+        match decision {
+            TrustDecision::Accepted => self.backend.accept_trust_request(&self.account.id, from)?,
+            TrustDecision::Discarded => self.backend.discard_trust_request(&self.account.id, from)?,
+        }
+        if from != self.rori_ring_id {
+            self.trust.lock().unwrap().remember(from, decision);
+        }
+        Ok(())
+    }
+
+    /**
+     * Run a trust command typed by the user, and say the result
+     * @param self
+     * @param command to run
+     */
+    fn run_trust_command(&mut self, command: &TrustCommand) {
+        let (from, decision) = match *command {
+            TrustCommand::List => {
+                let requests = self.trust_requests();
+                if requests.is_empty() {
+                    self.add_to_say_queue(&String::from("No trust request"));
+                } else {
+                    self.add_to_say_queue(&format!("Trust requests from: {}", requests.join(", ")));
+                }
+                return;
+            },
+            TrustCommand::Accept(ref from) => (from, TrustDecision::Accepted),
+            TrustCommand::Discard(ref from) => (from, TrustDecision::Discarded),
+        };
+        let result = match self.answer_trust_request(from, decision) {
+            Ok(_) if decision == TrustDecision::Accepted => format!("{} accepted", from),
+            Ok(_) => format!("{} discarded", from),
+            Err(e) => format!("Can't answer {}: {}", from, e),
+        };
+        self.add_to_say_queue(&result);
+    }
+
+    /**
+     * Send a device command to RORI. The answer is published as an Event::DeviceUpdate
+     * @param self
+     * @param command to send
+     * @return false if the command can't be sent
+     */
+    pub fn send_device_command(&mut self, command: &DeviceCommand) -> bool {
+        let order = command.to_rori_command(&self.account.alias);
+        info!("Device command: {}", order);
+        self.send_or_queue(&order, "rori/command")
+    }
+
+    /**
+     * Try to reach the nameserver again later
+     * @param self
+     * @param reason of the failure
+     */
+    fn retry_login(&mut self, reason: &str) {
+        let delay = self.login.next_delay();
+        warn!("{}. Trying again in {}s", reason, delay.as_secs());
+        self.login.retry_in(delay, reason);
+    }
+
+    /**
+     * @param self
+     * @return where the endpoint is in the login
+     */
+    pub fn login_state(&self) -> LoginState {
+        self.login.state().clone()
+    }
+
+    /**
+     * Change the login state, and publish it if it changed
+     * @param self
+     * @param state new state
+     * @param bus where the state is published
+     */
+    pub fn set_login_state(&mut self, state: LoginState, bus: &EventBus) {
+        if !self.login.set(state.clone()) {
+            return;
+        }
+        info!("{}: login {}", self.account.id, state);
+        if let LoginState::Failed { ref reason } = state {
+            error!("Login failed: {}", reason);
+            self.add_to_say_queue(&format!("Can't log in: {}", reason));
+        }
+        bus.publish(Event::LoginStateChanged(self.profile.clone(), state));
+    }
+
+    /**
+     * Call handlers for a signal of the daemon. Signals for other accounts are ignored
+     * @param self
+     * @param signal received
+     * @param bus where login states are published
+     */
+    pub fn handle_signal(&mut self, signal: &DaemonSignal, bus: &EventBus) {
+        self.handle_accounts_signals(signal, bus);
+        self.handle_registration_changed(signal, bus);
+        self.handle_message_status(signal);
+        if let Some((account_id, interaction)) = self.handle_interactions(signal) {
+            if account_id == self.account.id {
                 info!("New interation for {}: {}", account_id, interaction);
-                if account_id == m.account.id {
-                    if interaction.author_ring_id == rori_ring_id && interaction.body != "" {
-                        if interaction.datatype == "rori/message" {
-                            match from_str(&interaction.body) {
-                                Ok(j) => {
-                                    // Only if rori order
-                                    let j: Value = j;
-                                    if j["registered"].to_string() == "true" {
-                                        *user_logged.lock().unwrap() = true;
-                                        m.send_interaction_to_rori("/set_types music command alarm", "rori/command");
-                                        *rori_text.lock().unwrap() = String::new();
-                                    }
-                                },
-                                _ => {
-                                    warn!("Message received, but not recognized: {}", interaction.body);
-                                }
-                            };
-                        } else if interaction.datatype == "text/plain" {
-                            m.add_to_say_queue(&interaction.body);
-                        } else if interaction.datatype == "music" {
-                            Command::new("python3")
-                                .arg("scripts/music.py")
-                                .arg(&interaction.body)
-                                .spawn()
-                                .expect("music.py command failed to start");
-                        } else if interaction.datatype == "alarm" {
-                            Command::new("python3")
-                                .arg("scripts/alarm.py")
-                                .arg(&interaction.body)
-                                .spawn()
-                                .expect("alarm.py command failed to start");
-                        } else if interaction.datatype == "command" {
-                            Command::new("sh")
-                                .arg("-c")
-                                .arg(&interaction.body)
-                                .spawn()
-                                .expect("command failed to start");
-                        }
+                if interaction.author_ring_id == self.rori_ring_id && interaction.body != "" {
+                    let handlers = self.handlers.clone();
+                    let handled = HandlerRegistry::handle(&handlers, &interaction, self);
+                    if !handled {
+                        warn!("No handler for datatype: {}", interaction.datatype);
                     }
                 }
-            };
-            if let Some((account_id, from)) = m.handle_requests(&i) {
-                if account_id == m.account.id {
-                    info!("New request from {}", from);
-                    // TODO
-                }
-            };
-            let utext = user_text.lock().unwrap().clone();
-            if utext != "" {
-                *user_text.lock().unwrap() = String::new();
-
-                let mut datatype = "text/plain";
-                if m.is_a_command(&utext) {
-                    datatype = "rori/command";
-                }
-                m.send_interaction_to_rori(&*utext, datatype);
             }
-            if stop.load(Ordering::SeqCst) {
-                break;
+        };
+        if let Some((account_id, from)) = self.handle_requests(signal) {
+            if account_id == self.account.id {
+                info!("New request from {}", from);
+                self.on_trust_request(&from);
             }
+        };
+    }
+
+    /**
+     * Give a user entry to handlers, or send it to RORI
+     * @param self
+     * @param text entered by the user
+     */
+    pub fn handle_user_entry(&mut self, text: &str) {
+        // Local commands and stop requests are handled by their own subscribers
+        if text == "" || Endpoint::is_a_local_command(text) || speech::is_stop_command(text) {
+            return;
+        }
+        match TrustCommand::parse(text) {
+            Some(Ok(command)) => {
+                self.run_trust_command(&command);
+                return;
+            },
+            Some(Err(usage)) => {
+                self.add_to_say_queue(&usage);
+                return;
+            },
+            None => {}
+        }
+        match DeviceCommand::parse(text) {
+            Some(Ok(command)) => {
+                self.send_device_command(&command);
+                return;
+            },
+            Some(Err(usage)) => {
+                self.add_to_say_queue(&usage);
+                return;
+            },
+            None => {}
+        }
+        let handlers = self.handlers.clone();
+        let consumed = HandlerRegistry::handle_user_text(&handlers, text, self);
+        if !consumed {
+            let mut datatype = "text/plain";
+            if self.is_a_command(&String::from(text)) {
+                datatype = "rori/command";
+            }
+            self.send_or_queue(text, datatype);
         }
     }
 
     pub fn get_ring_id(nameserver: &String, name: &String) -> String {
-        // NOTE/TODO: Remove this line when RORI will gennerate certificate with Let's Encrypt
-        // For now, self signed certificate and local dev, so it's OK
-        let client = reqwest::ClientBuilder::new()
-                    .danger_accept_invalid_certs(true)
-                    .build().unwrap();
+        Endpoint::resolve_addr(nameserver, name).ok().and_then(|addr| addr).unwrap_or_default()
+    }
 
-        let mut ns = nameserver.clone();
-        if ns.find("http") != Some(0) {
-            ns = String::from("https://") + &*ns;
-        }
-        let mut res = match client.get(&*format!("{}/name/{}", ns, name)).send() {
-            Ok(res) => res,
-            _ => {
-                return String::new();
-            }
+    pub fn get_username_from_api(nameserver: &String, ring_id: &String) -> String {
+        Endpoint::resolve_name(nameserver, ring_id).ok().and_then(|name| name).unwrap_or_default()
+    }
+
+    /**
+     * @param nameserver to ask
+     * @param name registered
+     * @return the ring id of the name, None if not registered, or an error if the nameserver is unreachable
+     */
+    pub fn resolve_addr(nameserver: &str, name: &str) -> Result<Option<String>, String> {
+        let j = match Endpoint::query_nameserver(nameserver, &*format!("name/{}", name))? {
+            Some(j) => j,
+            None => return Ok(None)
         };
+        match j["addr"].as_str() {
+            Some(addr) if addr.trim_start_matches("0x") != "" => Ok(Some(String::from(addr.trim_start_matches("0x")))),
+            _ => Ok(None)
+        }
+    }
 
-        let mut body: String = String::new();
-        let _ = res.read_to_string(&mut body);
-        match from_str(&body) {
-            Ok(j) => {
-                // Only if rori order
-                let j: Value = j;
-                let addr = j["addr"].to_string();
-                if addr.len() > 4 {
-                    return String::from(&addr[3..addr.len()-1]);
-                }
-                return String::new();
-            },
-            _ => {
-                return String::new();
-            }
+    /**
+     * @param nameserver to ask
+     * @param ring_id to find
+     * @return the name registered for ring_id, None if not registered, or an error if the nameserver is unreachable
+     */
+    pub fn resolve_name(nameserver: &str, ring_id: &str) -> Result<Option<String>, String> {
+        let j = match Endpoint::query_nameserver(nameserver, &*format!("addr/{}", ring_id))? {
+            Some(j) => j,
+            None => return Ok(None)
         };
+        match j["name"].as_str() {
+            Some(name) if name != "" => Ok(Some(String::from(name))),
+            _ => Ok(None)
+        }
     }
 
-    pub fn get_username_from_api(nameserver: &String, ring_id: &String) -> String {
+    /**
+     * @param nameserver to ask
+     * @param path asked, like name/foo
+     * @return the JSON answer, None if not found, or an error if the nameserver is unreachable
+     */
+    fn query_nameserver(nameserver: &str, path: &str) -> Result<Option<Value>, String> {
         // NOTE/TODO: Remove this line when RORI will gennerate certificate with Let's Encrypt
         // For now, self signed certificate and local dev, so it's OK
         let client = reqwest::ClientBuilder::new()
                     .danger_accept_invalid_certs(true)
-                    .build().unwrap();
+                    .build().map_err(|e| e.to_string())?;
 
-        let mut ns = nameserver.clone();
+        let mut ns = String::from(nameserver);
         if ns.find("http") != Some(0) {
             ns = String::from("https://") + &*ns;
         }
-        let mut res = match client.get(&*format!("{}/addr/{}", ns, ring_id)).send() {
-            Ok(res) => res,
-            _ => {
-                return String::new();
-            }
-        };
-
+        let mut res = client.get(&*format!("{}/{}", ns, path)).send().map_err(|e| e.to_string())?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !res.status().is_success() {
+            return Err(format!("nameserver answered {}", res.status()));
+        }
         let mut body: String = String::new();
-        let _ = res.read_to_string(&mut body);
-        match from_str(&body) {
-            Ok(j) => {
-                // Only if rori order
-                let j: Value = j;
-                if j["name"].is_null() {
-                    return String::new();
-                } else {
-                    return j["name"].as_str().unwrap_or("").to_string();
-                }
-            },
-            _ => {
-                return String::new();
-            }
-        };
+        res.read_to_string(&mut body).map_err(|e| e.to_string())?;
+        from_str(&body).map(Some).map_err(|e| e.to_string())
     }
 
     /**
@@ -278,7 +515,7 @@ impl Endpoint {
      * @param text to verify
      * @return true if it's a correct command
      */
-    fn is_a_command(&self, text: &String) -> bool {
+    pub fn is_a_command(&self, text: &String) -> bool {
         let v: Vec<&str> = text.split(' ').collect();
         if v.len() == 0 {
             return false
@@ -288,146 +525,192 @@ impl Endpoint {
         whitelist_commands.contains(&v[0])
     }
 
-    pub fn mimic(body: &String, rori_text: &Arc<Mutex<String>>) {
-        *rori_text.lock().unwrap() = body.clone();
-        Command::new("mimic")
-            .arg("-t")
-            .arg(body)
-            .arg("-voice")
-            .arg("slt")
-            .output()
-            .expect("mimic command failed to start");
+    /**
+     * Detect commands for the client itself, never sent to RORI
+     * @param text to verify
+     * @return true if it's a local command
+     */
+    pub fn is_a_local_command(text: &str) -> bool {
+        text == "/profile" || text.starts_with("/profile ")
+    }
+
+    /**
+     * Add a handler for a new datatype. Must be called before the login
+     * to be announced to RORI.
+     * @param self
+     * @param handler to add
+     */
+    pub fn register_handler(&self, handler: Box<dyn DatatypeHandler>) {
+        self.handlers.lock().unwrap().register(handler);
     }
 
-    pub fn add_to_say_queue(&mut self, body: &String) {
-        self.to_say.lock().unwrap().push(body.clone());
+    /**
+     * Stop handling a datatype. RORI is informed at the next announce_datatypes
+     * @param self
+     * @param datatype to remove
+     */
+    pub fn unregister_handler(&self, datatype: &str) {
+        self.handlers.lock().unwrap().unregister(datatype);
     }
 
-    pub fn process_say(manager: Arc<Mutex<Endpoint>>, rori_text: &Arc<Mutex<String>>) {
-        let manager = manager.lock().unwrap();
-        let mut m = manager.to_say.lock().unwrap();
-        let to_say = m.clone();
-        m.clear();
-        for sentences in to_say {
-            Endpoint::mimic(&sentences, rori_text);
+    /**
+     * Use another Ring account. Handlers and the say queue are kept. A new login is needed
+     * @param self
+     * @param ring_id id of the account to use
+     * @param rori_server address of RORI
+     * @param rori_ring_id ring id of RORI
+     * @return an error if the account can't be loaded. The current account is kept
+     */
+    pub fn switch_account(&mut self, ring_id: &str, rori_server: &str, rori_ring_id: &str) -> Result<(), &'static str> {
+        let account = Endpoint::build_account(&self.backend, ring_id);
+        if account.ring_id == "" {
+            return Err("Cannot build RORI account, please check configuration");
+        }
+        self.account = account;
+        self.registered = true;
+        self.reregister = None;
+        if !self.account.enabled {
+            info!("{} was not enabled. Enable it", ring_id);
+            self.enable_account();
         }
+        self.set_rori(rori_server, rori_ring_id);
+        info!("{}: Account loaded", self.account.id);
+        Ok(())
+    }
+
+    /**
+     * @param self
+     * @return the daemon used
+     */
+    pub fn backend(&self) -> Arc<dyn DaemonBackend> {
+        self.backend.clone()
+    }
+
+    /**
+     * Change the RORI to talk to. A new login is needed
+     * @param self
+     * @param rori_server address of RORI
+     * @param rori_ring_id ring id of RORI
+     */
+    pub fn set_rori(&mut self, rori_server: &str, rori_ring_id: &str) {
+        self.rori_server = String::from(rori_server);
+        self.rori_ring_id = String::from(rori_ring_id);
+    }
+
+    /**
+     * Send supported datatypes to RORI
+     * @param self
+     */
+    pub fn announce_datatypes(&self) {
+        let types = self.handlers.lock().unwrap().announced_types();
+        self.send_interaction_to_rori(&*format!("/set_types {}", types.join(" ")), "rori/command");
+    }
+
+    /**
+     * Change how sentences are said
+     * @param self
+     * @param tts engine to use
+     */
+    pub fn set_tts(&mut self, tts: TtsConfig) {
+        self.speech.set_tts(&tts);
+    }
+
+    /**
+     * Use the speech queue of the client
+     * @param self
+     * @param speech queue shared by endpoints
+     * @param prefix added before each sentence, to know which RORI speaks
+     */
+    pub fn set_speech(&mut self, speech: SpeechQueue, prefix: &str) {
+        self.speech = speech;
+        self.speech_prefix = String::from(prefix);
+    }
+
+    /**
+     * @param self
+     * @return the queue of sentences to say, to say things from other threads
+     */
+    pub fn speech(&self) -> SpeechQueue {
+        self.speech.clone()
+    }
+
+    pub fn add_to_say_queue(&mut self, body: &String) {
+        self.speech.push(&format!("{}{}", self.speech_prefix, body), Priority::Chat);
+    }
+
+    pub fn add_markup_to_say_queue(&mut self, markup: Markup) {
+        self.speech.push_markup(markup.prefixed(&self.speech_prefix), Priority::Chat);
     }
 
     // Helpers
 
     /**
      * Add a RING account
+     * @param backend daemon to use
      * @param main_info path or alias
      * @param password
      * @param from_archive if main_info is a path
      */
-    pub fn add_account(main_info: &str, password: &str, from_archive: bool) {
-        let mut details: HashMap<&str, &str> = HashMap::new();
+    pub fn add_account(backend: &Arc<dyn DaemonBackend>, main_info: &str, password: &str, from_archive: bool) {
+        let mut details: HashMap<String, String> = HashMap::new();
         if from_archive {
-            details.insert("Account.archivePath", main_info);
+            details.insert(String::from("Account.archivePath"), String::from(main_info));
         } else {
-            details.insert("Account.alias", main_info);
-        }
-        details.insert("Account.type", "RING");
-        details.insert("Account.archivePassword", password);
-        let details = Dict::new(details.iter());
-        let dbus_msg = Message::new_method_call("cx.ring.Ring", "/cx/ring/Ring/ConfigurationManager",
-                                                "cx.ring.Ring.ConfigurationManager",
-                                                "addAccount");
-        if !dbus_msg.is_ok() {
-            error!("addAccount fails. Please verify daemon's API.");
-            return;
+            details.insert(String::from("Account.alias"), String::from(main_info));
         }
-        let conn = Connection::get_private(BusType::Session);
-        if !conn.is_ok() {
-            return;
+        details.insert(String::from("Account.type"), String::from("RING"));
+        details.insert(String::from("Account.archivePassword"), String::from(password));
+        match backend.add_account(details) {
+            Ok(account_added) => info!("New account: {:?}", account_added),
+            Err(e) => error!("addAccount fails: {}", e)
         }
-        let dbus = conn.unwrap();
-        let response = dbus.send_with_reply_and_block(dbus_msg.unwrap()
-                                                                .append1(details), 2000).unwrap();
-        // addAccount returns one argument, which is a string.
-        let account_added: &str  = match response.get1() {
-            Some(account) => account,
-            None => ""
-        };
-        info!("New account: {:?}", account_added);
     }
 
     /**
      * Get current ring accounts
+     * @param backend daemon to use
      * @return current accounts
      */
-    pub fn get_account_list() -> Vec<Account> {
-        let mut account_list: Vec<Account> = Vec::new();
-        let dbus_msg = Message::new_method_call("cx.ring.Ring", "/cx/ring/Ring/ConfigurationManager",
-                                                "cx.ring.Ring.ConfigurationManager",
-                                                "getAccountList");
-        if !dbus_msg.is_ok() {
-            error!("getAccountList fails. Please verify daemon's API.");
-            return account_list;
-        }
-        let conn = Connection::get_private(BusType::Session);
-        if !conn.is_ok() {
-            return account_list;
-        }
-        let dbus = conn.unwrap();
-        let response = dbus.send_with_reply_and_block(dbus_msg.unwrap(), 2000).unwrap();
-        // getAccountList returns one argument, which is an array of strings.
-        let accounts: Array<&str, _>  = match response.get1() {
-            Some(array) => array,
-            None => return account_list
+    pub fn get_account_list(backend: &Arc<dyn DaemonBackend>) -> Vec<Account> {
+        let accounts = match backend.get_account_list() {
+            Ok(accounts) => accounts,
+            Err(e) => {
+                error!("getAccountList fails: {}", e);
+                return Vec::new();
+            }
         };
-        for account in accounts {
-            account_list.push(Endpoint::build_account(account));
-        }
-        account_list
+        accounts.iter()
+                .map(|account| Endpoint::build_account(backend, account))
+                .collect()
     }
 
 // Private stuff
     /**
      * Build a new account with an id from the daemon
+     * @param backend daemon to use
      * @param id the account id to build
      * @return the account retrieven
      */
-    fn build_account(id: &str) -> Account {
-        let dbus_msg = Message::new_method_call("cx.ring.Ring", "/cx/ring/Ring/ConfigurationManager",
-                                                "cx.ring.Ring.ConfigurationManager",
-                                                "getAccountDetails");
-        if !dbus_msg.is_ok() {
-            error!("getAccountDetails fails. Please verify daemon's API.");
-            return Account::null();
-        }
-        let conn = Connection::get_private(BusType::Session);
-        if !conn.is_ok() {
-            error!("connection not ok.");
-            return Account::null();
-        }
-        let dbus = conn.unwrap();
-        let response = dbus.send_with_reply_and_block(
-                                           dbus_msg.unwrap().append1(id), 2000
-                                       ).ok().expect("Is the ring-daemon launched?");
-        let details: Dict<&str, &str, _> = match response.get1() {
-            Some(details) => details,
-            None => {
+    fn build_account(backend: &Arc<dyn DaemonBackend>, id: &str) -> Account {
+        let details = match backend.get_account_details(id) {
+            Ok(details) => details,
+            Err(e) => {
+                error!("getAccountDetails fails: {}", e);
                 return Account::null();
             }
         };
 
         let mut account = Account::null();
         account.id = id.to_owned();
-        for detail in details {
-            match detail {
-                (key, value) => {
-                    if key == "Account.enable" {
-                        account.enabled = value == "true";
-                    }
-                    if key == "Account.alias" {
-                        account.alias = String::from(value);
-                    }
-                    if key == "Account.username" {
-                        account.ring_id = String::from(value).replace("ring:", "");
-                    }
-                }
+        for (key, value) in details {
+            if key == "Account.enable" {
+                account.enabled = value == "true";
+            }
+            if key == "Account.alias" {
+                account.alias = value.clone();
+            }
+            if key == "Account.username" {
+                account.ring_id = value.replace("ring:", "");
             }
         }
         account
@@ -438,137 +721,700 @@ impl Endpoint {
      * @param self
      */
     pub fn enable_account(&self) {
-        let dbus_msg = Message::new_method_call(self.ring_dbus, self.configuration_path,
-                                                self.configuration_iface,
-                                                "sendRegister");
-        if !dbus_msg.is_ok() {
-            error!("sendRegister call fails. Please verify daemon's API.");
+        if let Err(e) = self.backend.send_register(&*self.account.id, true) {
+            error!("sendRegister call fails: {}", e);
+        }
+    }
+
+    /**
+     * Update current RORI account by handling accountsChanged signals from daemon.
+     * A disabled account is enabled again, a removed one stops the login.
+     * @param self
+     * @param signal
+     * @param bus where login states are published
+     */
+    fn handle_accounts_signals(&mut self, signal: &DaemonSignal, bus: &EventBus) {
+        // Check signal
+        if let &DaemonSignal::AccountsChanged = signal {} else { return };
+        if self.account.id == "" {
             return;
         }
-        let conn = Connection::get_private(BusType::Session);
-        if !conn.is_ok() {
+        let account = Endpoint::build_account(&self.backend, &self.account.id);
+        if account.ring_id == "" {
+            warn!("{}: account removed from the daemon", self.account.id);
+            self.registered = false;
+            self.reregister = None;
+            let reason = String::from("the Ring account was removed, please check configuration");
+            self.set_login_state(LoginState::Failed { reason }, bus);
             return;
         }
-        let dbus = conn.unwrap();
-        let _ = dbus.send_with_reply_and_block(
-            dbus_msg.unwrap().append2(self.account.id.clone(), true), 2000);
+        if self.account.enabled && !account.enabled {
+            warn!("{}: account disabled", self.account.id);
+            self.set_offline("account disabled", bus);
+        }
+        self.account.enabled = account.enabled;
     }
 
     /**
-     * Update current RORI account by handling accountsChanged signals from daemon.
+     * The account can't be used: enable or register it again later
      * @param self
-     * @param ci
+     * @param reason shown to the user
+     * @param bus where login states are published
      */
-    fn handle_accounts_signals(&mut self, ci: &ConnectionItem) {
-        // Check signal
-        let msg = if let &ConnectionItem::Signal(ref signal) = ci { signal } else { return };
-        if &*msg.interface().unwrap() != "cx.ring.Ring.ConfigurationManager" { return };
-        if &*msg.member().unwrap() != "accountsChanged" { return };
-        // TODO test if RORI accounts is still exists
+    fn set_offline(&mut self, reason: &str, bus: &EventBus) {
+        self.registered = false;
+        match *self.login.state() {
+            LoginState::Unconfigured | LoginState::Failed { .. } => {},
+            _ => self.set_login_state(LoginState::Offline { reason: String::from(reason) }, bus)
+        }
+        if self.reregister.is_none() {
+            self.reregister = Some((Instant::now() + Duration::from_secs(REREGISTER_DELAY_SECS), 0));
+        }
+    }
+
+    /**
+     * Enable or register the account again when it's time. Called regularly
+     * @param self
+     */
+    pub fn check_registration(&mut self) {
+        let attempts = match self.reregister {
+            Some((when, attempts)) if when <= Instant::now() => attempts + 1,
+            _ => return
+        };
+        info!("{}: registering the account again (attempt {})", self.account.id, attempts);
+        self.enable_account();
+        let delay = REREGISTER_DELAY_SECS.saturating_mul(1 << attempts.min(16)).min(MAX_REREGISTER_DELAY_SECS);
+        self.reregister = Some((Instant::now() + Duration::from_secs(delay), attempts));
     }
 
     /**
     * Handle new interactions signals
     * @param self
-    * @param ci
+    * @param signal
     * @return (accountId, interaction)
     */
-    fn handle_interactions(&self, ci: &ConnectionItem) -> Option<(String, Interaction)> {
+    fn handle_interactions(&self, signal: &DaemonSignal) -> Option<(String, Interaction)> {
         // Check signal
-        let msg = if let &ConnectionItem::Signal(ref signal) = ci { signal } else { return None };
-        if &*msg.interface().unwrap() != "cx.ring.Ring.ConfigurationManager" { return None };
-        if &*msg.member().unwrap() != "incomingAccountMessage" { return None };
-        // incomingAccountMessage return four arguments
-        let (account_id, _msg_id, author_ring_id, payloads) = msg.get4::<&str, &str, &str, Dict<&str, &str, _>>();
-        let author_ring_id = author_ring_id.unwrap().to_string();
+        let (account_id, author_ring_id, payloads) = match signal {
+            &DaemonSignal::IncomingAccountMessage { ref account_id, ref author_ring_id, ref payloads } => {
+                (account_id, author_ring_id, payloads)
+            },
+            _ => return None
+        };
         let mut body = String::new();
         let mut datatype = String::new();
-        let mut supported_types: Vec<String> = Vec::new();
-        supported_types.push(String::from("music"));
-        supported_types.push(String::from("command"));
-        supported_types.push(String::from("rori/message"));
-        supported_types.push(String::from("alarm"));
-        supported_types.push(String::from("text/plain"));
-        for detail in payloads.unwrap() {
-            match detail {
-                (key, value) => {
-                    if supported_types.contains(&key.to_string()) {
-                        datatype = key.to_string();
-                        body = value.to_string();
-                    }
-                    // Else metadatas. Unused for now
-                }
+        let supported_types = self.handlers.lock().unwrap().supported_types();
+        for (key, value) in payloads {
+            if supported_types.contains(key) {
+                datatype = key.clone();
+                body = value.clone();
             }
+            // Else metadatas. Unused for now
         };
         let interaction = Interaction {
-            author_ring_id: author_ring_id,
+            author_ring_id: author_ring_id.clone(),
             body: body,
             datatype: datatype,
             time: time::now()
         };
-        Some((account_id.unwrap().to_string(), interaction))
+        Some((account_id.clone(), interaction))
     }
 
     /**
-     * Update current RORI account by handling accountsChanged signals from daemon
+     * Follow the registration of the account: register it again when it's lost,
+     * and send queued messages and log in again when it's back
      * @param self
-     * @param ci
+     * @param signal
+     * @param bus where login states are published
      */
-    fn handle_registration_changed(&self, ci: &ConnectionItem) {
+    fn handle_registration_changed(&mut self, signal: &DaemonSignal, bus: &EventBus) {
         // Check signal
-        let msg = if let &ConnectionItem::Signal(ref signal) = ci { signal } else { return };
-        if &*msg.interface().unwrap() != "cx.ring.Ring.ConfigurationManager" { return };
-        if &*msg.member().unwrap() != "registrationStateChanged" { return };
-        // let (account_id, registration_state, _, _) = msg.get4::<&str, &str, u64, &str>();
-        // TODO the account can be disabled. Inform UI
+        let (account_id, state) = match signal {
+            &DaemonSignal::RegistrationStateChanged { ref account_id, ref state, .. } => (account_id, state),
+            _ => return
+        };
+        if *account_id != self.account.id {
+            return;
+        }
+        info!("{}: registration state {}", account_id, state);
+        if state == "REGISTERED" {
+            self.reregister = None;
+            if self.registered {
+                return;
+            }
+            self.registered = true;
+            if let LoginState::Offline { .. } = *self.login.state() {
+                // Log in again from the signal thread
+                self.login.reset();
+                self.login.retry_in(Duration::from_secs(0), "account registered again");
+            }
+            self.flush_outgoing();
+        } else if state == "UNREGISTERED" || state.starts_with("ERROR") {
+            self.set_offline(&format!("account {}", state.to_lowercase()), bus);
+        }
     }
 
     /**
      * Handle new pending requests signals
      * @param self
-     * @param ci
+     * @param signal
      * @return (accountId, from)
      */
-    fn handle_requests(&self, ci: &ConnectionItem) -> Option<(String, String)> {
+    fn handle_requests(&self, signal: &DaemonSignal) -> Option<(String, String)> {
         // Check signal
-        let msg = if let &ConnectionItem::Signal(ref signal) = ci { signal } else { return None };
-        if &*msg.interface().unwrap() != "cx.ring.Ring.ConfigurationManager" { return None };
-        if &*msg.member().unwrap() != "incomingTrustRequest" { return None };
-        // incomingTrustRequest return three arguments
-        let (account_id, from, _, _) = msg.get4::<&str, &str, Dict<&str, &str, _>, u64>();
-        Some((account_id.unwrap().to_string(), from.unwrap().to_string()))
+        match signal {
+            &DaemonSignal::IncomingTrustRequest { ref account_id, ref from } => {
+                Some((account_id.clone(), from.clone()))
+            },
+            _ => None
+        }
     }
 
 
     /**
-     * Send a new text message to rori
+     * Follow the status of messages sent to RORI
+     * @param self
+     * @param signal
+     */
+    fn handle_message_status(&mut self, signal: &DaemonSignal) {
+        let lost = match signal {
+            &DaemonSignal::MessageStatusChanged { ref account_id, message_id, status } if *account_id == self.account.id => {
+                self.delivery.lock().unwrap().update(message_id, status)
+            },
+            _ => return
+        };
+        if let Some(message) = lost {
+            self.on_undelivered(&message);
+        }
+    }
+
+    /**
+     * Send again failed messages when it's time. Called regularly
+     * @param self
+     */
+    pub fn check_delivery(&mut self) {
+        let due = self.delivery.lock().unwrap().take_due();
+        for message in due {
+            info!("Sending message {} again (attempt {})", message.id, message.attempts + 1);
+            if self.send(&message.body, &message.datatype, message.attempts + 1) == 0 {
+                let lost = self.delivery.lock().unwrap().resend_failed(message);
+                if let Some(message) = lost {
+                    self.on_undelivered(&message);
+                }
+            }
+        }
+    }
+
+    /**
+     * Tell the user that RORI never got a message
+     * @param self
+     * @param message given up
+     */
+    fn on_undelivered(&mut self, message: &TrackedMessage) {
+        if message.datatype == "text/plain" || message.datatype == "rori/command" {
+            self.add_to_say_queue(&format!("RORI didn't get: {}", message.body));
+        }
+    }
+
+    /**
+     * @param self
+     * @return messages not received by RORI yet, or given up
+     */
+    pub fn undelivered(&self) -> Vec<TrackedMessage> {
+        self.delivery.lock().unwrap().undelivered()
+    }
+
+    /**
+     * Use an outbox shared with other endpoints. Its messages are sent soon
+     * @param self
+     * @param outbox to use
+     */
+    pub fn set_outbox(&mut self, outbox: Arc<Mutex<Outbox>>) {
+        self.outbox = outbox;
+        self.flush_at = Some(Instant::now());
+    }
+
+    /**
+     * @param self
+     * @return the outbox of the endpoint
+     */
+    pub fn outbox(&self) -> Arc<Mutex<Outbox>> {
+        self.outbox.clone()
+    }
+
+    /**
+     * Write a message in the outbox, then send it if the account is registered
      * @param self
      * @param body text to send
-     * @return the interaction id if success. TODO, watch message status (if received)
-     */
-    fn send_interaction_to_rori(&self, body: &str, datatype: &str) -> u64 {
-        let mut payloads: HashMap<&str, &str> = HashMap::new();
-        payloads.insert(datatype, body);
-        let payloads = Dict::new(payloads.iter());
-
-        let dbus_msg = Message::new_method_call(self.ring_dbus, self.configuration_path, self.configuration_iface,
-                                                "sendTextMessage");
-        if !dbus_msg.is_ok() {
-            error!("sendTextMessage fails. Please verify daemon's API.");
-            return 0;
-        }
-        let conn = Connection::get_private(BusType::Session);
-        if !conn.is_ok() {
-            return 0;
-        }
-        let dbus = conn.unwrap();
-        let response = dbus.send_with_reply_and_block(dbus_msg.unwrap().append3(&*self.account.id,
-            self.rori_ring_id.clone(), payloads), 2000).unwrap();
-        // sendTextMessage returns one argument, which is a u64.
-        let interaction_id: u64  = match response.get1() {
-            Some(interaction_id) => interaction_id,
-            None => 0
+     * @param datatype of the body
+     * @return true once the message is in the outbox
+     */
+    pub fn send_or_queue(&mut self, body: &str, datatype: &str) -> bool {
+        let id = self.outbox.lock().unwrap().push(&self.account.id, datatype, body);
+        if !self.registered {
+            info!("Account not registered, {} queued ({})", datatype, id);
+            return true;
+        }
+        self.flush_outgoing();
+        true
+    }
+
+    /**
+     * Send messages of the outbox, oldest first. Stop at the first failure
+     * and try again later
+     * @param self
+     */
+    fn flush_outgoing(&mut self) {
+        let entries: Vec<OutboxEntry> = self.outbox.lock().unwrap().entries_for(&self.account.id);
+        if entries.len() > 1 {
+            info!("Sending {} queued messages", entries.len());
+        }
+        for entry in entries {
+            if self.send_interaction_to_rori(&entry.body, &entry.datatype) == 0 {
+                warn!("Outbox not sent, trying again in {}s", FLUSH_RETRY_SECS);
+                self.flush_at = Some(Instant::now() + Duration::from_secs(FLUSH_RETRY_SECS));
+                return;
+            }
+            self.outbox.lock().unwrap().mark_sent(entry.id);
+        }
+        self.flush_at = None;
+    }
+
+    /**
+     * Send the outbox again when it's time. Called regularly
+     * @param self
+     */
+    pub fn check_outbox(&mut self) {
+        match self.flush_at {
+            Some(when) if self.registered && when <= Instant::now() => self.flush_outgoing(),
+            _ => {}
+        }
+    }
+
+    /**
+     * Send a new text message to rori. Its status is followed, and it is sent again if it fails
+     * @param self
+     * @param body text to send
+     * @param datatype of the body
+     * @return the interaction id if success
+     */
+    pub fn send_interaction_to_rori(&self, body: &str, datatype: &str) -> u64 {
+        self.send(body, datatype, 1)
+    }
+
+    /**
+     * @param self
+     * @param body text to send
+     * @param datatype of the body
+     * @param attempts number of sends, including this one
+     * @return the interaction id if success
+     */
+    fn send(&self, body: &str, datatype: &str, attempts: u32) -> u64 {
+        let mut payloads: HashMap<String, String> = HashMap::new();
+        payloads.insert(String::from(datatype), String::from(body));
+        match self.backend.send_text_message(&*self.account.id, &*self.rori_ring_id, payloads) {
+            Ok(interaction_id) => {
+                self.delivery.lock().unwrap().track(interaction_id, datatype, body, attempts);
+                interaction_id
+            },
+            Err(e) => {
+                error!("sendTextMessage fails: {}", e);
+                0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rori::device::{self, DeviceUpdate};
+    use rori::handler::{RoriMessageHandler, TextHandler};
+    use rori::mock_daemon::MockBackend;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::mpsc::Receiver;
+    use std::thread;
+
+    const CLIENT_RING_ID: &str = "c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1";
+    const RORI_RING_ID: &str = "a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0";
+
+    /**
+     * Handler keeping the bodies it receives
+     */
+    struct RecordingHandler {
+        datatype: String,
+        received: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl DatatypeHandler for RecordingHandler {
+        fn datatype(&self) -> &str {
+            &*self.datatype
+        }
+
+        fn handle(&mut self, interaction: &Interaction, _endpoint: &mut Endpoint) {
+            self.received.lock().unwrap().push(interaction.body.clone());
+        }
+    }
+
+    /**
+     * Start a nameserver on localhost
+     * @param name registered for the ring id of the client, if any
+     * @param addr registered for the alias of the client, if any
+     * @return the address of the nameserver
+     */
+    fn nameserver(name: Option<&str>, addr: Option<&str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let name = name.map(String::from);
+        let addr = addr.map(String::from);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue
+                };
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let found = if request.starts_with("GET /addr/") {
+                    name.as_ref().map(|name| format!("{{\"name\":\"{}\"}}", name))
+                } else if request.starts_with("GET /name/") {
+                    addr.as_ref().map(|addr| format!("{{\"addr\":\"0x{}\"}}", addr))
+                } else {
+                    None
+                };
+                let response = match found {
+                    Some(body) => format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                          body.len(), body),
+                    None => String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        address
+    }
+
+    /**
+     * @param backend where the account of the client is added
+     * @param rori_server nameserver of RORI
+     * @return an endpoint handling text/plain and rori/message, and the bus of its login states
+     */
+    fn endpoint(backend: &MockBackend, rori_server: &str) -> (Arc<Mutex<Endpoint>>, EventBus) {
+        let account_id = backend.add_ring_account("alice", CLIENT_RING_ID);
+        let bus = EventBus::new();
+        let endpoint = Endpoint::init(Arc::new(backend.clone()), &account_id, rori_server, RORI_RING_ID).unwrap();
+        endpoint.register_handler(Box::new(TextHandler));
+        endpoint.register_handler(Box::new(RoriMessageHandler::new(bus.clone())));
+        (Arc::new(Mutex::new(endpoint)), bus)
+    }
+
+    /**
+     * @param endpoint receiving the message
+     * @param author ring id of the sender
+     * @param datatype of the message
+     * @param body of the message
+     * @return the signal of a new message for the endpoint
+     */
+    fn message(endpoint: &Arc<Mutex<Endpoint>>, author: &str, datatype: &str, body: &str) -> DaemonSignal {
+        let mut payloads = HashMap::new();
+        payloads.insert(String::from(datatype), String::from(body));
+        DaemonSignal::IncomingAccountMessage {
+            account_id: endpoint.lock().unwrap().account.id.clone(),
+            author_ring_id: String::from(author),
+            payloads,
+        }
+    }
+
+    /**
+     * @param rx subscribed to the bus
+     * @return login states published since the last call
+     */
+    fn states(rx: &Receiver<Event>) -> Vec<LoginState> {
+        rx.try_iter().filter_map(|event| match event {
+            Event::LoginStateChanged(_, state) => Some(state),
+            _ => None
+        }).collect()
+    }
+
+    /**
+     * @param backend used by the endpoint
+     * @return the rori/command bodies sent
+     */
+    fn commands(backend: &MockBackend) -> Vec<String> {
+        backend.sent_messages().iter()
+            .filter_map(|m| m.payloads.get("rori/command").cloned())
+            .collect()
+    }
+
+    #[test]
+    fn dispatch_by_datatype() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, "");
+        let music = Arc::new(Mutex::new(Vec::new()));
+        let alarm = Arc::new(Mutex::new(Vec::new()));
+        endpoint.lock().unwrap().register_handler(Box::new(RecordingHandler {
+            datatype: String::from("music"),
+            received: music.clone(),
+        }));
+        endpoint.lock().unwrap().register_handler(Box::new(RecordingHandler {
+            datatype: String::from("alarm"),
+            received: alarm.clone(),
+        }));
+
+        let signals = vec![
+            message(&endpoint, RORI_RING_ID, "music", "next"),
+            message(&endpoint, RORI_RING_ID, "alarm", "{}"),
+            message(&endpoint, RORI_RING_ID, "unknown", "ignored"),
+            // Only RORI can send orders
+            message(&endpoint, CLIENT_RING_ID, "music", "pause"),
+        ];
+        for signal in signals {
+            endpoint.lock().unwrap().handle_signal(&signal, &bus);
+        }
+        assert_eq!(*music.lock().unwrap(), vec![String::from("next")]);
+        assert_eq!(*alarm.lock().unwrap(), vec![String::from("{}")]);
+
+        endpoint.lock().unwrap().unregister_handler("music");
+        let signal = message(&endpoint, RORI_RING_ID, "music", "play");
+        endpoint.lock().unwrap().handle_signal(&signal, &bus);
+        assert_eq!(music.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn handler_replaced_by_a_new_registration() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, "");
+        let first = Arc::new(Mutex::new(Vec::new()));
+        let second = Arc::new(Mutex::new(Vec::new()));
+        for received in vec![first.clone(), second.clone()] {
+            endpoint.lock().unwrap().register_handler(Box::new(RecordingHandler {
+                datatype: String::from("music"),
+                received,
+            }));
+        }
+        let signal = message(&endpoint, RORI_RING_ID, "music", "next");
+        endpoint.lock().unwrap().handle_signal(&signal, &bus);
+        assert!(first.lock().unwrap().is_empty());
+        assert_eq!(second.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn set_types_payload() {
+        let backend = MockBackend::new();
+        let (endpoint, _) = endpoint(&backend, "");
+        for datatype in &["music", "alarm"] {
+            endpoint.lock().unwrap().register_handler(Box::new(RecordingHandler {
+                datatype: String::from(*datatype),
+                received: Arc::new(Mutex::new(Vec::new())),
+            }));
+        }
+        endpoint.lock().unwrap().announce_datatypes();
+        let sent = backend.sent_messages();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, RORI_RING_ID);
+        // text/plain and rori/message are always understood by RORI
+        assert_eq!(commands(&backend), vec![String::from("/set_types music alarm")]);
+    }
+
+    #[test]
+    fn login_unconfigured() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, "");
+        let rx = bus.subscribe();
+        Endpoint::login(endpoint.clone(), &bus);
+        assert_eq!(endpoint.lock().unwrap().login_state(), LoginState::Unconfigured);
+        assert!(states(&rx).is_empty());
+        assert!(backend.sent_messages().is_empty());
+    }
+
+    #[test]
+    fn login_already_registered() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, &nameserver(Some("alice"), Some(CLIENT_RING_ID)));
+        let rx = bus.subscribe();
+        Endpoint::login(endpoint.clone(), &bus);
+        assert_eq!(states(&rx), vec![LoginState::Resolving, LoginState::LoggedIn]);
+        assert_eq!(commands(&backend), vec![String::from("/set_types ")]);
+    }
+
+    #[test]
+    fn login_registers_a_new_username() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, &nameserver(None, None));
+        let rx = bus.subscribe();
+        Endpoint::login(endpoint.clone(), &bus);
+        assert_eq!(states(&rx), vec![LoginState::Resolving, LoginState::Registering, LoginState::AwaitingConfirmation]);
+        assert_eq!(commands(&backend), vec![String::from("/register alice")]);
+    }
+
+    #[test]
+    fn login_confirmed_after_registration() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, &nameserver(None, None));
+        endpoint.lock().unwrap().register_handler(Box::new(RecordingHandler {
+            datatype: String::from("music"),
+            received: Arc::new(Mutex::new(Vec::new())),
+        }));
+        let rx = bus.subscribe();
+        Endpoint::login(endpoint.clone(), &bus);
+        assert_eq!(states(&rx), vec![LoginState::Resolving, LoginState::Registering, LoginState::AwaitingConfirmation]);
+
+        let signal = message(&endpoint, RORI_RING_ID, "rori/message", "{\"registered\": true}");
+        endpoint.lock().unwrap().handle_signal(&signal, &bus);
+        assert_eq!(states(&rx), vec![LoginState::LoggedIn]);
+        assert_eq!(endpoint.lock().unwrap().login_state(), LoginState::LoggedIn);
+        assert_eq!(commands(&backend), vec![String::from("/register alice"), String::from("/set_types music")]);
+        // The confirmation timeout is cancelled
+        Endpoint::check_login(endpoint.clone(), &bus);
+        assert!(states(&rx).is_empty());
+    }
+
+    #[test]
+    fn login_links_a_known_username() {
+        let backend = MockBackend::new();
+        let other_device = "b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2";
+        let (endpoint, bus) = endpoint(&backend, &nameserver(None, Some(other_device)));
+        let rx = bus.subscribe();
+        Endpoint::login(endpoint.clone(), &bus);
+        assert_eq!(states(&rx), vec![LoginState::Resolving, LoginState::Linking, LoginState::AwaitingConfirmation]);
+        assert_eq!(commands(&backend), vec![String::from("/link alice")]);
+    }
+
+    #[test]
+    fn login_fails_for_another_username() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, &nameserver(Some("bob"), None));
+        let rx = bus.subscribe();
+        Endpoint::login(endpoint.clone(), &bus);
+        match states(&rx).last() {
+            Some(&LoginState::Failed { ref reason }) => assert!(reason.contains("bob")),
+            state => panic!("Unexpected state {:?}", state)
+        }
+        assert!(backend.sent_messages().is_empty());
+    }
+
+    #[test]
+    fn login_refused_by_rori() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, &nameserver(None, None));
+        Endpoint::login(endpoint.clone(), &bus);
+        let rx = bus.subscribe();
+        let signal = message(&endpoint, RORI_RING_ID, "rori/message", "{\"registered\": false}");
+        endpoint.lock().unwrap().handle_signal(&signal, &bus);
+        assert_eq!(states(&rx), vec![LoginState::Failed { reason: String::from("RORI refused the registration") }]);
+    }
+
+    #[test]
+    fn link_confirmed_as_a_device_update() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, &nameserver(Some("alice"), Some(CLIENT_RING_ID)));
+        Endpoint::login(endpoint.clone(), &bus);
+        let rx = bus.subscribe();
+        let command = DeviceCommand::Add(None);
+        assert!(command.is_confirmed());
+        endpoint.lock().unwrap().send_device_command(&command);
+        assert_eq!(commands(&backend).last(), Some(&String::from("/link alice")));
+
+        let signal = message(&endpoint, RORI_RING_ID, "rori/message", "{\"registered\": true}");
+        endpoint.lock().unwrap().handle_signal(&signal, &bus);
+        let update = device::wait_update(&rx, "", Duration::from_millis(100));
+        assert_eq!(update, Ok(DeviceUpdate::Linked));
+    }
+
+    #[test]
+    fn refused_link_keeps_the_login() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, &nameserver(Some("alice"), Some(CLIENT_RING_ID)));
+        Endpoint::login(endpoint.clone(), &bus);
+        let rx = bus.subscribe();
+        endpoint.lock().unwrap().send_device_command(&DeviceCommand::Add(None));
+
+        let signal = message(&endpoint, RORI_RING_ID, "rori/message", "{\"registered\": false}");
+        endpoint.lock().unwrap().handle_signal(&signal, &bus);
+        let update = device::wait_update(&rx, "", Duration::from_millis(100));
+        assert_eq!(update, Ok(DeviceUpdate::Failed(String::from("RORI refused the registration"))));
+        assert_eq!(endpoint.lock().unwrap().login_state(), LoginState::LoggedIn);
+    }
+
+    #[test]
+    fn login_retried_when_nameserver_unreachable() {
+        let backend = MockBackend::new();
+        // Nothing listens on this port once the listener is dropped
+        let address = format!("http://{}", TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        let (endpoint, bus) = endpoint(&backend, &address);
+        let rx = bus.subscribe();
+        Endpoint::login(endpoint.clone(), &bus);
+        assert_eq!(states(&rx), vec![LoginState::Resolving]);
+        assert!(backend.sent_messages().is_empty());
+        // Not due yet
+        Endpoint::check_login(endpoint.clone(), &bus);
+        assert!(states(&rx).is_empty());
+    }
+
+    #[test]
+    fn offline_then_logged_again() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, &nameserver(Some("alice"), Some(CLIENT_RING_ID)));
+        Endpoint::login(endpoint.clone(), &bus);
+        let rx = bus.subscribe();
+        let account_id = endpoint.lock().unwrap().account.id.clone();
+        let registration = |state: &str| DaemonSignal::RegistrationStateChanged {
+            account_id: account_id.clone(),
+            state: String::from(state),
+            code: 0,
+            detail: String::new(),
+        };
+
+        endpoint.lock().unwrap().handle_signal(&registration("UNREGISTERED"), &bus);
+        assert_eq!(states(&rx), vec![LoginState::Offline { reason: String::from("account unregistered") }]);
+
+        endpoint.lock().unwrap().handle_signal(&registration("REGISTERED"), &bus);
+        Endpoint::check_login(endpoint.clone(), &bus);
+        assert_eq!(states(&rx), vec![LoginState::Resolving, LoginState::LoggedIn]);
+    }
+
+    #[test]
+    fn outbox_sent_once_registered_again() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, "");
+        let account_id = endpoint.lock().unwrap().account.id.clone();
+        let registration = |state: &str| DaemonSignal::RegistrationStateChanged {
+            account_id: account_id.clone(),
+            state: String::from(state),
+            code: 0,
+            detail: String::new(),
         };
-        interaction_id
+        endpoint.lock().unwrap().handle_signal(&registration("UNREGISTERED"), &bus);
+        endpoint.lock().unwrap().handle_user_entry("next");
+        endpoint.lock().unwrap().handle_user_entry("next");
+        assert!(backend.sent_messages().is_empty());
+
+        endpoint.lock().unwrap().handle_signal(&registration("REGISTERED"), &bus);
+        endpoint.lock().unwrap().check_outbox();
+        let texts: Vec<String> = backend.sent_messages().iter()
+            .filter_map(|m| m.payloads.get("text/plain").cloned())
+            .collect();
+        assert_eq!(texts, vec![String::from("next"), String::from("next")]);
+        assert!(endpoint.lock().unwrap().outbox().lock().unwrap().list().is_empty());
+    }
+
+    #[test]
+    fn removed_account_fails_the_login() {
+        let backend = MockBackend::new();
+        let (endpoint, bus) = endpoint(&backend, "");
+        endpoint.lock().unwrap().account.id = String::from("removed");
+        let rx = bus.subscribe();
+        endpoint.lock().unwrap().handle_signal(&DaemonSignal::AccountsChanged, &bus);
+        match states(&rx).last() {
+            Some(&LoginState::Failed { .. }) => {},
+            state => panic!("Unexpected state {:?}", state)
+        }
     }
 }
@@ -0,0 +1,192 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::daemon::DaemonBackend;
+use rori::endpoint::Endpoint;
+use rori::event::{Event, EventBus};
+use rori::shutdown::ShutdownToken;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+
+/**
+ * An Endpoint and how to reach it
+ */
+struct Entry {
+    profile: String,
+    wake_word: String,
+    endpoint: Arc<Mutex<Endpoint>>,
+}
+
+/**
+ * Endpoints connected at the same time, one per RORI.
+ * The primary endpoint receives user entries without wake word,
+ * the others receive entries beginning with their wake word ("work, ...").
+ */
+pub struct EndpointSet {
+    primary: Arc<Mutex<Endpoint>>,
+    others: Vec<Entry>,
+}
+
+impl EndpointSet {
+    /**
+     * @param primary endpoint of the active profile
+     * @return an EndpointSet with only the primary endpoint
+     */
+    pub fn new(primary: Arc<Mutex<Endpoint>>) -> EndpointSet {
+        EndpointSet {
+            primary,
+            others: Vec::new(),
+        }
+    }
+
+    /**
+     * @param self
+     * @return the endpoint of the active profile
+     */
+    pub fn primary(&self) -> Arc<Mutex<Endpoint>> {
+        self.primary.clone()
+    }
+
+    /**
+     * Add an endpoint, or replace the one of the same profile
+     * @param self
+     * @param profile of the endpoint
+     * @param wake_word beginning user entries for this endpoint
+     * @param endpoint to add
+     */
+    pub fn insert(&mut self, profile: &str, wake_word: &str, endpoint: Arc<Mutex<Endpoint>>) {
+        self.remove(profile);
+        self.others.push(Entry {
+            profile: String::from(profile),
+            wake_word: wake_word.to_lowercase(),
+            endpoint,
+        });
+    }
+
+    /**
+     * Remove the endpoint of a profile (not the primary one)
+     * @param self
+     * @param profile to remove
+     * @return the removed endpoint
+     */
+    pub fn remove(&mut self, profile: &str) -> Option<Arc<Mutex<Endpoint>>> {
+        let idx = self.others.iter().position(|e| e.profile == profile)?;
+        Some(self.others.remove(idx).endpoint)
+    }
+
+    /**
+     * @param self
+     * @return profiles of secondary endpoints
+     */
+    pub fn profiles(&self) -> Vec<String> {
+        self.others.iter().map(|e| e.profile.clone()).collect()
+    }
+
+    /**
+     * @param self
+     * @return every endpoint, the primary one first
+     */
+    pub fn all(&self) -> Vec<Arc<Mutex<Endpoint>>> {
+        let mut all = vec![self.primary.clone()];
+        all.extend(self.others.iter().map(|e| e.endpoint.clone()));
+        all
+    }
+
+    /**
+     * Find the endpoint of a user entry
+     * @param self
+     * @param text entered by the user
+     * @return the endpoint and the entry without the wake word
+     */
+    pub fn route(&self, text: &str) -> (Arc<Mutex<Endpoint>>, String) {
+        let lower = text.to_lowercase();
+        for entry in &self.others {
+            if entry.wake_word == "" || !lower.starts_with(&entry.wake_word) {
+                continue;
+            }
+            let rest = match text.get(entry.wake_word.len()..) {
+                Some(rest) => rest,
+                None => continue
+            };
+            // The wake word must be a whole word
+            if rest == "" || rest.starts_with(|c: char| c == ',' || c == ':' || c.is_whitespace()) {
+                let rest = rest.trim_start_matches(|c: char| c == ',' || c == ':' || c.is_whitespace());
+                return (entry.endpoint.clone(), String::from(rest));
+            }
+        }
+        (self.primary.clone(), String::from(text))
+    }
+
+    /**
+     * Listen from interresting signals from the daemon and give them to every endpoint,
+     * send user entries to the right endpoint, and register accounts and retry logins when needed
+     * @param set endpoints
+     * @param backend daemon shared by endpoints
+     * @param bus where login states are published
+     * @param shutdown when to quit
+     * @param events from the EventBus, to send user entries
+     */
+    pub fn handle_signals(set: Arc<Mutex<EndpointSet>>, backend: Arc<dyn DaemonBackend>, bus: &EventBus,
+                          shutdown: ShutdownToken, events: Receiver<Event>) {
+        let mut listener = match backend.listen() {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Cannot listen signals from the daemon: {}", e);
+                return;
+            }
+        };
+        // For each signals, call handlers.
+        loop {
+            let signal = listener.next_signal(100);
+            let all = set.lock().unwrap().all();
+            for endpoint in all {
+                if let Some(ref signal) = signal {
+                    endpoint.lock().unwrap().handle_signal(signal, bus);
+                }
+                {
+                    let mut endpoint = endpoint.lock().unwrap();
+                    endpoint.check_registration();
+                    endpoint.check_delivery();
+                    endpoint.check_outbox();
+                }
+                Endpoint::check_login(endpoint, bus);
+            }
+            // Send every entry received since the last signal
+            while let Ok(event) = events.try_recv() {
+                let utext = match event {
+                    Event::UserUtterance(text, _) => text,
+                    _ => continue
+                };
+                let (endpoint, text) = set.lock().unwrap().route(&utext);
+                endpoint.lock().unwrap().handle_user_entry(&text);
+            }
+            if shutdown.is_requested() {
+                break;
+            }
+        }
+    }
+}
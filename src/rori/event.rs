@@ -0,0 +1,101 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::device::DeviceUpdate;
+use rori::login::LoginState;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/**
+ * Where a user entry comes from
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Ui,
+    Api,
+    Terminal,
+    /// Recognized from the microphone or an audio file
+    Voice,
+}
+
+/**
+ * Everything happening in the client
+ */
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Something the user said or typed, to send to RORI
+    UserUtterance(String, Source),
+    /// Something RORI is saying
+    RoriSpeech(String),
+    /// If the user is speaking
+    ListeningChanged(bool),
+    /// The user wants RORI to stop talking
+    StopSpeaking,
+    /// Login state of a profile (empty for the top level identity)
+    LoginStateChanged(String, LoginState),
+    /// Answer of RORI to a device command, for a profile
+    DeviceUpdate(String, DeviceUpdate),
+}
+
+/**
+ * Broadcast events to every subscriber. Clones share the same subscribers.
+ */
+#[derive(Clone)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    /**
+     * @return an EventBus without subscriber
+     */
+    pub fn new() -> EventBus {
+        EventBus {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /**
+     * Receive every event published from now
+     * @param self
+     * @return the receiver of events
+     */
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /**
+     * Send an event to every subscriber. Dropped subscribers are removed.
+     * @param self
+     * @param event to send
+     */
+    pub fn publish(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
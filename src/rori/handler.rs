@@ -0,0 +1,322 @@
+/**
+ * Copyright (c) 2019, Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::device::DeviceUpdate;
+use rori::endpoint::Endpoint;
+use rori::event::{Event, EventBus};
+use rori::interaction::Interaction;
+use rori::login::LoginState;
+use rori::markup::Markup;
+use serde_json::from_str;
+use std::sync::Mutex;
+
+/**
+ * Handle interactions of one datatype sent by RORI.
+ * Implement this trait and register it on the Endpoint to support a new datatype.
+ */
+pub trait DatatypeHandler: Send {
+    /**
+     * @return the datatype handled (e.g. "music")
+     */
+    fn datatype(&self) -> &str;
+
+    /**
+     * If the datatype must be sent to RORI via /set_types.
+     * Datatypes always understood by RORI (text/plain, rori/message) must not.
+     * @return true by default
+     */
+    fn announce(&self) -> bool {
+        true
+    }
+
+    /**
+     * Handle a new interaction from RORI
+     * @param self
+     * @param interaction received
+     * @param endpoint which received the interaction
+     */
+    fn handle(&mut self, interaction: &Interaction, endpoint: &mut Endpoint);
+
+    /**
+     * Called with each user entry before it is sent to RORI.
+     * Used by handlers waiting for an answer from the user.
+     * @param self
+     * @param text entered by the user
+     * @param endpoint which received the entry
+     * @return true if the entry is consumed and must not be sent to RORI
+     */
+    fn on_user_text(&mut self, _text: &str, _endpoint: &mut Endpoint) -> bool {
+        false
+    }
+}
+
+/**
+ * A handler in the registry. Its datatype is kept while the handler runs
+ */
+struct Entry {
+    datatype: String,
+    announce: bool,
+    /// None while the handler runs
+    handler: Option<Box<dyn DatatypeHandler>>,
+}
+
+/**
+ * Contains handlers for each supported datatype.
+ * A handler is taken out of the registry while it runs, so it can use the
+ * endpoint (and its registry) without deadlocking.
+ */
+pub struct HandlerRegistry {
+    handlers: Vec<Entry>,
+}
+
+impl HandlerRegistry {
+    /**
+     * Initializes an empty registry
+     * @return a HandlerRegistry
+     */
+    pub fn new() -> HandlerRegistry {
+        HandlerRegistry {
+            handlers: Vec::new(),
+        }
+    }
+
+    /**
+     * Add a handler. Replace the previous handler for the same datatype
+     * @param self
+     * @param handler to add
+     */
+    pub fn register(&mut self, handler: Box<dyn DatatypeHandler>) {
+        let entry = Entry {
+            datatype: handler.datatype().to_string(),
+            announce: handler.announce(),
+            handler: Some(handler),
+        };
+        match self.handlers.iter().position(|h| h.datatype == entry.datatype) {
+            Some(idx) => self.handlers[idx] = entry,
+            None => self.handlers.push(entry),
+        }
+    }
+
+    /**
+     * Remove the handler of a datatype
+     * @param self
+     * @param datatype to stop handling
+     * @return true if a handler was removed
+     */
+    pub fn unregister(&mut self, datatype: &str) -> bool {
+        let len = self.handlers.len();
+        self.handlers.retain(|h| h.datatype != datatype);
+        self.handlers.len() != len
+    }
+
+    /**
+     * @param self
+     * @return every datatype handled
+     */
+    pub fn supported_types(&self) -> Vec<String> {
+        self.handlers.iter().map(|h| h.datatype.clone()).collect()
+    }
+
+    /**
+     * @param self
+     * @return datatypes to send to RORI via /set_types
+     */
+    pub fn announced_types(&self) -> Vec<String> {
+        self.handlers.iter()
+            .filter(|h| h.announce)
+            .map(|h| h.datatype.clone())
+            .collect()
+    }
+
+    /**
+     * Take a handler out of the registry to run it
+     * @param self
+     * @param datatype handled
+     * @return the handler, None if not registered or already running
+     */
+    fn take(&mut self, datatype: &str) -> Option<Box<dyn DatatypeHandler>> {
+        self.handlers.iter_mut()
+            .find(|h| h.datatype == datatype)
+            .and_then(|h| h.handler.take())
+    }
+
+    /**
+     * Put back a handler after it ran. Dropped if it was replaced or unregistered meanwhile
+     * @param self
+     * @param handler to put back
+     */
+    fn restore(&mut self, handler: Box<dyn DatatypeHandler>) {
+        let datatype = handler.datatype().to_string();
+        if let Some(entry) = self.handlers.iter_mut().find(|h| h.datatype == datatype && h.handler.is_none()) {
+            entry.handler = Some(handler);
+        }
+    }
+
+    /**
+     * Dispatch an interaction to the right handler. The registry is not locked while the handler runs
+     * @param registry containing handlers
+     * @param interaction to handle
+     * @param endpoint which received the interaction
+     * @return false if no handler is registered for this datatype
+     */
+    pub fn handle(registry: &Mutex<HandlerRegistry>, interaction: &Interaction, endpoint: &mut Endpoint) -> bool {
+        let handler = registry.lock().unwrap().take(&interaction.datatype);
+        match handler {
+            Some(mut handler) => {
+                handler.handle(interaction, endpoint);
+                registry.lock().unwrap().restore(handler);
+                true
+            },
+            None => false
+        }
+    }
+
+    /**
+     * Give a user entry to handlers. The registry is not locked while handlers run
+     * @param registry containing handlers
+     * @param text entered by the user
+     * @param endpoint which received the entry
+     * @return true if a handler consumed the entry
+     */
+    pub fn handle_user_text(registry: &Mutex<HandlerRegistry>, text: &str, endpoint: &mut Endpoint) -> bool {
+        let datatypes = registry.lock().unwrap().supported_types();
+        for datatype in datatypes {
+            let handler = registry.lock().unwrap().take(&datatype);
+            if let Some(mut handler) = handler {
+                let consumed = handler.on_user_text(text, endpoint);
+                registry.lock().unwrap().restore(handler);
+                if consumed {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+/**
+ * Say text/plain messages from RORI
+ */
+pub struct TextHandler;
+
+impl DatatypeHandler for TextHandler {
+    fn datatype(&self) -> &str {
+        "text/plain"
+    }
+
+    fn announce(&self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, interaction: &Interaction, endpoint: &mut Endpoint) {
+        endpoint.add_to_say_queue(&interaction.body);
+    }
+}
+
+/**
+ * Say text/ssml messages from RORI, with pauses, emphasis, spelled out words
+ * and language switches. Only their text is shown.
+ */
+pub struct SsmlHandler;
+
+impl DatatypeHandler for SsmlHandler {
+    fn datatype(&self) -> &str {
+        "text/ssml"
+    }
+
+    fn handle(&mut self, interaction: &Interaction, endpoint: &mut Endpoint) {
+        let markup = Markup::parse_ssml(&interaction.body).unwrap_or_else(|e| {
+            warn!("Invalid SSML from RORI ({}), said as text: {}", e, interaction.body);
+            Markup::plain(&interaction.body)
+        });
+        endpoint.add_markup_to_say_queue(markup);
+    }
+}
+
+/**
+ * An order from RORI
+ */
+#[derive(Deserialize, Debug)]
+struct RoriOrder {
+    /// Answer to /register or /link
+    registered: Option<bool>,
+}
+
+/**
+ * Handle orders from RORI: confirmations of registrations and links
+ */
+pub struct RoriMessageHandler {
+    bus: EventBus,
+}
+
+impl RoriMessageHandler {
+    /**
+     * @param bus informed when RORI confirms the registration
+     * @return a RoriMessageHandler
+     */
+    pub fn new(bus: EventBus) -> RoriMessageHandler {
+        RoriMessageHandler {
+            bus,
+        }
+    }
+}
+
+impl DatatypeHandler for RoriMessageHandler {
+    fn datatype(&self) -> &str {
+        "rori/message"
+    }
+
+    fn announce(&self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, interaction: &Interaction, endpoint: &mut Endpoint) {
+        match from_str::<RoriOrder>(&interaction.body) {
+            Ok(RoriOrder { registered: Some(true) }) => {
+                endpoint.set_login_state(LoginState::LoggedIn, &self.bus);
+                endpoint.announce_datatypes();
+                self.bus.publish(Event::RoriSpeech(String::new()));
+                self.bus.publish(Event::DeviceUpdate(endpoint.profile.clone(), DeviceUpdate::Linked));
+            },
+            Ok(RoriOrder { registered: Some(false) }) => {
+                let reason = String::from("RORI refused the registration");
+                if endpoint.login_state().is_logged() {
+                    // Answer to /devices add, the current login is kept
+                    let update = DeviceUpdate::Failed(reason);
+                    endpoint.add_to_say_queue(&update.to_string());
+                    self.bus.publish(Event::DeviceUpdate(endpoint.profile.clone(), update));
+                } else {
+                    endpoint.set_login_state(LoginState::Failed { reason: reason.clone() }, &self.bus);
+                    self.bus.publish(Event::DeviceUpdate(endpoint.profile.clone(), DeviceUpdate::Failed(reason)));
+                }
+            },
+            _ => {
+                warn!("Message received, but not recognized: {}", interaction.body);
+            }
+        };
+    }
+}
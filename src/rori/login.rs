@@ -0,0 +1,229 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Max time to wait for RORI to confirm a registration or a link
+pub const CONFIRMATION_TIMEOUT_SECS: u64 = 60;
+/// Delay before trying to reach the nameserver again, multiplied by the number of attempts
+pub const RETRY_DELAY_SECS: u64 = 10;
+/// Attempts before giving up
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/**
+ * Where the client is in the login to RORI
+ */
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum LoginState {
+    /// No account or RORI to use
+    Unconfigured,
+    /// Asking the nameserver if the account is known
+    Resolving,
+    /// Sending /register for a new username
+    Registering,
+    /// Sending /link for a username used by another device
+    Linking,
+    /// Waiting for RORI to confirm the registration or the link
+    AwaitingConfirmation,
+    LoggedIn,
+    /// The Ring account is not registered on the network, logged in again when it is
+    Offline { reason: String },
+    /// Stopped until the next login, e.g. after a reload
+    Failed { reason: String },
+}
+
+impl LoginState {
+    /**
+     * @param self
+     * @return true if logged in
+     */
+    pub fn is_logged(&self) -> bool {
+        *self == LoginState::LoggedIn
+    }
+}
+
+impl fmt::Display for LoginState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoginState::Unconfigured => write!(f, "not configured"),
+            LoginState::Resolving => write!(f, "resolving"),
+            LoginState::Registering => write!(f, "registering"),
+            LoginState::Linking => write!(f, "linking"),
+            LoginState::AwaitingConfirmation => write!(f, "awaiting confirmation"),
+            LoginState::LoggedIn => write!(f, "logged"),
+            LoginState::Offline { ref reason } => write!(f, "offline: {}", reason),
+            LoginState::Failed { ref reason } => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+/**
+ * The login state of an Endpoint, with its retries
+ */
+pub struct LoginMachine {
+    state: LoginState,
+    attempts: u32,
+    /// When to try again, and why
+    retry: Option<(Instant, String)>,
+}
+
+impl LoginMachine {
+    /**
+     * @return a LoginMachine for an Endpoint not logged yet
+     */
+    pub fn new() -> LoginMachine {
+        LoginMachine {
+            state: LoginState::Unconfigured,
+            attempts: 0,
+            retry: None,
+        }
+    }
+
+    /**
+     * @param self
+     * @return the current state
+     */
+    pub fn state(&self) -> &LoginState {
+        &self.state
+    }
+
+    /**
+     * Forget previous attempts, before a new login
+     * @param self
+     */
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.retry = None;
+    }
+
+    /**
+     * Change the state. A final state cancels the next retry
+     * @param self
+     * @param state new state
+     * @return true if the state changed
+     */
+    pub fn set(&mut self, state: LoginState) -> bool {
+        match state {
+            LoginState::LoggedIn => self.reset(),
+            LoginState::Unconfigured | LoginState::Offline { .. } | LoginState::Failed { .. } => self.retry = None,
+            _ => {}
+        }
+        if self.state == state {
+            return false;
+        }
+        self.state = state;
+        true
+    }
+
+    /**
+     * Try again later. Each call is an attempt
+     * @param self
+     * @param delay before the next attempt
+     * @param reason of the retry, used if it is the last attempt
+     */
+    pub fn retry_in(&mut self, delay: Duration, reason: &str) {
+        self.attempts += 1;
+        self.retry = Some((Instant::now() + delay, String::from(reason)));
+    }
+
+    /**
+     * @param self
+     * @return the delay before trying to reach the nameserver again
+     */
+    pub fn next_delay(&self) -> Duration {
+        Duration::from_secs(RETRY_DELAY_SECS * (self.attempts as u64 + 1))
+    }
+
+    /**
+     * Take the retry when it is due
+     * @param self
+     * @return None if nothing to do, else Ok(reason) to try again, or Err(reason) if attempts are exhausted
+     */
+    pub fn due(&mut self) -> Option<Result<String, String>> {
+        match self.retry {
+            Some((when, _)) if when <= Instant::now() => {},
+            _ => return None
+        }
+        let (_, reason) = self.retry.take()?;
+        if self.attempts >= MAX_ATTEMPTS {
+            Some(Err(reason))
+        } else {
+            Some(Ok(reason))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut login = LoginMachine::new();
+        login.set(LoginState::Resolving);
+        for _ in 1..MAX_ATTEMPTS {
+            login.retry_in(Duration::from_secs(0), "unreachable");
+            assert_eq!(login.due(), Some(Ok(String::from("unreachable"))));
+        }
+        login.retry_in(Duration::from_secs(0), "unreachable");
+        assert_eq!(login.due(), Some(Err(String::from("unreachable"))));
+        assert_eq!(login.due(), None);
+    }
+
+    #[test]
+    fn retry_not_due_before_its_delay() {
+        let mut login = LoginMachine::new();
+        login.retry_in(Duration::from_secs(60), "no confirmation");
+        assert_eq!(login.due(), None);
+    }
+
+    #[test]
+    fn final_states_cancel_the_retry() {
+        let mut login = LoginMachine::new();
+        login.set(LoginState::AwaitingConfirmation);
+        login.retry_in(Duration::from_secs(0), "no confirmation");
+        assert!(login.set(LoginState::LoggedIn));
+        assert_eq!(login.due(), None);
+        assert!(!login.set(LoginState::LoggedIn));
+
+        login.retry_in(Duration::from_secs(0), "offline");
+        login.set(LoginState::Failed { reason: String::from("removed") });
+        assert_eq!(login.due(), None);
+    }
+
+    #[test]
+    fn delay_grows_with_attempts() {
+        let mut login = LoginMachine::new();
+        assert_eq!(login.next_delay(), Duration::from_secs(RETRY_DELAY_SECS));
+        login.retry_in(Duration::from_secs(0), "unreachable");
+        assert_eq!(login.next_delay(), Duration::from_secs(2 * RETRY_DELAY_SECS));
+        login.reset();
+        assert_eq!(login.next_delay(), Duration::from_secs(RETRY_DELAY_SECS));
+    }
+}
@@ -0,0 +1,475 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use std::time::Duration;
+
+/// Longest pause RORI can ask for
+const MAX_PAUSE_MS: u64 = 10000;
+
+/**
+ * A piece of a marked up sentence
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Part {
+    Text {
+        text: String,
+        emphasis: bool,
+        /// Say each character
+        spell_out: bool,
+        /// Language of the text, like "fr-FR"
+        lang: Option<String>,
+    },
+    /// Silence, in milliseconds
+    Pause(u64),
+}
+
+/**
+ * What to do to say a Markup with an engine not understanding SSML
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Chunk {
+    Say(String),
+    Pause(Duration),
+}
+
+/**
+ * A sentence with pauses, emphasis, spelled out words and language switches,
+ * parsed from the subset of SSML sent by RORI in text/ssml interactions
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Markup {
+    parts: Vec<Part>,
+}
+
+/**
+ * Style given by the opened tags
+ */
+#[derive(Clone, Default)]
+struct Style {
+    tag: String,
+    emphasis: bool,
+    spell_out: bool,
+    lang: Option<String>,
+}
+
+impl Markup {
+    /**
+     * @param text without markup
+     * @return a Markup of this text
+     */
+    pub fn plain(text: &str) -> Markup {
+        let mut markup = Markup::default();
+        markup.push_text(text, &Style::default());
+        markup
+    }
+
+    /**
+     * Parse <speak>, <break time="500ms"/>, <emphasis>, <say-as interpret-as="characters">
+     * and <lang xml:lang="fr-FR">. Other tags are ignored, but not their content.
+     * @param body of the interaction
+     * @return the Markup, or an error if a tag is not closed
+     */
+    pub fn parse_ssml(body: &str) -> Result<Markup, String> {
+        let mut markup = Markup::default();
+        let mut styles = vec![Style::default()];
+        let mut rest = body;
+        while let Some(start) = rest.find('<') {
+            markup.push_text(&decode_entities(&rest[..start]), styles.last().unwrap());
+            let end = match rest[start..].find('>') {
+                Some(end) => start + end,
+                None => return Err(String::from("unclosed tag")),
+            };
+            let tag = rest[start + 1..end].trim();
+            rest = &rest[end + 1..];
+            if tag.starts_with('?') || tag.starts_with('!') {
+                continue;
+            }
+            if tag.starts_with('/') {
+                let name = tag[1..].trim();
+                // Close the matching tag, and the ones left open inside it
+                if let Some(idx) = styles.iter().rposition(|s| s.tag == name) {
+                    if idx > 0 {
+                        styles.truncate(idx);
+                    }
+                }
+                continue;
+            }
+            let self_closing = tag.ends_with('/');
+            let tag = tag.trim_end_matches('/');
+            let name = tag.split_whitespace().next().unwrap_or("");
+            let mut style = styles.last().unwrap().clone();
+            style.tag = String::from(name);
+            match name {
+                "break" => markup.parts.push(Part::Pause(pause_ms(tag))),
+                "emphasis" => style.emphasis = attribute(tag, "level").map_or(true, |level| level != "none" && level != "reduced"),
+                "say-as" => {
+                    let interpret = attribute(tag, "interpret-as").unwrap_or_default();
+                    style.spell_out = interpret == "characters" || interpret == "spell-out" || interpret == "verbatim";
+                },
+                _ => {}
+            }
+            if let Some(lang) = attribute(tag, "xml:lang") {
+                style.lang = Some(lang);
+            }
+            if !self_closing && name != "break" {
+                styles.push(style);
+            }
+        }
+        markup.push_text(&decode_entities(rest), styles.last().unwrap());
+        Ok(markup)
+    }
+
+    /**
+     * Add text with a style, merged with the previous text of the same style
+     * @param self
+     * @param text to add
+     * @param style of the text
+     */
+    fn push_text(&mut self, text: &str, style: &Style) {
+        if text == "" {
+            return;
+        }
+        if let Some(&mut Part::Text { text: ref mut previous, emphasis, spell_out, ref lang }) = self.parts.last_mut() {
+            if emphasis == style.emphasis && spell_out == style.spell_out && *lang == style.lang {
+                previous.push_str(text);
+                return;
+            }
+        }
+        self.parts.push(Part::Text {
+            text: String::from(text),
+            emphasis: style.emphasis,
+            spell_out: style.spell_out,
+            lang: style.lang.clone(),
+        });
+    }
+
+    /**
+     * @param self
+     * @return true if the sentence has no pause and no style
+     */
+    pub fn is_plain(&self) -> bool {
+        self.parts.iter().all(|part| match *part {
+            Part::Text { emphasis, spell_out, ref lang, .. } => !emphasis && !spell_out && lang.is_none(),
+            Part::Pause(_) => false,
+        })
+    }
+
+    /**
+     * Add a text before the sentence, like the profile speaking
+     * @param self
+     * @param prefix to add
+     * @return the Markup with the prefix
+     */
+    pub fn prefixed(mut self, prefix: &str) -> Markup {
+        if prefix != "" {
+            self.parts.insert(0, Part::Text {
+                text: String::from(prefix),
+                emphasis: false,
+                spell_out: false,
+                lang: None,
+            });
+        }
+        self
+    }
+
+    /**
+     * @param self
+     * @return the sentence without markup, to show it
+     */
+    pub fn plain_text(&self) -> String {
+        let text: String = self.parts.iter().map(|part| match *part {
+            Part::Text { ref text, .. } => &**text,
+            Part::Pause(_) => " ",
+        }).collect();
+        text.split_whitespace().collect::<Vec<&str>>().join(" ")
+    }
+
+    /**
+     * @param self
+     * @return the sentence as SSML, for engines understanding it
+     */
+    pub fn to_ssml(&self) -> String {
+        let mut ssml = String::from("<speak>");
+        for part in &self.parts {
+            match *part {
+                Part::Text { ref text, emphasis, spell_out, ref lang } => {
+                    let mut element = encode_entities(text);
+                    if spell_out {
+                        element = format!("<say-as interpret-as=\"characters\">{}</say-as>", element);
+                    }
+                    if emphasis {
+                        element = format!("<emphasis>{}</emphasis>", element);
+                    }
+                    if let Some(ref lang) = *lang {
+                        element = format!("<voice xml:lang=\"{}\">{}</voice>", encode_entities(lang), element);
+                    }
+                    ssml += &*element;
+                },
+                Part::Pause(ms) => ssml += &*format!("<break time=\"{}ms\"/>", ms),
+            }
+        }
+        ssml + "</speak>"
+    }
+
+    /**
+     * Split the sentence for engines without SSML: pauses become silences
+     * and spelled out words are said letter by letter
+     * @param self
+     * @return what to say and to wait
+     */
+    pub fn chunks(&self) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        let mut current = String::new();
+        for part in &self.parts {
+            match *part {
+                Part::Text { ref text, spell_out: true, .. } => {
+                    let letters: Vec<String> = text.chars().filter(|c| c.is_alphanumeric()).map(|c| c.to_string()).collect();
+                    current += &*letters.join(" ");
+                },
+                Part::Text { ref text, .. } => current += text,
+                Part::Pause(ms) => {
+                    if current.trim() != "" {
+                        chunks.push(Chunk::Say(current.split_whitespace().collect::<Vec<&str>>().join(" ")));
+                    }
+                    current.clear();
+                    if ms > 0 {
+                        chunks.push(Chunk::Pause(Duration::from_millis(ms)));
+                    }
+                }
+            }
+        }
+        if current.trim() != "" {
+            chunks.push(Chunk::Say(current.split_whitespace().collect::<Vec<&str>>().join(" ")));
+        }
+        chunks
+    }
+}
+
+/**
+ * @param tag content of a <break> tag
+ * @return the duration of the pause, from its time or its strength
+ */
+fn pause_ms(tag: &str) -> u64 {
+    if let Some(time) = attribute(tag, "time") {
+        let ms = if time.ends_with("ms") {
+            time.trim_end_matches("ms").parse::<f64>().ok()
+        } else {
+            time.trim_end_matches('s').parse::<f64>().ok().map(|s| s * 1000.)
+        };
+        return ms.map_or(500, |ms| (ms.max(0.) as u64).min(MAX_PAUSE_MS));
+    }
+    match &*attribute(tag, "strength").unwrap_or_default() {
+        "none" => 0,
+        "x-weak" => 100,
+        "weak" => 250,
+        "strong" => 750,
+        "x-strong" => 1000,
+        _ => 500,
+    }
+}
+
+/**
+ * @param tag content of a tag, like `break time="1s"`
+ * @param name of the attribute
+ * @return the value of the attribute if present
+ */
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    while let Some(idx) = rest.find(name) {
+        let after = &rest[idx + name.len()..];
+        let preceded = idx == 0 || rest[..idx].ends_with(char::is_whitespace);
+        let after = after.trim_start();
+        if preceded && after.starts_with('=') {
+            let value = after[1..].trim_start();
+            let quote = value.chars().next()?;
+            if quote == '"' || quote == '\'' {
+                let end = value[1..].find(quote)?;
+                return Some(decode_entities(&value[1..end + 1]));
+            }
+        }
+        rest = &rest[idx + name.len()..];
+    }
+    None
+}
+
+/**
+ * @param text from SSML
+ * @return the text with &amp;, &lt;, &gt;, &quot;, &apos; and &#NN; decoded
+ */
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded += &rest[..start];
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let entity = &rest[1..end];
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with('#') => entity[1..].parse::<u32>().ok().and_then(::std::char::from_u32),
+            _ => None,
+        };
+        match character {
+            Some(character) => {
+                decoded.push(character);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded + rest
+}
+
+/**
+ * @param text to put in SSML
+ * @return the text with special characters encoded
+ */
+fn encode_entities(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str, emphasis: bool, spell_out: bool, lang: Option<&str>) -> Part {
+        Part::Text {
+            text: String::from(text),
+            emphasis,
+            spell_out,
+            lang: lang.map(String::from),
+        }
+    }
+
+    #[test]
+    fn nested_tags() {
+        let markup = Markup::parse_ssml("<speak>Hello <emphasis>very <say-as interpret-as=\"characters\">ABC</say-as> much</emphasis> bye</speak>").unwrap();
+        assert_eq!(markup.parts, vec![
+            text("Hello ", false, false, None),
+            text("very ", true, false, None),
+            text("ABC", true, true, None),
+            text(" much", true, false, None),
+            text(" bye", false, false, None),
+        ]);
+        assert_eq!(markup.plain_text(), "Hello very ABC much bye");
+    }
+
+    #[test]
+    fn closing_a_tag_closes_the_ones_inside() {
+        let markup = Markup::parse_ssml("<speak><lang xml:lang=\"fr-FR\"><emphasis>Salut</lang> hi</speak>").unwrap();
+        assert_eq!(markup.parts, vec![
+            text("Salut", true, false, Some("fr-FR")),
+            text(" hi", false, false, None),
+        ]);
+    }
+
+    #[test]
+    fn malformed_tags() {
+        assert!(Markup::parse_ssml("<speak>Hello <break").is_err());
+        // Unclosed tags last until the end, unknown closing tags are ignored
+        let markup = Markup::parse_ssml("<?xml version=\"1.0\"?><speak><emphasis>Hi</strong> there").unwrap();
+        assert_eq!(markup.parts, vec![text("Hi there", true, false, None)]);
+        let markup = Markup::parse_ssml("</emphasis>Hi <unknown>there</unknown>").unwrap();
+        assert_eq!(markup, Markup::plain("Hi there"));
+        assert!(markup.is_plain());
+    }
+
+    #[test]
+    fn entities_decoded() {
+        assert_eq!(decode_entities("a &amp; b &lt;c&gt; &quot;d&quot; &apos;e&apos; caf&#233;"), "a & b <c> \"d\" 'e' café");
+        assert_eq!(decode_entities("&unknown; & alone &#xyz; &amp"), "&unknown; & alone &#xyz; &amp");
+        let markup = Markup::parse_ssml("<speak>Tom &amp; Jerry</speak>").unwrap();
+        assert_eq!(markup.plain_text(), "Tom & Jerry");
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(attribute("say-as interpret-as=\"characters\"", "interpret-as"), Some(String::from("characters")));
+        assert_eq!(attribute("lang xml:lang = 'fr-FR'", "xml:lang"), Some(String::from("fr-FR")));
+        assert_eq!(attribute("voice name=\"a &amp; b\"", "name"), Some(String::from("a & b")));
+        // Only whole names are matched
+        assert_eq!(attribute("say-as interpret-as=\"characters\"", "as"), None);
+        assert_eq!(attribute("break time=\"1s", "time"), None);
+        assert_eq!(attribute("break time=1s", "time"), None);
+        assert_eq!(attribute("break", "time"), None);
+    }
+
+    #[test]
+    fn pauses() {
+        assert_eq!(pause_ms("break time=\"250ms\""), 250);
+        assert_eq!(pause_ms("break time=\"1.5s\""), 1500);
+        assert_eq!(pause_ms("break time=\"60s\""), MAX_PAUSE_MS);
+        assert_eq!(pause_ms("break time=\"soon\""), 500);
+        assert_eq!(pause_ms("break strength=\"strong\""), 750);
+        assert_eq!(pause_ms("break strength=\"none\""), 0);
+        assert_eq!(pause_ms("break"), 500);
+        let markup = Markup::parse_ssml("<speak>One<break time=\"2s\"/>two<break time=\"300ms\"></break>three</speak>").unwrap();
+        assert_eq!(markup.parts, vec![
+            text("One", false, false, None),
+            Part::Pause(2000),
+            text("two", false, false, None),
+            Part::Pause(300),
+            text("three", false, false, None),
+        ]);
+    }
+
+    #[test]
+    fn chunks_for_engines_without_ssml() {
+        let markup = Markup::parse_ssml("<speak>Call <say-as interpret-as=\"characters\">ab1</say-as><break time=\"1s\"/> now<break strength=\"none\"/></speak>").unwrap();
+        assert_eq!(markup.chunks(), vec![
+            Chunk::Say(String::from("Call a b 1")),
+            Chunk::Pause(Duration::from_secs(1)),
+            Chunk::Say(String::from("now")),
+        ]);
+    }
+
+    #[test]
+    fn ssml_round_trip() {
+        let body = "<speak>Tom &amp; <emphasis>Jerry</emphasis><break time=\"1s\"/>\
+                    <say-as interpret-as=\"characters\">ok</say-as> \
+                    <lang xml:lang=\"fr-FR\">&lt;salut&gt;</lang></speak>";
+        let markup = Markup::parse_ssml(body).unwrap();
+        assert_eq!(markup.to_ssml(), "<speak>Tom &amp; <emphasis>Jerry</emphasis><break time=\"1000ms\"/>\
+                                      <say-as interpret-as=\"characters\">ok</say-as> \
+                                      <voice xml:lang=\"fr-FR\">&lt;salut&gt;</voice></speak>");
+        assert_eq!(Markup::parse_ssml(&markup.to_ssml()).unwrap(), markup);
+    }
+}
@@ -0,0 +1,249 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::daemon::{DaemonBackend, DaemonSignal, SignalListener};
+use rori::delivery::MessageStatus;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/**
+ * A message sent through the MockBackend
+ */
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    pub id: u64,
+    pub account_id: String,
+    pub to: String,
+    pub payloads: HashMap<String, String>,
+}
+
+struct MockState {
+    accounts: Vec<(String, HashMap<String, String>)>,
+    sent: Vec<SentMessage>,
+    /// (account_id, from) of trust requests waiting for an answer
+    trust_requests: Vec<(String, String)>,
+    /// Peers accepted as contacts
+    contacts: Vec<(String, String)>,
+    signals: VecDeque<DaemonSignal>,
+    next_id: u64,
+}
+
+/**
+ * In-memory daemon, used to run the client without a ring-daemon.
+ * Clones share the same state, so a test can keep one to inject signals
+ * and inspect what the client sent.
+ */
+#[derive(Clone)]
+pub struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockBackend {
+    /**
+     * @return a MockBackend without any account
+     */
+    pub fn new() -> MockBackend {
+        MockBackend {
+            state: Arc::new(Mutex::new(MockState {
+                accounts: Vec::new(),
+                sent: Vec::new(),
+                trust_requests: Vec::new(),
+                contacts: Vec::new(),
+                signals: VecDeque::new(),
+                next_id: 1,
+            })),
+        }
+    }
+
+    /**
+     * Add an enabled account with a known ring id
+     * @param self
+     * @param alias of the account
+     * @param ring_id of the account
+     * @return the account id
+     */
+    pub fn add_ring_account(&self, alias: &str, ring_id: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        let id = format!("mock{}", state.next_id);
+        state.next_id += 1;
+        let mut details = HashMap::new();
+        details.insert(String::from("Account.type"), String::from("RING"));
+        details.insert(String::from("Account.alias"), String::from(alias));
+        details.insert(String::from("Account.username"), format!("ring:{}", ring_id));
+        details.insert(String::from("Account.enable"), String::from("true"));
+        state.accounts.push((id.clone(), details));
+        id
+    }
+
+    /**
+     * Queue a signal, returned by the next call to SignalListener::next_signal
+     * @param self
+     * @param signal to emit
+     */
+    pub fn push_signal(&self, signal: DaemonSignal) {
+        self.state.lock().unwrap().signals.push_back(signal);
+    }
+
+    /**
+     * Receive a trust request, and emit its signal
+     * @param self
+     * @param account_id receiving the request
+     * @param from ring id of the peer
+     */
+    pub fn push_trust_request(&self, account_id: &str, from: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.trust_requests.push((String::from(account_id), String::from(from)));
+        state.signals.push_back(DaemonSignal::IncomingTrustRequest {
+            account_id: String::from(account_id),
+            from: String::from(from),
+        });
+    }
+
+    /**
+     * @param self
+     * @return (account_id, ring_id) of accepted peers
+     */
+    pub fn contacts(&self) -> Vec<(String, String)> {
+        self.state.lock().unwrap().contacts.clone()
+    }
+
+    /**
+     * @param self
+     * @return every message sent since the creation
+     */
+    pub fn sent_messages(&self) -> Vec<SentMessage> {
+        self.state.lock().unwrap().sent.clone()
+    }
+}
+
+impl DaemonBackend for MockBackend {
+    fn add_account(&self, details: HashMap<String, String>) -> Result<String, &'static str> {
+        let alias = details.get("Account.alias").cloned().unwrap_or_default();
+        let ring_id = format!("{:040x}", self.state.lock().unwrap().next_id);
+        let id = self.add_ring_account(&*alias, &*ring_id);
+        self.push_signal(DaemonSignal::AccountsChanged);
+        Ok(id)
+    }
+
+    fn get_account_list(&self) -> Result<Vec<String>, &'static str> {
+        Ok(self.state.lock().unwrap().accounts.iter().map(|a| a.0.clone()).collect())
+    }
+
+    fn get_account_details(&self, account_id: &str) -> Result<HashMap<String, String>, &'static str> {
+        let state = self.state.lock().unwrap();
+        match state.accounts.iter().find(|a| a.0 == account_id) {
+            Some(account) => Ok(account.1.clone()),
+            None => Ok(HashMap::new())
+        }
+    }
+
+    fn send_register(&self, account_id: &str, enable: bool) -> Result<(), &'static str> {
+        let mut state = self.state.lock().unwrap();
+        match state.accounts.iter_mut().find(|a| a.0 == account_id) {
+            Some(account) => {
+                account.1.insert(String::from("Account.enable"), enable.to_string());
+            },
+            None => return Err("Unknown account")
+        }
+        let registration_state = if enable { "REGISTERED" } else { "UNREGISTERED" };
+        state.signals.push_back(DaemonSignal::RegistrationStateChanged {
+            account_id: String::from(account_id),
+            state: String::from(registration_state),
+            code: 0,
+            detail: String::new(),
+        });
+        Ok(())
+    }
+
+    fn send_text_message(&self, account_id: &str, to: &str, payloads: HashMap<String, String>) -> Result<u64, &'static str> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.sent.push(SentMessage {
+            id,
+            account_id: String::from(account_id),
+            to: String::from(to),
+            payloads,
+        });
+        state.signals.push_back(DaemonSignal::MessageStatusChanged {
+            account_id: String::from(account_id),
+            message_id: id,
+            status: MessageStatus::Sent,
+        });
+        Ok(id)
+    }
+
+    fn get_trust_requests(&self, account_id: &str) -> Result<Vec<String>, &'static str> {
+        Ok(self.state.lock().unwrap().trust_requests.iter()
+            .filter(|r| r.0 == account_id)
+            .map(|r| r.1.clone())
+            .collect())
+    }
+
+    fn accept_trust_request(&self, account_id: &str, from: &str) -> Result<(), &'static str> {
+        let mut state = self.state.lock().unwrap();
+        let len = state.trust_requests.len();
+        state.trust_requests.retain(|r| r.0 != account_id || r.1 != from);
+        if state.trust_requests.len() == len {
+            return Err("Unknown trust request");
+        }
+        state.contacts.push((String::from(account_id), String::from(from)));
+        Ok(())
+    }
+
+    fn discard_trust_request(&self, account_id: &str, from: &str) -> Result<(), &'static str> {
+        let mut state = self.state.lock().unwrap();
+        let len = state.trust_requests.len();
+        state.trust_requests.retain(|r| r.0 != account_id || r.1 != from);
+        if state.trust_requests.len() == len {
+            return Err("Unknown trust request");
+        }
+        Ok(())
+    }
+
+    fn listen(&self) -> Result<Box<dyn SignalListener>, &'static str> {
+        Ok(Box::new(MockSignalListener {
+            state: self.state.clone(),
+        }))
+    }
+}
+
+struct MockSignalListener {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl SignalListener for MockSignalListener {
+    fn next_signal(&mut self, timeout_ms: u32) -> Option<DaemonSignal> {
+        if let Some(signal) = self.state.lock().unwrap().signals.pop_front() {
+            return Some(signal);
+        }
+        thread::sleep(Duration::from_millis(timeout_ms as u64));
+        self.state.lock().unwrap().signals.pop_front()
+    }
+}
@@ -27,5 +27,27 @@
 
 pub mod api;
 pub mod account;
+pub mod alarm;
+pub mod command;
+pub mod config;
+pub mod event;
+pub mod daemon;
+pub mod dbus_daemon;
+pub mod delivery;
+pub mod device;
+pub mod handler;
 pub mod interaction;
+pub mod login;
+pub mod markup;
+pub mod mock_daemon;
+pub mod music;
+pub mod outbox;
+pub mod reload;
+pub mod shutdown;
+pub mod speech;
+pub mod stt;
+pub mod trust;
+pub mod tts;
+pub mod tts_cache;
 pub mod endpoint;
+pub mod endpoints;
@@ -0,0 +1,433 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use dbus::{BusType, Connection, Message, Path};
+use dbus::arg::{Array, RefArg, Variant};
+use dbus::stdintf::org_freedesktop_dbus::Properties;
+use rori::endpoint::Endpoint;
+use rori::handler::DatatypeHandler;
+use rori::interaction::Interaction;
+use std::collections::HashMap;
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_IFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/**
+ * Control any MPRIS2 compatible player on the session bus
+ */
+#[derive(Clone)]
+pub struct MusicController {
+    /// Bus to use. None for the session bus
+    address: Option<String>,
+    /// Player to use if running (e.g. "rhythmbox")
+    preferred: Option<String>,
+}
+
+impl MusicController {
+    /**
+     * @param preferred player to use if running (e.g. "rhythmbox")
+     * @return a MusicController on the session bus
+     */
+    pub fn new(preferred: Option<String>) -> MusicController {
+        MusicController {
+            address: None,
+            preferred,
+        }
+    }
+
+    /**
+     * @param address of the bus to use (e.g. a private bus with a fake player)
+     * @param preferred player to use if running
+     * @return a MusicController
+     */
+    pub fn with_address(address: &str, preferred: Option<String>) -> MusicController {
+        MusicController {
+            address: Some(String::from(address)),
+            preferred,
+        }
+    }
+
+    /**
+     * Open a connection to the bus
+     * @param self
+     * @return the connection
+     */
+    fn connect(&self) -> Result<Connection, String> {
+        match self.address {
+            Some(ref address) => {
+                let conn = Connection::open_private(address).map_err(|e| e.to_string())?;
+                conn.register().map_err(|e| e.to_string())?;
+                Ok(conn)
+            },
+            None => Connection::get_private(BusType::Session).map_err(|e| e.to_string())
+        }
+    }
+
+    /**
+     * Find the player to control: the preferred one, then a playing one,
+     * then a paused one, then any player
+     * @param self
+     * @param conn to use
+     * @return the bus name of the player
+     */
+    fn find_player(&self, conn: &Connection) -> Result<String, String> {
+        let msg = Message::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                           "org.freedesktop.DBus", "ListNames")?;
+        let response = conn.send_with_reply_and_block(msg, 2000).map_err(|e| e.to_string())?;
+        let names: Array<&str, _> = response.get1().ok_or(String::from("ListNames returned nothing"))?;
+        let players: Vec<String> = names.filter(|n| n.starts_with(MPRIS_PREFIX))
+                                        .map(|n| n.to_string())
+                                        .collect();
+        if let Some(ref preferred) = self.preferred {
+            let name = format!("{}{}", MPRIS_PREFIX, preferred);
+            if players.contains(&name) {
+                return Ok(name);
+            }
+        }
+        for wanted in ["Playing", "Paused"].iter() {
+            for player in &players {
+                let status: String = conn.with_path(&**player, MPRIS_PATH, 2000)
+                                         .get(PLAYER_IFACE, "PlaybackStatus")
+                                         .unwrap_or_default();
+                if status == *wanted {
+                    return Ok(player.clone());
+                }
+            }
+        }
+        players.into_iter().next().ok_or(String::from("No music player found"))
+    }
+
+    /**
+     * Call a method without arguments on the player
+     * @param self
+     * @param method to call
+     */
+    fn call(&self, method: &str) -> Result<(), String> {
+        let conn = self.connect()?;
+        let player = self.find_player(&conn)?;
+        let msg = Message::new_method_call(&*player, MPRIS_PATH, PLAYER_IFACE, method)?;
+        conn.send_with_reply_and_block(msg, 2000).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    pub fn play(&self) -> Result<(), String> { self.call("Play") }
+    pub fn pause(&self) -> Result<(), String> { self.call("Pause") }
+    pub fn toggle(&self) -> Result<(), String> { self.call("PlayPause") }
+    pub fn stop(&self) -> Result<(), String> { self.call("Stop") }
+    pub fn next(&self) -> Result<(), String> { self.call("Next") }
+    pub fn previous(&self) -> Result<(), String> { self.call("Previous") }
+
+    /**
+     * @param self
+     * @return the volume, between 0 and 1
+     */
+    pub fn volume(&self) -> Result<f64, String> {
+        let conn = self.connect()?;
+        let player = self.find_player(&conn)?;
+        conn.with_path(&*player, MPRIS_PATH, 2000)
+            .get(PLAYER_IFACE, "Volume")
+            .map_err(|e| e.to_string())
+    }
+
+    /**
+     * @param self
+     * @param volume between 0 and 1
+     */
+    pub fn set_volume(&self, volume: f64) -> Result<(), String> {
+        let volume = volume.max(0.).min(1.);
+        let conn = self.connect()?;
+        let player = self.find_player(&conn)?;
+        conn.with_path(&*player, MPRIS_PATH, 2000)
+            .set(PLAYER_IFACE, "Volume", volume)
+            .map_err(|e| e.to_string())
+    }
+
+    /**
+     * Move in the current track
+     * @param self
+     * @param offset in seconds, can be negative
+     */
+    pub fn seek(&self, offset: i64) -> Result<(), String> {
+        let conn = self.connect()?;
+        let player = self.find_player(&conn)?;
+        let msg = Message::new_method_call(&*player, MPRIS_PATH, PLAYER_IFACE, "Seek")?
+                    .append1(offset * 1_000_000);
+        conn.send_with_reply_and_block(msg, 2000).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /**
+     * Go to a position in the current track
+     * @param self
+     * @param position in seconds
+     */
+    pub fn set_position(&self, position: i64) -> Result<(), String> {
+        let conn = self.connect()?;
+        let player = self.find_player(&conn)?;
+        let metadata = self.metadata(&conn, &player)?;
+        let track_id = metadata.get("mpris:trackid")
+                               .and_then(|v| v.0.as_str().map(|s| s.to_string()))
+                               .ok_or(String::from("Unknown track"))?;
+        let track_id = Path::new(track_id)?;
+        let msg = Message::new_method_call(&*player, MPRIS_PATH, PLAYER_IFACE, "SetPosition")?
+                    .append2(track_id, position * 1_000_000);
+        conn.send_with_reply_and_block(msg, 2000).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn metadata(&self, conn: &Connection, player: &str) -> Result<HashMap<String, Variant<Box<dyn RefArg>>>, String> {
+        conn.with_path(player, MPRIS_PATH, 2000)
+            .get(PLAYER_IFACE, "Metadata")
+            .map_err(|e| e.to_string())
+    }
+
+    /**
+     * @param self
+     * @return a sentence describing what is playing
+     */
+    pub fn now_playing(&self) -> Result<String, String> {
+        let conn = self.connect()?;
+        let player = self.find_player(&conn)?;
+        let status: String = conn.with_path(&*player, MPRIS_PATH, 2000)
+                                 .get(PLAYER_IFACE, "PlaybackStatus")
+                                 .map_err(|e| e.to_string())?;
+        if status == "Stopped" {
+            return Ok(String::from("Nothing is playing"));
+        }
+        let metadata = self.metadata(&conn, &player)?;
+        let title = metadata.get("xesam:title")
+                            .and_then(|v| v.0.as_str().map(|s| s.to_string()))
+                            .unwrap_or(String::from("an unknown track"));
+        let artists: Vec<String> = metadata.get("xesam:artist")
+                                           .and_then(|v| v.0.as_iter())
+                                           .map(|i| i.filter_map(|a| a.as_str().map(|s| s.to_string())).collect())
+                                           .unwrap_or_default();
+        let prefix = if status == "Paused" { "Paused" } else { "Now playing" };
+        if artists.is_empty() {
+            Ok(format!("{}: {}", prefix, title))
+        } else {
+            Ok(format!("{}: {} by {}", prefix, title, artists.join(", ")))
+        }
+    }
+
+    /**
+     * Execute an order from RORI
+     * @param self
+     * @param order like "start", "pause", "volume 50", "seek +30" or "what is playing"
+     * @return a sentence describing the result
+     */
+    pub fn execute(&self, order: &str) -> Result<String, String> {
+        let order = order.trim().to_lowercase();
+        let mut words = order.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let arg = words.collect::<Vec<&str>>().join(" ");
+        match command {
+            "start" | "play" | "resume" => self.play().map(|_| String::from("Playing")),
+            "pause" => self.pause().map(|_| String::from("Paused")),
+            "toggle" => self.toggle().map(|_| String::from("Done")),
+            "stop" => self.stop().map(|_| String::from("Stopped")),
+            "next" => self.next().map(|_| String::from("Next track")),
+            "previous" | "prev" | "back" => self.previous().map(|_| String::from("Previous track")),
+            "volume" => {
+                let current = self.volume()?;
+                let volume = match &*arg {
+                    "" => return Ok(format!("Volume at {}%", (current * 100.).round())),
+                    "up" => current + 0.1,
+                    "down" => current - 0.1,
+                    _ => arg.trim_end_matches('%').parse::<f64>()
+                            .map_err(|_| format!("Invalid volume: {}", arg))? / 100.
+                };
+                self.set_volume(volume)?;
+                Ok(format!("Volume at {}%", (volume.max(0.).min(1.) * 100.).round()))
+            },
+            "seek" => {
+                if arg.contains(':') {
+                    let mut parts = arg.splitn(2, ':');
+                    let minutes: i64 = parts.next().unwrap_or("").parse().map_err(|_| format!("Invalid position: {}", arg))?;
+                    let seconds: i64 = parts.next().unwrap_or("").parse().map_err(|_| format!("Invalid position: {}", arg))?;
+                    self.set_position(minutes * 60 + seconds)?;
+                } else {
+                    let offset: i64 = arg.trim_start_matches('+').parse().map_err(|_| format!("Invalid offset: {}", arg))?;
+                    self.seek(offset)?;
+                }
+                Ok(String::from("Done"))
+            },
+            "what" | "playing" | "status" => self.now_playing(),
+            _ => Err(format!("Unknown music order: {}", order))
+        }
+    }
+}
+
+/**
+ * Handle the music datatype and send the result back to RORI
+ */
+pub struct MusicHandler {
+    controller: MusicController,
+}
+
+impl MusicHandler {
+    /**
+     * @param controller used to control players
+     * @return a MusicHandler
+     */
+    pub fn new(controller: MusicController) -> MusicHandler {
+        MusicHandler {
+            controller,
+        }
+    }
+}
+
+impl DatatypeHandler for MusicHandler {
+    fn datatype(&self) -> &str {
+        "music"
+    }
+
+    fn handle(&mut self, interaction: &Interaction, endpoint: &mut Endpoint) {
+        let reply = match self.controller.execute(&interaction.body) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("music {} failed: {}", interaction.body, e);
+                e
+            }
+        };
+        endpoint.send_interaction_to_rori(&*reply, "text/plain");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::{MessageType, NameFlag};
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use std::time::Duration;
+
+    /**
+     * A private bus with fake players, stopped when dropped
+     */
+    struct Bus {
+        daemon: Child,
+        address: String,
+        stopped: Arc<AtomicBool>,
+    }
+
+    impl Bus {
+        fn start() -> Bus {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("dbus-daemon is needed by the tests");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            Bus {
+                daemon,
+                address: address.trim().to_string(),
+                stopped: Arc::new(AtomicBool::new(false)),
+            }
+        }
+
+        /**
+         * Start a fake player, without properties
+         * @param self
+         * @param name of the player
+         * @return methods called on the player
+         */
+        fn player(&self, name: &str) -> Receiver<String> {
+            let (tx, rx) = channel();
+            let (ready_tx, ready_rx) = channel();
+            let address = self.address.clone();
+            let name = format!("{}{}", MPRIS_PREFIX, name);
+            let stopped = self.stopped.clone();
+            thread::spawn(move || {
+                let conn = Connection::open_private(&address).unwrap();
+                conn.register().unwrap();
+                conn.register_name(&name, NameFlag::DoNotQueue as u32).unwrap();
+                conn.register_object_path(MPRIS_PATH).unwrap();
+                ready_tx.send(()).unwrap();
+                while !stopped.load(Ordering::SeqCst) {
+                    for msg in conn.incoming(100) {
+                        if msg.interface().is_some_and(|i| &*i == PLAYER_IFACE) {
+                            // Recorded before the reply, so the caller sees it once its call returns
+                            let _ = tx.send(msg.member().unwrap().to_string());
+                            let _ = conn.send(msg.method_return());
+                        } else if msg.msg_type() == MessageType::MethodCall {
+                            let error = Message::new_error(&msg, "org.freedesktop.DBus.Error.UnknownMethod", "Unknown");
+                            let _ = conn.send(error.unwrap());
+                        }
+                    }
+                }
+            });
+            ready_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            rx
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            self.stopped.store(true, Ordering::SeqCst);
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    #[test]
+    fn orders_sent_to_the_player() {
+        let bus = Bus::start();
+        let calls = bus.player("fake");
+        let controller = MusicController::with_address(&bus.address, None);
+        controller.play().unwrap();
+        controller.pause().unwrap();
+        controller.next().unwrap();
+        let received: Vec<String> = calls.try_iter().collect();
+        assert_eq!(received, vec!["Play", "Pause", "Next"]);
+    }
+
+    #[test]
+    fn preferred_player_used() {
+        let bus = Bus::start();
+        let other = bus.player("other");
+        let preferred = bus.player("preferred");
+        let controller = MusicController::with_address(&bus.address, Some(String::from("preferred")));
+        assert_eq!(controller.execute("next"), Ok(String::from("Next track")));
+        assert_eq!(preferred.try_iter().collect::<Vec<_>>(), vec!["Next"]);
+        assert!(other.try_iter().next().is_none());
+    }
+
+    #[test]
+    fn no_player_on_the_bus() {
+        let bus = Bus::start();
+        let controller = MusicController::with_address(&bus.address, None);
+        assert_eq!(controller.play(), Err(String::from("No music player found")));
+    }
+}
@@ -0,0 +1,338 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use libc;
+use serde_json;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use time;
+
+/// File of the outbox, in the data directory
+pub const OUTBOX_FILE: &str = "outbox.json";
+
+/**
+ * A message waiting to be sent to RORI
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OutboxEntry {
+    pub id: u64,
+    /// Account which sends the message
+    pub account_id: String,
+    pub datatype: String,
+    pub body: String,
+    /// When the message was queued, in seconds since epoch
+    pub created: i64,
+}
+
+/**
+ * Content of the outbox file
+ */
+#[derive(Serialize, Deserialize, Default)]
+struct OutboxFile {
+    /// Ids are never reused, so a sent message is never confused with a new one
+    next_id: u64,
+    entries: Vec<OutboxEntry>,
+}
+
+/**
+ * Exclusive lock of the outbox file, shared by the client and the command line.
+ * Released when dropped, with its file.
+ */
+struct OutboxLock {
+    _file: File,
+}
+
+impl OutboxLock {
+    /**
+     * Wait for the lock
+     * @param path of the outbox
+     * @return the lock, None if it can't be taken
+     */
+    fn acquire(path: &str) -> Option<OutboxLock> {
+        let file = OpenOptions::new().create(true).write(true).open(format!("{}.lock", path)).ok()?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return None;
+        }
+        Some(OutboxLock {
+            _file: file,
+        })
+    }
+}
+
+/**
+ * Messages written on the disk before being sent, so nothing is lost
+ * if the daemon or the network is down. The file is locked and read again
+ * for each change, so the command line can cancel messages of a running client.
+ */
+pub struct Outbox {
+    content: OutboxFile,
+    /// Messages sent, but maybe still on the disk. Never sent again
+    sent: Vec<u64>,
+    path: String,
+}
+
+impl Outbox {
+    /**
+     * @param path of the file, empty to keep messages in memory
+     * @return the Outbox
+     */
+    pub fn load(path: &str) -> Outbox {
+        let mut outbox = Outbox {
+            content: OutboxFile::default(),
+            sent: Vec::new(),
+            path: String::from(path),
+        };
+        outbox.update(|_| ((), false));
+        outbox
+    }
+
+    /**
+     * Read the file again
+     * @param self
+     */
+    fn refresh(&mut self) {
+        if self.path == "" {
+            return;
+        }
+        self.content = match File::open(&self.path) {
+            Ok(mut file) => {
+                let mut content = String::new();
+                let _ = file.read_to_string(&mut content);
+                serde_json::from_str(&content).unwrap_or_else(|e| {
+                    error!("Can't read the outbox from {}: {}", self.path, e);
+                    OutboxFile::default()
+                })
+            },
+            Err(_) => OutboxFile::default()
+        };
+    }
+
+    /**
+     * Write messages on the disk
+     * @param self
+     * @return false if the file can't be written
+     */
+    fn save(&self) -> bool {
+        if self.path == "" {
+            return true;
+        }
+        let content = serde_json::to_string_pretty(&self.content).unwrap_or(String::new());
+        let tmp = format!("{}.tmp", self.path);
+        let written = File::create(&tmp).and_then(|mut file| file.write_all(content.as_bytes()));
+        if written.and_then(|_| fs::rename(&tmp, &self.path)).is_err() {
+            error!("Can't save the outbox to {}", self.path);
+            return false;
+        }
+        true
+    }
+
+    /**
+     * Change the outbox with the file locked: read it, forget sent messages, change it and save it
+     * @param self
+     * @param change returns its result and if the outbox changed
+     * @return the result of change
+     */
+    fn update<T, F>(&mut self, change: F) -> T where F: FnOnce(&mut OutboxFile) -> (T, bool) {
+        let _lock = match &*self.path {
+            "" => None,
+            path => {
+                let lock = OutboxLock::acquire(path);
+                if lock.is_none() {
+                    warn!("Can't lock the outbox {}", path);
+                }
+                lock
+            }
+        };
+        self.refresh();
+        let len = self.content.entries.len();
+        let sent = self.sent.clone();
+        self.content.entries.retain(|e| !sent.contains(&e.id));
+        let (result, changed) = change(&mut self.content);
+        if (changed || self.content.entries.len() != len) && self.save() {
+            self.sent.clear();
+        }
+        result
+    }
+
+    /**
+     * Queue a message
+     * @param self
+     * @param account_id sending the message
+     * @param datatype of the message
+     * @param body of the message
+     * @return the id of the entry
+     */
+    pub fn push(&mut self, account_id: &str, datatype: &str, body: &str) -> u64 {
+        self.update(|content| {
+            let id = content.entries.iter().map(|e| e.id + 1).fold(content.next_id.max(1), u64::max);
+            content.next_id = id + 1;
+            content.entries.push(OutboxEntry {
+                id,
+                account_id: String::from(account_id),
+                datatype: String::from(datatype),
+                body: String::from(body),
+                created: time::get_time().sec,
+            });
+            (id, true)
+        })
+    }
+
+    /**
+     * Remove a message, sent or cancelled
+     * @param self
+     * @param id of the entry
+     * @return false if unknown
+     */
+    pub fn remove(&mut self, id: u64) -> bool {
+        self.update(|content| {
+            let len = content.entries.len();
+            content.entries.retain(|e| e.id != id);
+            let removed = content.entries.len() != len;
+            (removed, removed)
+        })
+    }
+
+    /**
+     * Remove a sent message. If the file can't be written, the message
+     * is still never sent again
+     * @param self
+     * @param id of the entry
+     */
+    pub fn mark_sent(&mut self, id: u64) {
+        self.sent.push(id);
+        self.update(|_| ((), false));
+    }
+
+    /**
+     * @param self
+     * @return every waiting message, oldest first
+     */
+    pub fn list(&mut self) -> Vec<OutboxEntry> {
+        self.update(|content| (content.entries.clone(), false))
+    }
+
+    /**
+     * @param self
+     * @param account_id sending messages
+     * @return waiting messages of the account, oldest first
+     */
+    pub fn entries_for(&mut self, account_id: &str) -> Vec<OutboxEntry> {
+        self.list().into_iter().filter(|e| e.account_id == account_id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::thread;
+
+    /**
+     * @param name of the test
+     * @return the path of an outbox file which doesn't exist yet
+     */
+    fn outbox_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("rori-outbox-{}-{}.json", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn clean(path: &str) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(format!("{}.lock", path));
+    }
+
+    #[test]
+    fn same_message_queued_twice() {
+        let mut outbox = Outbox::load("");
+        let first = outbox.push("account", "text/plain", "next");
+        let second = outbox.push("account", "text/plain", "next");
+        assert!(first != second);
+        assert_eq!(outbox.entries_for("account").len(), 2);
+        assert!(outbox.entries_for("other").is_empty());
+    }
+
+    #[test]
+    fn sent_messages_never_listed_again() {
+        let mut outbox = Outbox::load("");
+        let id = outbox.push("account", "text/plain", "next");
+        outbox.push("account", "text/plain", "next");
+        outbox.mark_sent(id);
+        let entries = outbox.list();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].id != id);
+    }
+
+    #[test]
+    fn ids_not_reused() {
+        let path = outbox_path("ids");
+        let mut outbox = Outbox::load(&path);
+        let first = outbox.push("account", "text/plain", "hello");
+        assert!(outbox.remove(first));
+        assert!(!outbox.remove(first));
+        let second = Outbox::load(&path).push("account", "text/plain", "hello");
+        assert!(second > first);
+        clean(&path);
+    }
+
+    #[test]
+    fn changes_of_another_process_kept() {
+        let path = outbox_path("shared");
+        let mut client = Outbox::load(&path);
+        let mut cli = Outbox::load(&path);
+        let cancelled = client.push("account", "text/plain", "play music");
+        assert!(cli.remove(cancelled));
+        let kept = client.push("account", "text/plain", "next");
+        let ids: Vec<u64> = cli.list().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![kept]);
+        clean(&path);
+    }
+
+    #[test]
+    fn concurrent_pushes_not_lost() {
+        let path = outbox_path("concurrent");
+        let writers: Vec<_> = (0..4).map(|writer| {
+            let path = path.clone();
+            thread::spawn(move || {
+                let mut outbox = Outbox::load(&path);
+                for i in 0..25 {
+                    outbox.push("account", "text/plain", &format!("{} {}", writer, i));
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let mut entries = Outbox::load(&path).list();
+        assert_eq!(entries.len(), 100);
+        entries.dedup_by_key(|e| e.id);
+        assert_eq!(entries.len(), 100);
+        clean(&path);
+    }
+}
@@ -0,0 +1,668 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::alarm::{AlarmHandler, AlarmRunner, AlarmStore};
+use rori::api::API;
+use rori::command::{CommandHandler, CommandPolicy};
+use rori::config::{Config, ConfigError, ConfigLoader, Profile};
+use rori::delivery::Undelivered;
+use rori::device::DeviceCommand;
+use rori::endpoint::Endpoint;
+use rori::endpoints::EndpointSet;
+use rori::event::{Event, EventBus};
+use rori::handler::{RoriMessageHandler, SsmlHandler, TextHandler};
+use rori::login::LoginState;
+use rori::music::{MusicController, MusicHandler};
+use rori::outbox::{Outbox, OUTBOX_FILE};
+use rori::shutdown::ShutdownToken;
+use rori::stt::{self, SpeechRecognizer};
+use rori::speech::SpeechQueue;
+use rori::trust::{TrustDecision, TrustRequest};
+use rori::tts_cache::{SpeechCache, CACHE_DIR};
+use serde_json::{self, Value};
+use signal_hook;
+use signal_hook::iterator::Signals;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Keys only read at startup
+const RESTART_KEYS: [&str; 6] = ["data_dir", "alarm_grace_secs", "stt",
+                                 "timeouts.daemon_ms", "timeouts.say_interval_ms", "timeouts.shutdown_secs"];
+/// Sections compared key by key
+const SECTIONS: [&str; 4] = ["api", "tts", "handlers", "timeouts"];
+
+/**
+ * What a reload changed
+ */
+#[derive(Serialize, Debug, Default)]
+pub struct ReloadReport {
+    /// Keys with a new value
+    pub changed: Vec<String>,
+    /// Changed keys which will only be used after a restart
+    pub restart_needed: Vec<String>,
+    /// The new configuration is invalid and was ignored
+    pub errors: Vec<ConfigError>,
+}
+
+/**
+ * Reload the configuration and apply changes to the running client
+ */
+pub struct Reloader {
+    config_file: Option<String>,
+    overrides: Vec<String>,
+    current: Config,
+    bus: EventBus,
+    alarms: Arc<Mutex<AlarmStore>>,
+    data_dir: PathBuf,
+    endpoints: Option<Arc<Mutex<EndpointSet>>>,
+    /// Identities of the connected secondary endpoints
+    connected: HashMap<String, Profile>,
+    runner: Option<Arc<Mutex<AlarmRunner>>>,
+    api: Option<API>,
+}
+
+impl Reloader {
+    /**
+     * @param config_file given on the command line
+     * @param overrides --set options given on the command line
+     * @param current configuration used at startup
+     * @param bus used by the client
+     * @param alarms saved alarms
+     * @param data_dir where data files are
+     * @return a Reloader, without anything to update yet
+     */
+    pub fn new(config_file: Option<String>, overrides: Vec<String>, current: Config,
+               bus: EventBus, alarms: Arc<Mutex<AlarmStore>>, data_dir: PathBuf) -> Reloader {
+        Reloader {
+            config_file,
+            overrides,
+            current,
+            bus,
+            alarms,
+            data_dir,
+            endpoints: None,
+            connected: HashMap::new(),
+            runner: None,
+            api: None,
+        }
+    }
+
+    /**
+     * @param self
+     * @return a loader reading the same layers as at startup
+     */
+    pub fn loader(&self) -> ConfigLoader {
+        ConfigLoader::standard(self.config_file.as_ref().map(|c| &**c), &self.overrides)
+    }
+
+    /**
+     * Update the endpoints and the alarm runner from now, and connect profiles in `connect`
+     * @param self
+     * @param endpoints with the primary endpoint initialized
+     * @param runner ringing alarms
+     * @param config used to initialize them
+     */
+    pub fn attach(&mut self, endpoints: Arc<Mutex<EndpointSet>>, runner: Arc<Mutex<AlarmRunner>>, config: Config) {
+        self.endpoints = Some(endpoints);
+        self.runner = Some(runner);
+        self.current = config;
+        let config = self.current.clone();
+        self.connect_profiles(&config);
+    }
+
+    /**
+     * @param self
+     * @return the endpoint of the active profile, if started
+     */
+    fn primary(&self) -> Option<Arc<Mutex<Endpoint>>> {
+        self.endpoints.as_ref().map(|set| set.lock().unwrap().primary())
+    }
+
+    /**
+     * @param self
+     * @return every started endpoint
+     */
+    fn all_endpoints(&self) -> Vec<Arc<Mutex<Endpoint>>> {
+        match self.endpoints {
+            Some(ref set) => set.lock().unwrap().all(),
+            None => Vec::new()
+        }
+    }
+
+    /**
+     * Start endpoints of profiles in `connect`, stop the others
+     * @param self
+     * @param config wanted configuration
+     */
+    fn connect_profiles(&mut self, config: &Config) {
+        let set = match self.endpoints {
+            Some(ref set) => set.clone(),
+            None => return
+        };
+        let mut wanted = HashMap::new();
+        for name in config.connect.iter().filter(|name| **name != config.profile) {
+            if let Some(profile) = config.resolve_profile(name) {
+                wanted.insert(name.clone(), profile);
+            }
+        }
+        let connected: Vec<String> = self.connected.keys().cloned().collect();
+        for name in connected {
+            if wanted.get(&name) == self.connected.get(&name) {
+                continue;
+            }
+            info!("Disconnecting profile {}", name);
+            set.lock().unwrap().remove(&name);
+            self.connected.remove(&name);
+            self.bus.publish(Event::LoginStateChanged(name, LoginState::Unconfigured));
+        }
+        let (backend, trust, outbox, speech) = {
+            let primary = set.lock().unwrap().primary();
+            let primary = primary.lock().unwrap();
+            (primary.backend(), primary.trust_store(), primary.outbox(), primary.speech())
+        };
+        for (name, profile) in wanted {
+            if self.connected.contains_key(&name) {
+                continue;
+            }
+            info!("Connecting profile {}", name);
+            let mut endpoint = match Endpoint::init(backend.clone(), &profile.ring_id,
+                                                    &profile.rori_server, &profile.rori_ring_id) {
+                Ok(endpoint) => endpoint,
+                Err(e) => {
+                    error!("Can't connect profile {}: {}", name, e);
+                    continue;
+                }
+            };
+            endpoint.profile = name.clone();
+            endpoint.set_tts(config.tts.clone());
+            endpoint.set_trust_store(trust.clone());
+            endpoint.process_trust_requests();
+            endpoint.set_outbox(outbox.clone());
+            endpoint.set_speech(speech.clone(), &format!("{}: ", name));
+            register_handlers(&endpoint, config, &self.bus, &self.alarms, &self.data_dir);
+            let endpoint = Arc::new(Mutex::new(endpoint));
+            Endpoint::login(endpoint.clone(), &self.bus);
+            set.lock().unwrap().insert(&name, &profile.wake_word, endpoint);
+            self.connected.insert(name, profile);
+        }
+    }
+
+    /**
+     * Start the API with the current configuration, and update it from now
+     * @param self
+     * @param api to start
+     */
+    pub fn attach_api(&mut self, mut api: API) {
+        api.apply(&self.current.api);
+        self.api = Some(api);
+    }
+
+    /**
+     * Stop the API
+     * @param self
+     */
+    pub fn close_api(&mut self) {
+        if let Some(ref mut api) = self.api {
+            api.close();
+        }
+    }
+
+    /**
+     * @param self
+     * @return the active profile (empty for none) and every profile
+     */
+    pub fn profiles(&self) -> (String, Vec<String>) {
+        (self.current.profile.clone(), self.current.profile_names())
+    }
+
+    /**
+     * @param self
+     * @return the login state of each connected profile (empty for the top level identity)
+     */
+    pub fn login_states(&self) -> BTreeMap<String, LoginState> {
+        self.all_endpoints().iter()
+            .map(|endpoint| {
+                let endpoint = endpoint.lock().unwrap();
+                (endpoint.profile.clone(), endpoint.login_state())
+            })
+            .collect()
+    }
+
+    /**
+     * Log in again every endpoint which is not logged, e.g. after a failure
+     * @param self
+     */
+    pub fn login_again(&self) {
+        for endpoint in self.all_endpoints() {
+            let logged = endpoint.lock().unwrap().login_state().is_logged();
+            if !logged {
+                Endpoint::login(endpoint, &self.bus);
+            }
+        }
+    }
+
+    /**
+     * @param self
+     * @return trust requests waiting for the user, for every connected profile
+     */
+    pub fn trust_requests(&self) -> Vec<TrustRequest> {
+        let mut requests = Vec::new();
+        for endpoint in self.all_endpoints() {
+            let endpoint = endpoint.lock().unwrap();
+            for from in endpoint.trust_requests() {
+                requests.push(TrustRequest {
+                    profile: endpoint.profile.clone(),
+                    from,
+                });
+            }
+        }
+        requests
+    }
+
+    /**
+     * Answer a trust request, with the endpoint which received it
+     * @param self
+     * @param from ring id of the peer
+     * @param decision to apply
+     * @return an error if the request is unknown or the daemon refused
+     */
+    pub fn answer_trust_request(&self, from: &str, decision: TrustDecision) -> Result<(), String> {
+        for endpoint in self.all_endpoints() {
+            let endpoint = endpoint.lock().unwrap();
+            if endpoint.trust_requests().iter().any(|r| r == from) {
+                return endpoint.answer_trust_request(from, decision).map_err(String::from);
+            }
+        }
+        Err(format!("No trust request from {}", from))
+    }
+
+    /**
+     * @param self
+     * @return messages not received by RORI yet, or given up, for every connected profile
+     */
+    pub fn undelivered(&self) -> Vec<Undelivered> {
+        let mut undelivered = Vec::new();
+        for endpoint in self.all_endpoints() {
+            let endpoint = endpoint.lock().unwrap();
+            undelivered.extend(endpoint.undelivered().into_iter().map(|message| Undelivered {
+                profile: endpoint.profile.clone(),
+                message,
+            }));
+        }
+        undelivered
+    }
+
+    /**
+     * @param self
+     * @return the outbox file of the client
+     */
+    pub fn outbox(&self) -> Outbox {
+        Outbox::load(&self.data_dir.join(OUTBOX_FILE).to_string_lossy())
+    }
+
+    /**
+     * @param self
+     * @return the speech recognizer of the configuration, None if disabled
+     */
+    pub fn recognizer(&self) -> Option<Box<dyn SpeechRecognizer>> {
+        stt::recognizer(&self.current.stt)
+    }
+
+    /**
+     * @param self
+     * @return the cache of rendered sentences, with the current voice
+     */
+    pub fn speech_cache(&self) -> SpeechCache {
+        SpeechCache::new(&self.data_dir.join(CACHE_DIR), &self.current.tts)
+    }
+
+    /**
+     * Send a device command with the endpoint of the active profile
+     * @param self
+     * @param command to send
+     * @return the profile of the endpoint, to wait for its answer, or an error
+     */
+    pub fn device_command(&self, command: &DeviceCommand) -> Result<String, String> {
+        let endpoint = match self.primary() {
+            Some(endpoint) => endpoint,
+            None => return Err(String::from("Not connected to RORI yet"))
+        };
+        let mut endpoint = endpoint.lock().unwrap();
+        if !endpoint.send_device_command(command) {
+            return Err(String::from("Cannot send the command to RORI"));
+        }
+        Ok(endpoint.profile.clone())
+    }
+
+    /**
+     * Use another profile, and log in with its account
+     * @param self
+     * @param name of the profile, empty for the top level identity
+     * @return what changed
+     */
+    pub fn switch_profile(&mut self, name: &str) -> ReloadReport {
+        let profiles = self.loader().load().map(|c| c.profile_names()).unwrap_or_default();
+        if name != "" && !profiles.iter().any(|p| p == name) {
+            let mut report = ReloadReport::default();
+            report.errors.push(ConfigError {
+                key: String::from("profile"),
+                source: String::from(name),
+                message: format!("unknown profile, expected one of: {}", profiles.join(", ")),
+            });
+            return report;
+        }
+        let previous = self.overrides.clone();
+        self.overrides.retain(|o| !o.starts_with("profile="));
+        self.overrides.push(format!("profile={}", serde_json::to_string(name).unwrap_or_default()));
+        info!("Switching to profile {}", name);
+        let report = self.reload();
+        if !report.errors.is_empty() {
+            self.overrides = previous;
+        }
+        report
+    }
+
+    /**
+     * Say something with the endpoint, or only show it if not started
+     * @param self
+     * @param sentence to say
+     */
+    fn say(&self, sentence: &str) {
+        match self.primary() {
+            Some(endpoint) => endpoint.lock().unwrap().add_to_say_queue(&String::from(sentence)),
+            None => self.bus.publish(Event::RoriSpeech(String::from(sentence)))
+        }
+    }
+
+    /**
+     * Read the configuration again, and apply what changed
+     * @param self
+     * @return what changed
+     */
+    pub fn reload(&mut self) -> ReloadReport {
+        let mut report = ReloadReport::default();
+        let mut new = match self.loader().load() {
+            Ok(config) => config,
+            Err(errors) => {
+                for error in &errors {
+                    error!("Configuration not reloaded: {}", error);
+                }
+                report.errors = errors;
+                return report;
+            }
+        };
+        report.changed = diff(&self.current, &new);
+        report.restart_needed = report.changed.iter()
+            .filter(|key| RESTART_KEYS.contains(&&***key))
+            .cloned()
+            .collect();
+        let changed_keys = report.changed.clone();
+        let changed = |prefix: &str| changed_keys.iter()
+            .any(|key| key == prefix || key.starts_with(&format!("{}.", prefix)));
+
+        for endpoint in self.all_endpoints() {
+            if changed("tts") {
+                endpoint.lock().unwrap().set_tts(new.tts.clone());
+            }
+            if changed("handlers") || changed("music_player") || changed("commands") {
+                let endpoint = endpoint.lock().unwrap();
+                register_handlers(&endpoint, &new, &self.bus, &self.alarms, &self.data_dir);
+                endpoint.announce_datatypes();
+            }
+        }
+        if let Some(ref endpoint) = self.primary() {
+            if changed("ring_id") || changed("username") || changed("rori_server") || changed("rori_ring_id") {
+                let res = endpoint.lock().unwrap().switch_account(&new.ring_id, &new.rori_server, &new.rori_ring_id);
+                match res {
+                    Ok(_) => {
+                        {
+                            let mut endpoint = endpoint.lock().unwrap();
+                            endpoint.set_login_state(LoginState::Unconfigured, &self.bus);
+                            endpoint.profile = new.profile.clone();
+                            endpoint.process_trust_requests();
+                        }
+                        Endpoint::login(endpoint.clone(), &self.bus);
+                    },
+                    Err(e) => {
+                        error!("Can't use account {}: {}", new.ring_id, e);
+                        report.errors.push(ConfigError {
+                            key: String::from("ring_id"),
+                            source: String::from("daemon"),
+                            message: String::from(e),
+                        });
+                        // Try again at the next reload
+                        new.ring_id = self.current.ring_id.clone();
+                        new.username = self.current.username.clone();
+                        new.rori_server = self.current.rori_server.clone();
+                        new.rori_ring_id = self.current.rori_ring_id.clone();
+                        new.profile = self.current.profile.clone();
+                    }
+                }
+            }
+            endpoint.lock().unwrap().profile = new.profile.clone();
+        }
+        if let Some(ref runner) = self.runner {
+            if changed("alarm_actions") || changed("commands") || changed("music_player") {
+                let speech = match self.primary() {
+                    Some(endpoint) => endpoint.lock().unwrap().speech(),
+                    None => SpeechQueue::new(&new.tts, "", self.bus.clone())
+                };
+                *runner.lock().unwrap() = alarm_runner(&new, speech, &self.data_dir);
+            }
+        }
+        if changed("api") {
+            if let Some(ref mut api) = self.api {
+                api.apply(&new.api);
+            }
+        }
+        self.connect_profiles(&new);
+
+        // Keep values used until the restart, to report them again
+        new.data_dir = self.current.data_dir.clone();
+        new.alarm_grace_secs = self.current.alarm_grace_secs;
+        new.stt = self.current.stt.clone();
+        new.timeouts.daemon_ms = self.current.timeouts.daemon_ms;
+        new.timeouts.say_interval_ms = self.current.timeouts.say_interval_ms;
+        new.timeouts.shutdown_secs = self.current.timeouts.shutdown_secs;
+        self.current = new;
+
+        if report.changed.is_empty() {
+            info!("Configuration reloaded, nothing changed");
+        } else {
+            info!("Configuration reloaded, changed: {}", report.changed.join(", "));
+        }
+        if !report.restart_needed.is_empty() {
+            warn!("Restart the client to use: {}", report.restart_needed.join(", "));
+        }
+        report
+    }
+}
+
+/**
+ * @param config current configuration
+ * @param data_dir where relative audit logs are written
+ * @return the command policy with the audit log in the data directory
+ */
+pub fn command_policy(config: &Config, data_dir: &Path) -> CommandPolicy {
+    let mut policy = config.commands.clone();
+    if Path::new(&policy.audit_log).is_relative() {
+        policy.audit_log = data_dir.join(&policy.audit_log).to_string_lossy().into_owned();
+    }
+    policy
+}
+
+/**
+ * Register text/plain, text/ssml and rori/message, then optional handlers enabled in the configuration, remove the others
+ * @param endpoint to update
+ * @param config current configuration
+ * @param bus where RORI messages are published
+ * @param alarms saved alarms
+ * @param data_dir where data files are
+ */
+pub fn register_handlers(endpoint: &Endpoint, config: &Config, bus: &EventBus,
+                         alarms: &Arc<Mutex<AlarmStore>>, data_dir: &Path) {
+    endpoint.register_handler(Box::new(TextHandler));
+    endpoint.register_handler(Box::new(SsmlHandler));
+    endpoint.register_handler(Box::new(RoriMessageHandler::new(bus.clone())));
+    if config.handlers.music {
+        let music = MusicController::new(config.music_player.clone());
+        endpoint.register_handler(Box::new(MusicHandler::new(music)));
+    } else {
+        endpoint.unregister_handler("music");
+    }
+    if config.handlers.command {
+        endpoint.register_handler(Box::new(CommandHandler::new(command_policy(config, data_dir))));
+    } else {
+        endpoint.unregister_handler("command");
+    }
+    if config.handlers.alarm {
+        endpoint.register_handler(Box::new(AlarmHandler::new(alarms.clone())));
+    } else {
+        endpoint.unregister_handler("alarm");
+    }
+}
+
+/**
+ * @param config current configuration
+ * @param speech queue of sentences to say
+ * @param data_dir where data files are
+ * @return a runner for alarm actions
+ */
+pub fn alarm_runner(config: &Config, speech: SpeechQueue, data_dir: &Path) -> AlarmRunner {
+    AlarmRunner::new(config.alarm_actions.clone(), speech, command_policy(config, data_dir),
+                     MusicController::new(config.music_player.clone()))
+}
+
+/**
+ * Compare two configurations
+ * @param old configuration
+ * @param new configuration
+ * @return dotted keys with a different value
+ */
+pub fn diff(old: &Config, new: &Config) -> Vec<String> {
+    let old = serde_json::to_value(old).unwrap_or(Value::Null);
+    let new = serde_json::to_value(new).unwrap_or(Value::Null);
+    let mut changed = Vec::new();
+    if let (Some(old), Some(new)) = (old.as_object(), new.as_object()) {
+        for (key, value) in new {
+            let old_value = old.get(key).unwrap_or(&Value::Null);
+            match (old_value.as_object(), value.as_object()) {
+                (Some(old_section), Some(section)) if SECTIONS.contains(&&**key) => {
+                    for (sub_key, sub_value) in section {
+                        if old_section.get(sub_key) != Some(sub_value) {
+                            changed.push(format!("{}.{}", key, sub_key));
+                        }
+                    }
+                },
+                _ => {
+                    if old_value != value {
+                        changed.push(key.clone());
+                    }
+                }
+            }
+        }
+    }
+    changed
+}
+
+/**
+ * Handle "/profile [name]" entered by the user: list profiles or switch to one
+ * @param reloader to call
+ * @param bus where entries are published
+ */
+pub fn listen_profile_commands(reloader: Arc<Mutex<Reloader>>, bus: &EventBus) {
+    let events = bus.subscribe();
+    thread::spawn(move || {
+        for event in events {
+            let text = match event {
+                Event::UserUtterance(ref text, _) if Endpoint::is_a_local_command(text) => text.clone(),
+                _ => continue
+            };
+            let name = text["/profile".len()..].trim();
+            let mut reloader = reloader.lock().unwrap();
+            if name == "" {
+                let (active, profiles) = reloader.profiles();
+                let active = if active == "" { String::from("none") } else { active };
+                reloader.say(&format!("Active profile: {}. Profiles: {}", active, profiles.join(", ")));
+                continue;
+            }
+            let report = reloader.switch_profile(name);
+            match report.errors.first() {
+                Some(error) => reloader.say(&format!("Can't switch to {}: {}", name, error.message)),
+                None => reloader.say(&format!("Profile {} activated", name))
+            }
+        }
+    });
+}
+
+/**
+ * Reload the configuration on SIGHUP
+ * @param reloader to call
+ * @return an error if signals can't be handled
+ */
+pub fn listen_sighup(reloader: Arc<Mutex<Reloader>>) -> io::Result<()> {
+    let signals = Signals::new(&[signal_hook::SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("SIGHUP received, reloading the configuration");
+            reloader.lock().unwrap().reload();
+        }
+    });
+    Ok(())
+}
+
+/**
+ * Reload the configuration when one of its files changes, until a shutdown is requested
+ * @param reloader to call
+ * @param shutdown when to quit
+ * @return the thread
+ */
+pub fn watch(reloader: Arc<Mutex<Reloader>>, shutdown: ShutdownToken) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let two_secs = Duration::from_secs(2);
+        let files = reloader.lock().unwrap().loader().files().clone();
+        let modified = |files: &Vec<PathBuf>| -> HashMap<PathBuf, Option<SystemTime>> {
+            files.iter()
+                .map(|f| (f.clone(), fs::metadata(f).and_then(|m| m.modified()).ok()))
+                .collect()
+        };
+        let mut last = modified(&files);
+        while !shutdown.wait_timeout(two_secs) {
+            let current = modified(&files);
+            if current != last {
+                info!("Configuration file changed, reloading");
+                reloader.lock().unwrap().reload();
+                last = current;
+            }
+        }
+    })
+}
@@ -0,0 +1,168 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use signal_hook;
+use signal_hook::iterator::Signals;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/**
+ * Shared between every thread to know when the client must stop.
+ * Clones share the same state.
+ */
+#[derive(Clone)]
+pub struct ShutdownToken {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl ShutdownToken {
+    /**
+     * @return a ShutdownToken not requested
+     */
+    pub fn new() -> ShutdownToken {
+        ShutdownToken {
+            state: Arc::new((Mutex::new(false), Condvar::new())),
+        }
+    }
+
+    /**
+     * Ask every thread to stop
+     * @param self
+     */
+    pub fn request(&self) {
+        let &(ref requested, ref cvar) = &*self.state;
+        *requested.lock().unwrap() = true;
+        cvar.notify_all();
+    }
+
+    /**
+     * @param self
+     * @return true if the client must stop
+     */
+    pub fn is_requested(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /**
+     * Block until a shutdown is requested
+     * @param self
+     */
+    pub fn wait(&self) {
+        let &(ref requested, ref cvar) = &*self.state;
+        let mut requested = requested.lock().unwrap();
+        while !*requested {
+            requested = cvar.wait(requested).unwrap();
+        }
+    }
+
+    /**
+     * Sleep, but wake up if a shutdown is requested
+     * @param self
+     * @param timeout maximum time to wait
+     * @return true if the client must stop
+     */
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let &(ref requested, ref cvar) = &*self.state;
+        let requested = requested.lock().unwrap();
+        if *requested {
+            return true;
+        }
+        let (requested, _) = cvar.wait_timeout(requested, timeout).unwrap();
+        *requested
+    }
+
+    /**
+     * Request a shutdown on SIGINT or SIGTERM
+     * @param self
+     */
+    pub fn listen_signals(&self) -> io::Result<()> {
+        let signals = Signals::new(&[signal_hook::SIGINT, signal_hook::SIGTERM])?;
+        let token = self.clone();
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("Signal {} received, stopping", signal);
+                token.request();
+            }
+        });
+        Ok(())
+    }
+}
+
+/**
+ * Wait for threads to finish, but not longer than timeout
+ * @param threads (name, handle) to join
+ * @param timeout maximum time to wait for all threads
+ * @return true if every thread finished without panicking
+ */
+pub fn join_all(threads: Vec<(&'static str, thread::JoinHandle<()>)>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut threads = threads;
+    let mut success = true;
+    loop {
+        let (finished, running): (Vec<_>, Vec<_>) = threads.into_iter().partition(|t| t.1.is_finished());
+        for (name, handle) in finished {
+            if handle.join().is_err() {
+                error!("{} thread panicked", name);
+                success = false;
+            }
+        }
+        threads = running;
+        if threads.is_empty() {
+            return success;
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    for (name, _) in threads {
+        warn!("{} thread still running after {:?}", name, timeout);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_all_reports_stuck_and_panicked_threads() {
+        let token = ShutdownToken::new();
+        let waiting = token.clone();
+        let worker = thread::spawn(move || waiting.wait());
+        token.request();
+        assert!(join_all(vec![("worker", worker)], Duration::from_secs(5)));
+
+        let stuck = thread::spawn(|| thread::sleep(Duration::from_secs(1)));
+        assert!(!join_all(vec![("stuck", stuck)], Duration::from_millis(0)));
+
+        let panicked = thread::spawn(|| panic!("worker failed"));
+        assert!(!join_all(vec![("panicked", panicked)], Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,481 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::config::TtsConfig;
+use rori::event::{Event, EventBus};
+use rori::markup::{Chunk, Markup};
+use rori::shutdown::ShutdownToken;
+use rori::tts::{self, SpeechSynthesizer};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A sentence said again during this time is ignored
+pub const DUPLICATE_WINDOW_SECS: u64 = 10;
+/// How often a sentence being said is checked, to interrupt it
+const SPEAKING_POLL_MS: u64 = 50;
+/// What the user can say or type to stop RORI talking. A lone "stop" is left
+/// to RORI, which can mean the music
+const STOP_COMMANDS: [&str; 4] = ["/stop", "stop talking", "shut up", "be quiet"];
+
+/**
+ * Importance of a sentence. Higher priorities are said first
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Replies of RORI and messages of the client
+    Chat,
+    /// Alarms, never dropped when the user speaks
+    Alarm,
+}
+
+struct Sentence {
+    /// Shown text, also used to find repetitions
+    text: String,
+    markup: Markup,
+    priority: Priority,
+}
+
+struct State {
+    queue: Vec<Sentence>,
+    speaking: Option<String>,
+    /// Sentences said recently, to ignore repetitions
+    recent: Vec<(String, Instant)>,
+    /// Incremented to cut the current sentence short
+    interruptions: u64,
+    /// Nothing is said while the user speaks
+    listening: bool,
+    synthesizer: Arc<dyn SpeechSynthesizer>,
+    /// Where rendered sentences are kept, empty for no cache
+    cache_dir: String,
+}
+
+/**
+ * Sentences waiting to be said by the speech worker. Clones share the same queue.
+ */
+#[derive(Clone)]
+pub struct SpeechQueue {
+    state: Arc<(Mutex<State>, Condvar)>,
+    bus: EventBus,
+}
+
+/**
+ * @param text entered or said by the user
+ * @return true if the user asks RORI to stop talking
+ */
+pub fn is_stop_command(text: &str) -> bool {
+    let text = text.trim().trim_end_matches(|c: char| c == '!' || c == '.').to_lowercase();
+    STOP_COMMANDS.contains(&&*text)
+}
+
+impl SpeechQueue {
+    /**
+     * @param tts how sentences are said
+     * @param cache_dir where rendered sentences are kept, empty for no cache
+     * @param bus where sentences are published
+     * @return an empty SpeechQueue
+     */
+    pub fn new(tts: &TtsConfig, cache_dir: &str, bus: EventBus) -> SpeechQueue {
+        SpeechQueue {
+            state: Arc::new((Mutex::new(State {
+                queue: Vec::new(),
+                speaking: None,
+                recent: Vec::new(),
+                interruptions: 0,
+                listening: false,
+                synthesizer: Arc::from(tts::synthesizer(tts, cache_dir)),
+                cache_dir: String::from(cache_dir),
+            }), Condvar::new())),
+            bus,
+        }
+    }
+
+    /**
+     * Change how sentences are said, from the next sentence
+     * @param self
+     * @param tts engine to use
+     */
+    pub fn set_tts(&self, tts: &TtsConfig) {
+        let mut state = self.state.0.lock().unwrap();
+        state.synthesizer = Arc::from(tts::synthesizer(tts, &state.cache_dir));
+    }
+
+    /**
+     * Show a sentence and queue it. A chat sentence already queued or just said
+     * is not said again, alarms always are
+     * @param self
+     * @param text to say
+     * @param priority of the sentence
+     * @return false if the sentence is a repetition, only shown
+     */
+    pub fn push(&self, text: &str, priority: Priority) -> bool {
+        self.queue(text, Markup::plain(text), priority)
+    }
+
+    /**
+     * Show the text of a marked up sentence and queue it, unless it is a repeated chat sentence
+     * @param self
+     * @param markup to say
+     * @param priority of the sentence
+     * @return false if the sentence is a repetition, only shown
+     */
+    pub fn push_markup(&self, markup: Markup, priority: Priority) -> bool {
+        self.queue(&markup.plain_text(), markup, priority)
+    }
+
+    /**
+     * @param self
+     * @param text shown
+     * @param markup said
+     * @param priority of the sentence
+     * @return false if the sentence is a repetition, only shown
+     */
+    fn queue(&self, text: &str, markup: Markup, priority: Priority) -> bool {
+        self.bus.publish(Event::RoriSpeech(String::from(text)));
+        let &(ref state, ref cvar) = &*self.state;
+        let mut state = state.lock().unwrap();
+        let window = Duration::from_secs(DUPLICATE_WINDOW_SECS);
+        state.recent.retain(|&(_, said)| said.elapsed() < window);
+        let repeated = state.speaking.as_ref().map_or(false, |s| s == text)
+            || state.queue.iter().any(|s| s.text == text)
+            || state.recent.iter().any(|&(ref s, _)| s == text);
+        if priority == Priority::Chat && repeated {
+            debug!("Repeated sentence ignored: {}", text);
+            return false;
+        }
+        state.queue.push(Sentence {
+            text: String::from(text),
+            markup,
+            priority,
+        });
+        cvar.notify_all();
+        true
+    }
+
+    /**
+     * Stop talking: cut the current sentence short and forget queued ones
+     * @param self
+     * @return number of sentences not said
+     */
+    pub fn stop(&self) -> usize {
+        let &(ref state, ref cvar) = &*self.state;
+        let mut state = state.lock().unwrap();
+        let dropped = state.queue.len();
+        state.queue.clear();
+        state.interruptions += 1;
+        cvar.notify_all();
+        info!("Stop talking, {} sentence(s) dropped", dropped);
+        dropped
+    }
+
+    /**
+     * Follow if the user speaks. When the user begins to speak, RORI stops
+     * talking and forgets queued chat, but alarms are said once the user stops.
+     * @param self
+     * @param listening if the user speaks
+     */
+    pub fn set_listening(&self, listening: bool) {
+        let &(ref state, ref cvar) = &*self.state;
+        let mut state = state.lock().unwrap();
+        if listening && !state.listening {
+            state.queue.retain(|s| s.priority == Priority::Alarm);
+            state.interruptions += 1;
+        }
+        state.listening = listening;
+        cvar.notify_all();
+    }
+
+    /**
+     * Say queued sentences until a shutdown is requested, then say remaining ones
+     * @param self
+     * @param shutdown when to quit
+     * @param interval maximum time to wait for a sentence, between two checks of the shutdown
+     * @return the thread of the worker
+     */
+    pub fn start(&self, shutdown: ShutdownToken, interval: Duration) -> JoinHandle<()> {
+        let queue = self.clone();
+        let events = self.bus.subscribe();
+        thread::spawn(move || {
+            loop {
+                queue.handle_events(&events);
+                if let Some(next) = queue.next(interval) {
+                    queue.say(next, &events);
+                }
+                if shutdown.is_requested() {
+                    break;
+                }
+            }
+            // Say sentences queued before the shutdown
+            queue.set_listening(false);
+            while let Some(next) = queue.next(Duration::from_millis(0)) {
+                queue.say(next, &events);
+            }
+        })
+    }
+
+    /**
+     * Follow the user speaking and stop requests
+     * @param self
+     * @param events from the EventBus
+     */
+    fn handle_events(&self, events: &Receiver<Event>) {
+        while let Ok(event) = events.try_recv() {
+            match event {
+                Event::ListeningChanged(listening) => self.set_listening(listening),
+                Event::StopSpeaking => { self.stop(); },
+                Event::UserUtterance(ref text, _) if is_stop_command(text) => { self.stop(); },
+                _ => {}
+            }
+        }
+    }
+
+    /**
+     * Wait for the next sentence to say
+     * @param self
+     * @param timeout maximum time to wait
+     * @return the sentence, the engine to use and the current number of interruptions
+     */
+    fn next(&self, timeout: Duration) -> Option<(Sentence, Arc<dyn SpeechSynthesizer>, u64)> {
+        let &(ref state, ref cvar) = &*self.state;
+        let mut state = state.lock().unwrap();
+        if state.queue.is_empty() || state.listening {
+            state = cvar.wait_timeout(state, timeout).unwrap().0;
+        }
+        if state.listening {
+            return None;
+        }
+        let highest = state.queue.iter().map(|s| s.priority).max()?;
+        let idx = state.queue.iter().position(|s| s.priority == highest)?;
+        let sentence = state.queue.remove(idx);
+        state.speaking = Some(sentence.text.clone());
+        Some((sentence, state.synthesizer.clone(), state.interruptions))
+    }
+
+    /**
+     * Say a sentence, without holding the queue, until it ends or is interrupted.
+     * Engines without SSML say marked up sentences in chunks, separated by pauses.
+     * @param self
+     * @param next sentence, engine and number of interruptions when it was taken
+     * @param events from the EventBus, followed while speaking
+     */
+    fn say(&self, next: (Sentence, Arc<dyn SpeechSynthesizer>, u64), events: &Receiver<Event>) {
+        let (sentence, synthesizer, interruptions) = next;
+        let chunks = if sentence.markup.is_plain() {
+            vec![Chunk::Say(sentence.text.clone())]
+        } else if synthesizer.supports_ssml() {
+            vec![Chunk::Say(sentence.markup.to_ssml())]
+        } else {
+            sentence.markup.chunks()
+        };
+        for chunk in chunks {
+            let said = match chunk {
+                Chunk::Say(text) => self.say_chunk(&text, &*synthesizer, interruptions, events),
+                Chunk::Pause(duration) => self.pause(duration, interruptions, events),
+            };
+            if !said {
+                break;
+            }
+        }
+        let mut state = self.state.0.lock().unwrap();
+        state.speaking = None;
+        state.recent.push((sentence.text, Instant::now()));
+    }
+
+    /**
+     * Say a text until it ends or is interrupted
+     * @param self
+     * @param text to say
+     * @param synthesizer engine to use
+     * @param interruptions when the sentence was taken
+     * @param events from the EventBus, followed while speaking
+     * @return false if interrupted
+     */
+    fn say_chunk(&self, text: &str, synthesizer: &dyn SpeechSynthesizer, interruptions: u64, events: &Receiver<Event>) -> bool {
+        let mut speech = match synthesizer.start(text) {
+            Ok(speech) => speech,
+            Err(e) => {
+                error!("{} can't say \"{}\": {}", synthesizer.name(), text, e);
+                return true;
+            }
+        };
+        loop {
+            match speech.is_done() {
+                Ok(true) => return true,
+                Ok(false) => {},
+                Err(e) => {
+                    error!("{} failed to say \"{}\": {}", synthesizer.name(), text, e);
+                    return true;
+                }
+            }
+            self.handle_events(events);
+            let &(ref state, ref cvar) = &*self.state;
+            let state = state.lock().unwrap();
+            if state.interruptions != interruptions {
+                speech.stop();
+                return false;
+            }
+            let _ = cvar.wait_timeout(state, Duration::from_millis(SPEAKING_POLL_MS)).unwrap();
+        }
+    }
+
+    /**
+     * Stay silent, unless interrupted
+     * @param self
+     * @param duration of the pause
+     * @param interruptions when the sentence was taken
+     * @param events from the EventBus, followed while waiting
+     * @return false if interrupted
+     */
+    fn pause(&self, duration: Duration, interruptions: u64, events: &Receiver<Event>) -> bool {
+        let end = Instant::now() + duration;
+        loop {
+            self.handle_events(events);
+            let &(ref state, ref cvar) = &*self.state;
+            let state = state.lock().unwrap();
+            if state.interruptions != interruptions {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= end {
+                return true;
+            }
+            let wait = (end - now).min(Duration::from_millis(SPEAKING_POLL_MS));
+            let _ = cvar.wait_timeout(state, wait).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rori::config::Config;
+    use rori::tts::Speech;
+    use std::path::Path;
+
+    /**
+     * Remembers what it says, at once
+     */
+    struct FakeSynthesizer {
+        said: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl SpeechSynthesizer for FakeSynthesizer {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn start(&self, text: &str) -> Result<Speech, String> {
+            self.said.lock().unwrap().push(String::from(text));
+            Ok(Speech::done())
+        }
+
+        fn render(&self, _: &str, _: &Path) -> Result<Speech, String> {
+            Err(String::from("no rendering"))
+        }
+    }
+
+    fn queue() -> (SpeechQueue, Arc<Mutex<Vec<String>>>) {
+        let queue = SpeechQueue::new(&Config::default().tts, "", EventBus::new());
+        let said = Arc::new(Mutex::new(Vec::new()));
+        queue.state.0.lock().unwrap().synthesizer = Arc::new(FakeSynthesizer { said: said.clone() });
+        (queue, said)
+    }
+
+    fn take(queue: &SpeechQueue) -> Option<String> {
+        queue.next(Duration::from_millis(0)).map(|(sentence, _, _)| sentence.text)
+    }
+
+    fn wait_said(said: &Arc<Mutex<Vec<String>>>, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while said.lock().unwrap().len() < count {
+            assert!(Instant::now() < deadline, "nothing said");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn alarms_said_first() {
+        let (queue, _) = queue();
+        queue.push("first", Priority::Chat);
+        queue.push("wake up", Priority::Alarm);
+        queue.push("second", Priority::Chat);
+        assert_eq!(take(&queue), Some(String::from("wake up")));
+        assert_eq!(take(&queue), Some(String::from("first")));
+        assert_eq!(take(&queue), Some(String::from("second")));
+        assert_eq!(take(&queue), None);
+    }
+
+    #[test]
+    fn only_chat_repetitions_ignored() {
+        let (queue, _) = queue();
+        assert!(queue.push("hello", Priority::Chat));
+        assert!(!queue.push("hello", Priority::Chat));
+        assert!(queue.push("wake up", Priority::Alarm));
+        assert!(queue.push("wake up", Priority::Alarm));
+        assert_eq!(queue.state.0.lock().unwrap().queue.len(), 3);
+    }
+
+    #[test]
+    fn stop_cuts_the_sentence_short() {
+        let (queue, said) = queue();
+        let shutdown = ShutdownToken::new();
+        let worker = queue.start(shutdown.clone(), Duration::from_millis(10));
+        let markup = Markup::parse_ssml("<speak>one<break time=\"10s\"/>two</speak>").unwrap();
+        queue.push_markup(markup, Priority::Chat);
+        wait_said(&said, 1);
+        queue.push("next", Priority::Chat);
+        assert_eq!(queue.stop(), 1);
+        shutdown.request();
+        worker.join().unwrap();
+        assert_eq!(*said.lock().unwrap(), vec![String::from("one")]);
+    }
+
+    #[test]
+    fn user_speaking_drops_chat_but_not_alarms() {
+        let (queue, _) = queue();
+        queue.push("hello", Priority::Chat);
+        queue.push("wake up", Priority::Alarm);
+        queue.set_listening(true);
+        assert_eq!(take(&queue), None);
+        queue.set_listening(false);
+        assert_eq!(take(&queue), Some(String::from("wake up")));
+        assert_eq!(take(&queue), None);
+    }
+
+    #[test]
+    fn queued_sentences_said_at_shutdown() {
+        let (queue, said) = queue();
+        queue.set_listening(true);
+        queue.push("hello", Priority::Chat);
+        queue.push("wake up", Priority::Alarm);
+        let shutdown = ShutdownToken::new();
+        shutdown.request();
+        queue.start(shutdown, Duration::from_millis(10)).join().unwrap();
+        assert_eq!(*said.lock().unwrap(), vec![String::from("wake up"), String::from("hello")]);
+    }
+}
@@ -0,0 +1,501 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::config::SttConfig;
+use rori::event::{Event, EventBus, Source};
+use rori::shutdown::ShutdownToken;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::io::prelude::*;
+use std::path::Path;
+use std::process::{self, Child, ChildStdout, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use time;
+
+/// Sample rate of the microphone, expected by whisper-cli
+pub const SAMPLE_RATE: u32 = 16000;
+/// Length of a frame, the unit of the voice detection
+const FRAME_MS: u32 = 30;
+/// Noise measured at startup, before listening
+const CALIBRATION_MS: u32 = 1000;
+/// The voice must be this number of times louder than the noise
+const THRESHOLD_FACTOR: f64 = 3.;
+/// Lowest threshold, for very quiet rooms
+const MIN_THRESHOLD: f64 = 300.;
+/// Audio kept before the voice is detected, not to cut the first word
+const PRE_ROLL_MS: u32 = 300;
+/// Silence ending an utterance
+const SILENCE_MS: u32 = 800;
+/// Shorter sounds are ignored
+const MIN_SPEECH_MS: u32 = 200;
+/// Longest utterance
+const MAX_UTTERANCE_SECS: u32 = 15;
+/// Delay before opening the microphone again after an error
+const RETRY_DELAY_SECS: u64 = 5;
+
+/**
+ * Text recognized in an audio file
+ */
+#[derive(Serialize, Debug)]
+pub struct Transcription {
+    pub text: String,
+}
+
+/**
+ * Something able to transcribe what the user said
+ */
+pub trait SpeechRecognizer: Send {
+    /**
+     * @param self
+     * @return name of the engine, for logs
+     */
+    fn name(&self) -> &str;
+
+    /**
+     * @param self
+     * @param wav 16 kHz mono WAV file
+     * @return the recognized text, empty if nothing was understood
+     */
+    fn transcribe(&self, wav: &Path) -> Result<String, String>;
+}
+
+/**
+ * Create the recognizer described by the configuration
+ * @param config stt section
+ * @return the recognizer, None if speech recognition is disabled
+ */
+pub fn recognizer(config: &SttConfig) -> Option<Box<dyn SpeechRecognizer>> {
+    match &*config.engine {
+        "whisper" => Some(Box::new(Whisper { config: config.clone() })),
+        _ => None,
+    }
+}
+
+/**
+ * whisper.cpp, through its command line (whisper-cli) run for each
+ * utterance on the CPU, with a local ggml model
+ */
+pub struct Whisper {
+    config: SttConfig,
+}
+
+impl SpeechRecognizer for Whisper {
+    fn name(&self) -> &str {
+        "whisper"
+    }
+
+    fn transcribe(&self, wav: &Path) -> Result<String, String> {
+        let output = Command::new(&self.config.command)
+            .arg("-m").arg(&self.config.model)
+            .arg("-f").arg(wav)
+            .arg("-l").arg(&self.config.language)
+            // Only the text: no timestamps, no progress
+            .arg("-nt").arg("-np")
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("can't start {}: {}", self.config.command, e))?;
+        if !output.status.success() {
+            return Err(format!("{} exited with {}: {}", self.config.command, output.status,
+                               String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        let text = String::from_utf8_lossy(&output.stdout);
+        // Non speech is annotated like [BLANK_AUDIO] or (music)
+        let words: Vec<&str> = text.lines()
+            .map(|line| line.trim())
+            .filter(|line| !(line.starts_with('[') && line.ends_with(']')))
+            .filter(|line| !(line.starts_with('(') && line.ends_with(')')))
+            .filter(|line| *line != "")
+            .collect();
+        Ok(words.join(" "))
+    }
+}
+
+/**
+ * Transcribe a WAV file received in memory
+ * @param recognizer to use
+ * @param audio content of a 16 kHz mono WAV file
+ * @return the recognized text, empty if nothing was understood
+ */
+pub fn transcribe_audio(recognizer: &dyn SpeechRecognizer, audio: &[u8]) -> Result<String, String> {
+    let path = env::temp_dir().join(format!("rori-transcribe-{}-{}.wav", process::id(), time::precise_time_ns()));
+    let text = fs::write(&path, audio).map_err(|e| format!("can't write {}: {}", path.display(), e))
+        .and_then(|_| recognizer.transcribe(&path));
+    let _ = fs::remove_file(&path);
+    text
+}
+
+/**
+ * Write 16 bits mono samples in a WAV file
+ * @param path of the file
+ * @param samples to write
+ * @param rate of the samples
+ */
+pub fn write_wav(path: &Path, samples: &[i16], rate: u32) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(b"RIFF")?;
+    file.write_all(&(36 + data_len).to_le_bytes())?;
+    file.write_all(b"WAVEfmt ")?;
+    file.write_all(&16u32.to_le_bytes())?;
+    // PCM, mono
+    file.write_all(&1u16.to_le_bytes())?;
+    file.write_all(&1u16.to_le_bytes())?;
+    file.write_all(&rate.to_le_bytes())?;
+    file.write_all(&(rate * 2).to_le_bytes())?;
+    file.write_all(&2u16.to_le_bytes())?;
+    file.write_all(&16u16.to_le_bytes())?;
+    file.write_all(b"data")?;
+    file.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        file.write_all(&sample.to_le_bytes())?;
+    }
+    file.flush()
+}
+
+/**
+ * @param frame samples
+ * @return the energy of the frame
+ */
+fn rms(frame: &[i16]) -> f64 {
+    if frame.is_empty() {
+        return 0.;
+    }
+    let sum: f64 = frame.iter().map(|&s| f64::from(s) * f64::from(s)).sum();
+    (sum / frame.len() as f64).sqrt()
+}
+
+/**
+ * Audio captured by an arecord process
+ */
+struct Microphone {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Microphone {
+    /**
+     * @param device given to arecord, empty for the default one
+     * @return the opened microphone
+     */
+    fn open(device: &str) -> Result<Microphone, String> {
+        let mut command = Command::new("arecord");
+        command.args(&["-q", "-t", "raw", "-f", "S16_LE", "-c", "1", "-r"]).arg(SAMPLE_RATE.to_string());
+        if device != "" {
+            command.arg("-D").arg(device);
+        }
+        let mut child = command.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("can't start arecord: {}", e))?;
+        match child.stdout.take() {
+            Some(stdout) => Ok(Microphone { child, stdout }),
+            None => Err(String::from("no audio from arecord")),
+        }
+    }
+
+    /**
+     * @param self
+     * @return the next frame of FRAME_MS
+     */
+    fn read_frame(&mut self) -> Result<Vec<i16>, String> {
+        let mut bytes = vec![0u8; (SAMPLE_RATE * FRAME_MS / 1000 * 2) as usize];
+        self.stdout.read_exact(&mut bytes).map_err(|e| format!("can't read the microphone: {}", e))?;
+        Ok(bytes.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect())
+    }
+}
+
+impl Drop for Microphone {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/**
+ * Listen to the microphone and give what the user says to RORI
+ */
+struct Listener {
+    recognizer: Box<dyn SpeechRecognizer>,
+    device: String,
+    bus: EventBus,
+    shutdown: ShutdownToken,
+    /// Energy above which the user speaks, measured at startup
+    threshold: Option<f64>,
+}
+
+impl Listener {
+    /**
+     * Listen until a shutdown is requested
+     * @param self
+     */
+    fn run(&mut self) {
+        while !self.shutdown.is_requested() {
+            if let Err(e) = self.listen_once() {
+                error!("Speech recognition: {}", e);
+                if self.shutdown.wait_timeout(Duration::from_secs(RETRY_DELAY_SECS)) {
+                    break;
+                }
+            }
+        }
+    }
+
+    /**
+     * Record an utterance, then transcribe it and send it.
+     * The microphone is closed while transcribing, not to keep old audio.
+     * @param self
+     */
+    fn listen_once(&mut self) -> Result<(), String> {
+        let samples = {
+            let mut microphone = Microphone::open(&self.device)?;
+            if self.threshold.is_none() {
+                self.threshold = Some(self.calibrate(&mut microphone)?);
+            }
+            match self.record(&mut microphone)? {
+                Some(samples) => samples,
+                None => return Ok(()),
+            }
+        };
+        let path = env::temp_dir().join(format!("rori-utterance-{}.wav", process::id()));
+        write_wav(&path, &samples, SAMPLE_RATE).map_err(|e| format!("can't write {}: {}", path.display(), e))?;
+        let text = self.recognizer.transcribe(&path);
+        let _ = fs::remove_file(&path);
+        let text = text.map_err(|e| format!("{} failed: {}", self.recognizer.name(), e))?;
+        if text == "" {
+            info!("Nothing recognized");
+            return Ok(());
+        }
+        info!("Recognized: {}", text);
+        self.bus.publish(Event::UserUtterance(text, Source::Voice));
+        Ok(())
+    }
+
+    /**
+     * Measure the noise of the room
+     * @param self
+     * @param microphone to read
+     * @return the threshold of the voice
+     */
+    fn calibrate(&self, microphone: &mut Microphone) -> Result<f64, String> {
+        info!("Measuring the noise, a moment of silence please...");
+        let mut noise = 0.;
+        let frames = CALIBRATION_MS / FRAME_MS;
+        for _ in 0..frames {
+            noise += rms(&microphone.read_frame()?);
+        }
+        let threshold = (noise / f64::from(frames) * THRESHOLD_FACTOR).max(MIN_THRESHOLD);
+        info!("Voice threshold: {}", threshold as u32);
+        Ok(threshold)
+    }
+
+    /**
+     * Wait for the user to speak, then record until a silence
+     * @param self
+     * @param microphone to read
+     * @return the samples of the utterance, None if stopped or too short
+     */
+    fn record(&self, microphone: &mut Microphone) -> Result<Option<Vec<i16>>, String> {
+        let threshold = self.threshold.unwrap_or(MIN_THRESHOLD);
+        let mut pre_roll: Vec<Vec<i16>> = Vec::new();
+        // Wait for the voice
+        let first = loop {
+            if self.shutdown.is_requested() {
+                return Ok(None);
+            }
+            let frame = microphone.read_frame()?;
+            if rms(&frame) > threshold {
+                break frame;
+            }
+            pre_roll.push(frame);
+            if pre_roll.len() > (PRE_ROLL_MS / FRAME_MS) as usize {
+                pre_roll.remove(0);
+            }
+        };
+        self.bus.publish(Event::ListeningChanged(true));
+        let mut samples: Vec<i16> = pre_roll.concat();
+        samples.extend(first);
+        let mut speech_ms = FRAME_MS;
+        let mut silence_ms = 0;
+        let mut result = Ok(());
+        while silence_ms < SILENCE_MS && samples.len() < (SAMPLE_RATE * MAX_UTTERANCE_SECS) as usize {
+            if self.shutdown.is_requested() {
+                break;
+            }
+            let frame = match microphone.read_frame() {
+                Ok(frame) => frame,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            if rms(&frame) > threshold {
+                speech_ms += FRAME_MS;
+                silence_ms = 0;
+            } else {
+                silence_ms += FRAME_MS;
+            }
+            samples.extend(frame);
+        }
+        self.bus.publish(Event::ListeningChanged(false));
+        result?;
+        if speech_ms < MIN_SPEECH_MS || self.shutdown.is_requested() {
+            return Ok(None);
+        }
+        Ok(Some(samples))
+    }
+}
+
+/**
+ * Listen to the microphone in a thread, if speech recognition is enabled
+ * @param config stt section
+ * @param bus where utterances and the listening state are published
+ * @param shutdown when to stop
+ * @return the thread, None if disabled
+ */
+pub fn start(config: &SttConfig, bus: EventBus, shutdown: ShutdownToken) -> Option<JoinHandle<()>> {
+    let recognizer = recognizer(config)?;
+    info!("Speech recognition with {}", recognizer.name());
+    let mut listener = Listener {
+        recognizer,
+        device: config.device.clone(),
+        bus,
+        shutdown,
+        threshold: None,
+    };
+    Some(thread::spawn(move || listener.run()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rori::config::Config;
+    use std::os::unix::fs::PermissionsExt;
+
+    /**
+     * Recognizer saying how many samples the WAV file holds
+     */
+    struct CountingRecognizer;
+
+    impl SpeechRecognizer for CountingRecognizer {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        fn transcribe(&self, wav: &Path) -> Result<String, String> {
+            let bytes = fs::read(wav).map_err(|e| e.to_string())?;
+            if bytes.len() < 44 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+                return Err(String::from("not a WAV file"));
+            }
+            Ok(format!("{} samples", (bytes.len() - 44) / 2))
+        }
+    }
+
+    /**
+     * @param name of the test
+     * @return an empty directory for the test
+     */
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("rori-stt-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn write_wav_header_and_samples() {
+        let dir = test_dir("wav");
+        let path = dir.join("samples.wav");
+        write_wav(&path, &[0, 1, -1, i16::max_value()], SAMPLE_RATE).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + 8);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        // PCM, mono, 16 bits
+        assert_eq!(u16_at(20), 1);
+        assert_eq!(u16_at(22), 1);
+        assert_eq!(u32_at(24), SAMPLE_RATE);
+        assert_eq!(u32_at(28), SAMPLE_RATE * 2);
+        assert_eq!(u16_at(34), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(40), 8);
+        assert_eq!(&bytes[44..], &[0, 0, 1, 0, 0xff, 0xff, 0xff, 0x7f]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rms_of_frames() {
+        assert_eq!(rms(&[]), 0.);
+        assert_eq!(rms(&[0; 480]), 0.);
+        assert_eq!(rms(&[1000, -1000, 1000, -1000]), 1000.);
+        assert_eq!(rms(&[3, 4, 3, 4]), 12.5f64.sqrt());
+        assert!(rms(&[i16::min_value(); 4]) > 32767.);
+    }
+
+    #[test]
+    fn transcribe_a_wav_file() {
+        let dir = test_dir("transcribe");
+        let path = dir.join("question.wav");
+        write_wav(&path, &[100; 1600], SAMPLE_RATE).unwrap();
+        let audio = fs::read(&path).unwrap();
+        assert_eq!(transcribe_audio(&CountingRecognizer, &audio), Ok(String::from("1600 samples")));
+        assert!(transcribe_audio(&CountingRecognizer, b"not audio").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn whisper_output_without_annotations() {
+        let dir = test_dir("whisper");
+        let command = dir.join("whisper-cli");
+        fs::write(&command, "#!/bin/sh\n\
+                             [ -f \"$4\" ] || exit 1\n\
+                             echo '[BLANK_AUDIO]'\n\
+                             echo ' What time is it?'\n\
+                             echo '(music)'\n\
+                             echo ' Thanks.'\n").unwrap();
+        fs::set_permissions(&command, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut config = Config::default().stt;
+        config.engine = String::from("whisper");
+        config.command = command.to_string_lossy().into_owned();
+        config.model = String::from("model.bin");
+        let recognizer = recognizer(&config).unwrap();
+
+        let wav = dir.join("question.wav");
+        write_wav(&wav, &[0; 160], SAMPLE_RATE).unwrap();
+        assert_eq!(recognizer.transcribe(&wav), Ok(String::from("What time is it? Thanks.")));
+        assert!(recognizer.transcribe(&dir.join("missing.wav")).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn disabled_recognizer() {
+        assert!(recognizer(&Config::default().stt).is_none());
+    }
+}
@@ -0,0 +1,153 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use serde_json;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+
+/**
+ * Answer given to a trust request
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustDecision {
+    Accepted,
+    Discarded,
+}
+
+/**
+ * A trust request waiting for the user
+ */
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TrustRequest {
+    /// Profile of the account receiving the request, empty for the top level identity
+    pub profile: String,
+    /// Ring id of the peer
+    pub from: String,
+}
+
+/**
+ * Trust requests management asked by the user
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrustCommand {
+    List,
+    Accept(String),
+    Discard(String),
+}
+
+impl TrustCommand {
+    /**
+     * Parse "/trust", "/trust accept RING_ID" or "/trust discard RING_ID"
+     * @param text entered by the user
+     * @return None if not a trust command, else the command or an error for the user
+     */
+    pub fn parse(text: &str) -> Option<Result<TrustCommand, String>> {
+        let mut words = text.split_whitespace();
+        if words.next() != Some("/trust") {
+            return None;
+        }
+        let args: Vec<&str> = words.collect();
+        Some(match args.as_slice() {
+            [] | ["list"] => Ok(TrustCommand::List),
+            ["accept", from] => Ok(TrustCommand::Accept(String::from(*from))),
+            ["discard", from] => Ok(TrustCommand::Discard(String::from(*from))),
+            _ => Err(String::from("Usage: /trust [list | accept RING_ID | discard RING_ID]"))
+        })
+    }
+}
+
+/**
+ * Decisions taken by the user, to answer again the same way
+ * when a known peer sends another trust request
+ */
+pub struct TrustStore {
+    decisions: HashMap<String, TrustDecision>,
+    path: String,
+}
+
+impl TrustStore {
+    /**
+     * Load decisions from a file
+     * @param path of the file, empty to keep decisions in memory
+     * @return the TrustStore
+     */
+    pub fn load(path: &str) -> TrustStore {
+        let decisions = match File::open(path) {
+            Ok(mut file) => {
+                let mut content = String::new();
+                let _ = file.read_to_string(&mut content);
+                serde_json::from_str(&content).unwrap_or_else(|e| {
+                    error!("Can't read trust decisions from {}: {}", path, e);
+                    HashMap::new()
+                })
+            },
+            Err(_) => HashMap::new()
+        };
+        TrustStore {
+            decisions,
+            path: String::from(path),
+        }
+    }
+
+    /**
+     * Write decisions on the disk
+     * @param self
+     */
+    pub fn save(&self) {
+        if self.path == "" {
+            return;
+        }
+        let content = serde_json::to_string_pretty(&self.decisions).unwrap_or(String::from("{}"));
+        let tmp = format!("{}.tmp", self.path);
+        let written = File::create(&tmp).and_then(|mut file| file.write_all(content.as_bytes()));
+        if written.and_then(|_| fs::rename(&tmp, &self.path)).is_err() {
+            error!("Can't save trust decisions to {}", self.path);
+        }
+    }
+
+    /**
+     * @param self
+     * @param from ring id of the peer
+     * @return the previous decision for this peer
+     */
+    pub fn get(&self, from: &str) -> Option<TrustDecision> {
+        self.decisions.get(from).cloned()
+    }
+
+    /**
+     * Remember a decision, and save it
+     * @param self
+     * @param from ring id of the peer
+     * @param decision taken
+     */
+    pub fn remember(&mut self, from: &str, decision: TrustDecision) {
+        self.decisions.insert(String::from(from), decision);
+        self.save();
+    }
+}
@@ -0,0 +1,556 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::config::TtsConfig;
+use rori::tts_cache::{CachedSynthesizer, SpeechCache};
+use std::io::prelude::*;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+/// Normal speed of espeak-ng, in words per minute
+const ESPEAK_WPM: f32 = 175.;
+/// Normal pitch of mimic, in Hz
+const MIMIC_F0: f32 = 100.;
+
+/**
+ * Something able to say a sentence
+ */
+pub trait SpeechSynthesizer: Send + Sync {
+    /**
+     * @param self
+     * @return name of the engine, for logs
+     */
+    fn name(&self) -> &str;
+
+    /**
+     * Begin to say a sentence
+     * @param self
+     * @param text to say
+     * @return the sentence being said, or an error if the engine can't start
+     */
+    fn start(&self, text: &str) -> Result<Speech, String>;
+
+    /**
+     * Begin to write a sentence in a WAV file, without saying it
+     * @param self
+     * @param text to render
+     * @param path of the WAV file
+     * @return the rendering, or an error if the engine can't start
+     */
+    fn render(&self, text: &str, path: &Path) -> Result<Speech, String>;
+
+    /**
+     * @param self
+     * @return true if start and render understand SSML texts, beginning with <speak>
+     */
+    fn supports_ssml(&self) -> bool {
+        false
+    }
+}
+
+/// What to do once the processes of a Speech are done
+type NextStep = Box<dyn FnOnce() -> Result<Speech, String> + Send>;
+
+/**
+ * A sentence being said, by one or several processes
+ */
+pub struct Speech {
+    children: Vec<Child>,
+    then: Option<NextStep>,
+}
+
+impl Speech {
+    /**
+     * @return a Speech already said, for engines without process
+     */
+    pub fn done() -> Speech {
+        Speech {
+            children: Vec::new(),
+            then: None,
+        }
+    }
+
+    /**
+     * Continue with another step once the processes succeed
+     * @param self
+     * @param step to run, like playing a rendered file
+     * @return the Speech of both steps
+     */
+    pub fn then<F>(mut self, step: F) -> Speech where F: FnOnce() -> Result<Speech, String> + Send + 'static {
+        self.then = Some(Box::new(step));
+        self
+    }
+
+    /**
+     * Start the next step, if any
+     * @param self
+     * @return true if a step was started
+     */
+    fn next_step(&mut self) -> Result<bool, String> {
+        match self.then.take() {
+            Some(step) => {
+                *self = step()?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /**
+     * @param self
+     * @return true if every process exited, an error if one failed
+     */
+    pub fn is_done(&mut self) -> Result<bool, String> {
+        let mut done = true;
+        for child in &mut self.children {
+            match child.try_wait() {
+                Ok(Some(status)) if !status.success() => return Err(format!("exited with {}", status)),
+                Ok(Some(_)) => {},
+                Ok(None) => done = false,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        if done && self.next_step()? {
+            return self.is_done();
+        }
+        Ok(done)
+    }
+
+    /**
+     * Cut the sentence short
+     * @param self
+     */
+    pub fn stop(&mut self) {
+        self.then = None;
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    /**
+     * Wait until the sentence is said
+     * @param self
+     * @return an error if a process failed
+     */
+    pub fn wait(mut self) -> Result<(), String> {
+        loop {
+            for child in &mut self.children {
+                let status = child.wait().map_err(|e| e.to_string())?;
+                if !status.success() {
+                    return Err(format!("exited with {}", status));
+                }
+            }
+            if !self.next_step()? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/**
+ * Create the synthesizer described by the configuration
+ * @param config tts section
+ * @param cache_dir where rendered sentences are kept, empty to say them directly
+ * @return the synthesizer, silent if the engine is unknown
+ */
+pub fn synthesizer(config: &TtsConfig, cache_dir: &str) -> Box<dyn SpeechSynthesizer> {
+    let engine = engine(config);
+    if cache_dir == "" || !config.cache || config.engine == "none" {
+        return engine;
+    }
+    Box::new(CachedSynthesizer::new(engine, SpeechCache::new(Path::new(cache_dir), config)))
+}
+
+/**
+ * @param config tts section
+ * @return the engine described by the configuration, silent if unknown
+ */
+fn engine(config: &TtsConfig) -> Box<dyn SpeechSynthesizer> {
+    match &*config.engine {
+        "mimic" => Box::new(Mimic { config: config.clone() }),
+        "espeak-ng" => Box::new(EspeakNg { config: config.clone() }),
+        "festival" => Box::new(Festival { config: config.clone() }),
+        "piper" => Box::new(Piper { config: config.clone() }),
+        _ => Box::new(Silent {}),
+    }
+}
+
+/**
+ * Start a command
+ * @param command to run
+ * @param input written to its stdin, if any
+ * @return the running command, or an error if it can't start
+ */
+fn spawn(command: &mut Command, input: Option<&str>) -> Result<Child, String> {
+    command.stdout(Stdio::null());
+    if input.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    let mut child = command.spawn().map_err(|e| format!("can't start: {}", e))?;
+    if let Some(input) = input {
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Err(e) = stdin.write_all(input.as_bytes()) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("can't write: {}", e));
+            }
+        }
+    }
+    Ok(child)
+}
+
+/**
+ * Start a command saying a sentence alone
+ * @param command to run
+ * @param input written to its stdin, if any
+ * @return the sentence being said
+ */
+fn run(command: &mut Command, input: Option<&str>) -> Result<Speech, String> {
+    Ok(Speech {
+        children: vec![spawn(command, input)?],
+        then: None,
+    })
+}
+
+/**
+ * Play a WAV file
+ * @param path of the file
+ * @return the file being played
+ */
+pub fn play(path: &Path) -> Result<Speech, String> {
+    run(Command::new("aplay").arg("-q").arg(path), None)
+}
+
+/**
+ * Mimic (Flite). Honours voice, rate and pitch.
+ */
+pub struct Mimic {
+    config: TtsConfig,
+}
+
+impl SpeechSynthesizer for Mimic {
+    fn name(&self) -> &str {
+        "mimic"
+    }
+
+    fn start(&self, text: &str) -> Result<Speech, String> {
+        run(&mut self.command(text), None)
+    }
+
+    fn render(&self, text: &str, path: &Path) -> Result<Speech, String> {
+        run(self.command(text).arg("-o").arg(path), None)
+    }
+}
+
+impl Mimic {
+    fn command(&self, text: &str) -> Command {
+        let voice = if self.config.voice == "" { "slt" } else { &*self.config.voice };
+        let mut command = Command::new("mimic");
+        command.arg("-t").arg(text)
+            .arg("-voice").arg(voice)
+            .arg("--setf").arg(format!("duration_stretch={}", 1. / self.config.rate))
+            .arg("--setf").arg(format!("int_f0_target_mean={}", MIMIC_F0 * self.config.pitch));
+        command
+    }
+}
+
+/**
+ * espeak-ng. Honours voice, rate, pitch, volume and SSML.
+ */
+pub struct EspeakNg {
+    config: TtsConfig,
+}
+
+impl SpeechSynthesizer for EspeakNg {
+    fn name(&self) -> &str {
+        "espeak-ng"
+    }
+
+    fn start(&self, text: &str) -> Result<Speech, String> {
+        run(self.command(text).arg("--").arg(text), None)
+    }
+
+    fn render(&self, text: &str, path: &Path) -> Result<Speech, String> {
+        run(self.command(text).arg("-w").arg(path).arg("--").arg(text), None)
+    }
+
+    fn supports_ssml(&self) -> bool {
+        true
+    }
+}
+
+impl EspeakNg {
+    fn command(&self, text: &str) -> Command {
+        let mut command = Command::new("espeak-ng");
+        if text.starts_with("<speak") {
+            command.arg("-m");
+        }
+        if self.config.voice != "" {
+            command.arg("-v").arg(&self.config.voice);
+        }
+        command.arg("-s").arg(format!("{}", (ESPEAK_WPM * self.config.rate) as u32))
+            .arg("-p").arg(format!("{}", ((50. * self.config.pitch) as u32).min(99)))
+            .arg("-a").arg(format!("{}", ((100. * self.config.volume) as u32).min(200)));
+        command
+    }
+}
+
+/**
+ * Festival, driven by its scheme interpreter, and text2wave to render.
+ * Honours voice and rate.
+ */
+pub struct Festival {
+    config: TtsConfig,
+}
+
+impl SpeechSynthesizer for Festival {
+    fn name(&self) -> &str {
+        "festival"
+    }
+
+    fn start(&self, text: &str) -> Result<Speech, String> {
+        run(Command::new("festival").arg("--pipe"), Some(&*self.script(text)))
+    }
+
+    fn render(&self, text: &str, path: &Path) -> Result<Speech, String> {
+        run(&mut self.render_command(path), Some(text))
+    }
+}
+
+impl Festival {
+    fn script(&self, text: &str) -> String {
+        let mut script = String::new();
+        if self.config.voice != "" {
+            script += &*format!("(voice_{})\n", self.config.voice);
+        }
+        script += &*format!("(Parameter.set 'Duration_Stretch {})\n", 1. / self.config.rate);
+        script += &*format!("(SayText \"{}\")\n", text.replace('\\', "\\\\").replace('"', "\\\""));
+        script
+    }
+
+    fn render_command(&self, path: &Path) -> Command {
+        let mut command = Command::new("text2wave");
+        command.arg("-o").arg(path);
+        if self.config.voice != "" {
+            command.arg("-eval").arg(format!("(voice_{})", self.config.voice));
+        }
+        command.arg("-eval").arg(format!("(Parameter.set 'Duration_Stretch {})", 1. / self.config.rate));
+        command
+    }
+}
+
+/**
+ * Piper, played with aplay. voice is the path of the .onnx model.
+ * Honours voice and rate.
+ */
+pub struct Piper {
+    config: TtsConfig,
+}
+
+impl SpeechSynthesizer for Piper {
+    fn name(&self) -> &str {
+        "piper"
+    }
+
+    fn start(&self, text: &str) -> Result<Speech, String> {
+        if self.config.voice == "" {
+            return Err(String::from("tts.voice must be the path of a piper model"));
+        }
+        let mut piper = self.command()
+            .arg("--output_raw")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("can't start: {}", e))?;
+        let audio = piper.stdout.take();
+        let written = match piper.stdin.take() {
+            Some(mut stdin) => stdin.write_all(text.as_bytes()).map_err(|e| format!("can't write: {}", e)),
+            None => Err(String::from("no input")),
+        };
+        let player = match (written, audio) {
+            (Ok(()), Some(audio)) => spawn(Command::new("aplay")
+                .args(&["-q", "-r", "22050", "-f", "S16_LE", "-t", "raw", "-"])
+                .stdin(audio), None),
+            (Err(e), _) => Err(e),
+            (_, None) => Err(String::from("no audio output")),
+        };
+        match player {
+            Ok(player) => Ok(Speech {
+                children: vec![piper, player],
+                then: None,
+            }),
+            Err(e) => {
+                let _ = piper.kill();
+                let _ = piper.wait();
+                Err(e)
+            }
+        }
+    }
+
+    fn render(&self, text: &str, path: &Path) -> Result<Speech, String> {
+        if self.config.voice == "" {
+            return Err(String::from("tts.voice must be the path of a piper model"));
+        }
+        run(self.command()
+            .arg("--output_file").arg(path)
+            .stderr(Stdio::null()), Some(text))
+    }
+}
+
+impl Piper {
+    fn command(&self) -> Command {
+        let mut command = Command::new("piper");
+        command.arg("--model").arg(&self.config.voice)
+            .arg("--length_scale").arg(format!("{}", 1. / self.config.rate));
+        command
+    }
+}
+
+/**
+ * Only logs sentences, they are still shown
+ */
+pub struct Silent {}
+
+impl SpeechSynthesizer for Silent {
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn start(&self, text: &str) -> Result<Speech, String> {
+        info!("(silent) {}", text);
+        Ok(Speech::done())
+    }
+
+    fn render(&self, _: &str, _: &Path) -> Result<Speech, String> {
+        Err(String::from("no audio without engine"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn config(engine: &str, voice: &str) -> TtsConfig {
+        TtsConfig {
+            engine: String::from(engine),
+            voice: String::from(voice),
+            rate: 2.,
+            pitch: 1.5,
+            volume: 0.5,
+            cache: false,
+        }
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn stop_kills_processes_and_skips_the_next_step() {
+        let next = Arc::new(AtomicBool::new(false));
+        let ran = next.clone();
+        let mut speech = run(Command::new("sleep").arg("10"), None).unwrap().then(move || {
+            ran.store(true, Ordering::SeqCst);
+            Ok(Speech::done())
+        });
+        assert_eq!(speech.is_done(), Ok(false));
+        speech.stop();
+        for child in &mut speech.children {
+            assert!(child.try_wait().unwrap().is_some());
+        }
+        assert!(speech.then.is_none());
+        assert!(!next.load(Ordering::SeqCst));
+
+        let ran = next.clone();
+        run(&mut Command::new("true"), None).unwrap().then(move || {
+            ran.store(true, Ordering::SeqCst);
+            Ok(Speech::done())
+        }).wait().unwrap();
+        assert!(next.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn mimic_command() {
+        let mimic = Mimic { config: config("mimic", "") };
+        let command = mimic.command("hello");
+        assert_eq!(command.get_program(), "mimic");
+        assert_eq!(args(&command), vec!["-t", "hello", "-voice", "slt",
+                                        "--setf", "duration_stretch=0.5",
+                                        "--setf", "int_f0_target_mean=150"]);
+    }
+
+    #[test]
+    fn espeak_ng_command() {
+        let espeak = EspeakNg { config: config("espeak-ng", "fr") };
+        let command = espeak.command("bonjour");
+        assert_eq!(command.get_program(), "espeak-ng");
+        assert_eq!(args(&command), vec!["-v", "fr", "-s", "350", "-p", "75", "-a", "50"]);
+        // SSML is announced, loud and high values are bounded
+        let espeak = EspeakNg { config: TtsConfig { pitch: 3., volume: 4., ..config("espeak-ng", "") } };
+        assert_eq!(args(&espeak.command("<speak>hi</speak>")), vec!["-m", "-s", "350", "-p", "99", "-a", "200"]);
+    }
+
+    #[test]
+    fn festival_commands() {
+        let festival = Festival { config: config("festival", "kal_diphone") };
+        assert_eq!(festival.script("say \"hi\""),
+                   "(voice_kal_diphone)\n(Parameter.set 'Duration_Stretch 0.5)\n(SayText \"say \\\"hi\\\"\")\n");
+        let command = festival.render_command(Path::new("/tmp/hi.wav"));
+        assert_eq!(command.get_program(), "text2wave");
+        assert_eq!(args(&command), vec!["-o", "/tmp/hi.wav", "-eval", "(voice_kal_diphone)",
+                                        "-eval", "(Parameter.set 'Duration_Stretch 0.5)"]);
+        let festival = Festival { config: config("festival", "") };
+        assert_eq!(festival.script("hi"), "(Parameter.set 'Duration_Stretch 0.5)\n(SayText \"hi\")\n");
+    }
+
+    #[test]
+    fn piper_command() {
+        let piper = Piper { config: config("piper", "/models/en.onnx") };
+        let command = piper.command();
+        assert_eq!(command.get_program(), "piper");
+        assert_eq!(args(&command), vec!["--model", "/models/en.onnx", "--length_scale", "0.5"]);
+        // The model is required
+        let piper = Piper { config: config("piper", "") };
+        assert!(piper.start("hi").is_err());
+        assert!(piper.render("hi", Path::new("/tmp/hi.wav")).is_err());
+    }
+
+    #[test]
+    fn engine_chosen_by_the_configuration() {
+        for name in &["mimic", "espeak-ng", "festival", "piper"] {
+            assert_eq!(engine(&config(name, "")).name(), *name);
+        }
+        let silent = engine(&config("unknown", ""));
+        assert_eq!(silent.name(), "none");
+        assert!(silent.start("hi").is_ok());
+        assert!(silent.render("hi", Path::new("/tmp/hi.wav")).is_err());
+    }
+}
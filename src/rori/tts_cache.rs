@@ -0,0 +1,372 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use rori::config::TtsConfig;
+use rori::tts::{self, Speech, SpeechSynthesizer};
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// Directory of the cache, in the data directory
+pub const CACHE_DIR: &str = "speech";
+/// Oldest files are removed beyond this number
+pub const MAX_CACHED_FILES: usize = 500;
+
+const FNV_OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013B;
+/// Suffix of files being rendered, ignored by the cache
+const TMP_SUFFIX: &str = ".tmp.wav";
+/// Files being rendered for longer are left by a process which exited
+const TMP_MAX_AGE_SECS: u64 = 300;
+
+/// Makes names of files being rendered unique in this process
+static RENDERING: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * Name of the cached file of a sentence. Everything changing the audio is part of it.
+ * @param config how the sentence is said
+ * @param text to say
+ * @return a 128 bits FNV-1a hash, in hex
+ */
+pub fn cache_key(config: &TtsConfig, text: &str) -> String {
+    let content = format!("{}\0{}\0{}\0{}\0{}\0{}", config.engine, config.voice,
+                          config.rate, config.pitch, config.volume, text);
+    let mut hash = FNV_OFFSET;
+    for byte in content.bytes() {
+        hash ^= u128::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    format!("{:032x}", hash)
+}
+
+/**
+ * A file being rendered, removed when the rendering is dropped
+ * without being moved in the cache (stopped or failed)
+ */
+struct TmpFile(PathBuf);
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if self.0.exists() {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
+
+/**
+ * WAV files of sentences already said, so they are not synthesized again
+ */
+#[derive(Clone)]
+pub struct SpeechCache {
+    dir: PathBuf,
+    config: TtsConfig,
+}
+
+impl SpeechCache {
+    /**
+     * @param dir where files are written, created when needed
+     * @param config how sentences are said
+     * @return a SpeechCache
+     */
+    pub fn new(dir: &Path, config: &TtsConfig) -> SpeechCache {
+        SpeechCache {
+            dir: dir.to_path_buf(),
+            config: config.clone(),
+        }
+    }
+
+    /**
+     * The file is touched, so sentences often said are the last to be removed
+     * @param self
+     * @param text said
+     * @return the file of the sentence if it is cached
+     */
+    pub fn get(&self, text: &str) -> Option<PathBuf> {
+        let path = self.dir.join(format!("{}.wav", cache_key(&self.config, text)));
+        if !path.is_file() {
+            return None;
+        }
+        let touched = OpenOptions::new().append(true).open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        if let Err(e) = touched {
+            warn!("Can't touch {} in the speech cache: {}", path.display(), e);
+        }
+        Some(path)
+    }
+
+    /**
+     * Begin to render a sentence in the cache. The file is only added
+     * when the rendering succeeds, so concurrent renderings of a sentence
+     * each write their own temporary file. The temporary file is removed
+     * if the rendering is stopped or fails.
+     * @param self
+     * @param synthesizer engine described by the configuration of the cache
+     * @param text to render
+     * @return the rendering, and the file it will create
+     */
+    pub fn render(&self, synthesizer: &dyn SpeechSynthesizer, text: &str) -> Result<(Speech, PathBuf), String> {
+        fs::create_dir_all(&self.dir).map_err(|e| format!("can't create {}: {}", self.dir.display(), e))?;
+        let key = cache_key(&self.config, text);
+        let path = self.dir.join(format!("{}.wav", key));
+        let tmp = TmpFile(self.dir.join(format!("{}.{}-{}{}", key, process::id(),
+                                                RENDERING.fetch_add(1, Ordering::SeqCst), TMP_SUFFIX)));
+        let rendered = path.clone();
+        let cache = self.clone();
+        let speech = synthesizer.render(text, &tmp.0)?.then(move || {
+            fs::rename(&tmp.0, &rendered).map_err(|e| format!("can't write {}: {}", rendered.display(), e))?;
+            cache.prune();
+            Ok(Speech::done())
+        });
+        Ok((speech, path))
+    }
+
+    /**
+     * Get the file of a sentence, rendered now if needed
+     * @param self
+     * @param text to render
+     * @return the WAV file
+     */
+    pub fn audio(&self, text: &str) -> Result<PathBuf, String> {
+        if let Some(path) = self.get(text) {
+            return Ok(path);
+        }
+        let (speech, path) = self.render(&*tts::synthesizer(&self.config, ""), text)?;
+        speech.wait()?;
+        Ok(path)
+    }
+
+    /**
+     * Remove the least recently used files beyond MAX_CACHED_FILES,
+     * and files left by renderings which never ended
+     * @param self
+     */
+    fn prune(&self) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let mut files = Vec::new();
+        for (modified, path) in entries.filter_map(|e| e.ok())
+            .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path()))) {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            if name.ends_with(TMP_SUFFIX) {
+                let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+                if age.as_secs() > TMP_MAX_AGE_SECS {
+                    info!("Remove {}, left by an interrupted rendering", path.display());
+                    let _ = fs::remove_file(&path);
+                }
+            } else if name.ends_with(".wav") {
+                files.push((modified, path));
+            }
+        }
+        if files.len() <= MAX_CACHED_FILES {
+            return;
+        }
+        files.sort();
+        for &(_, ref path) in files.iter().take(files.len() - MAX_CACHED_FILES) {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Can't remove {} from the speech cache: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/**
+ * Say sentences from the cache, rendering them first if needed
+ */
+pub struct CachedSynthesizer {
+    engine: Box<dyn SpeechSynthesizer>,
+    cache: SpeechCache,
+}
+
+impl CachedSynthesizer {
+    /**
+     * @param engine to render sentences
+     * @param cache where sentences are kept
+     * @return a CachedSynthesizer
+     */
+    pub fn new(engine: Box<dyn SpeechSynthesizer>, cache: SpeechCache) -> CachedSynthesizer {
+        CachedSynthesizer {
+            engine,
+            cache,
+        }
+    }
+}
+
+impl SpeechSynthesizer for CachedSynthesizer {
+    fn name(&self) -> &str {
+        self.engine.name()
+    }
+
+    fn start(&self, text: &str) -> Result<Speech, String> {
+        if let Some(path) = self.cache.get(text) {
+            debug!("Said from the cache: {}", text);
+            return tts::play(&path);
+        }
+        match self.cache.render(&*self.engine, text) {
+            Ok((speech, path)) => Ok(speech.then(move || tts::play(&path))),
+            Err(e) => {
+                warn!("Can't render \"{}\", said directly: {}", text, e);
+                self.engine.start(text)
+            }
+        }
+    }
+
+    fn render(&self, text: &str, path: &Path) -> Result<Speech, String> {
+        self.engine.render(text, path)
+    }
+
+    fn supports_ssml(&self) -> bool {
+        self.engine.supports_ssml()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rori::config::Config;
+    use std::env;
+    use std::time::Duration;
+
+    /**
+     * Writes the text as audio
+     */
+    struct FakeEngine;
+
+    impl SpeechSynthesizer for FakeEngine {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn start(&self, _: &str) -> Result<Speech, String> {
+            Ok(Speech::done())
+        }
+
+        fn render(&self, text: &str, path: &Path) -> Result<Speech, String> {
+            fs::write(path, text).map_err(|e| e.to_string())?;
+            Ok(Speech::done())
+        }
+    }
+
+    /**
+     * Writes part of the audio, then fails
+     */
+    struct FailingEngine;
+
+    impl SpeechSynthesizer for FailingEngine {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn start(&self, _: &str) -> Result<Speech, String> {
+            Err(String::from("can't start"))
+        }
+
+        fn render(&self, text: &str, path: &Path) -> Result<Speech, String> {
+            fs::write(path, text).map_err(|e| e.to_string())?;
+            Err(String::from("exited with 1"))
+        }
+    }
+
+    fn cache(name: &str) -> SpeechCache {
+        let dir = env::temp_dir().join(format!("rori-speech-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        SpeechCache::new(&dir, &Config::default().tts)
+    }
+
+    fn age(path: &Path, secs: u64) {
+        let file = OpenOptions::new().append(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(secs)).unwrap();
+    }
+
+    #[test]
+    fn concurrent_renderings_do_not_collide() {
+        let cache = cache("concurrent");
+        let (first, path) = cache.render(&FakeEngine, "hello").unwrap();
+        let (second, other) = cache.render(&FakeEngine, "hello").unwrap();
+        assert_eq!(path, other);
+        // Both temporary files exist before being moved
+        let rendering = fs::read_dir(&cache.dir).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(TMP_SUFFIX))
+            .count();
+        assert_eq!(rendering, 2);
+        first.wait().unwrap();
+        second.wait().unwrap();
+        assert_eq!(fs::read_dir(&cache.dir).unwrap().count(), 1);
+        assert_eq!(cache.get("hello"), Some(path));
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn sentences_said_again_are_kept() {
+        let cache = cache("prune");
+        for idx in 0..MAX_CACHED_FILES {
+            let text = format!("sentence {}", idx);
+            cache.render(&FakeEngine, &text).unwrap().0.wait().unwrap();
+            age(&cache.get(&text).unwrap(), 1000 - idx as u64);
+        }
+        // The oldest sentence is said again, the next one becomes the oldest
+        let said = cache.get("sentence 0").unwrap();
+        cache.render(&FakeEngine, "new sentence").unwrap().0.wait().unwrap();
+        assert_eq!(fs::read_dir(&cache.dir).unwrap().count(), MAX_CACHED_FILES);
+        assert!(said.is_file());
+        assert!(cache.get("sentence 1").is_none());
+        assert!(cache.get("new sentence").is_some());
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+    #[test]
+    fn interrupted_rendering_leaves_no_file() {
+        let cache = cache("interrupted");
+        let (mut speech, path) = cache.render(&FakeEngine, "hello").unwrap();
+        speech.stop();
+        drop(speech);
+        assert!(!path.is_file());
+        assert_eq!(fs::read_dir(&cache.dir).unwrap().count(), 0);
+
+        assert!(cache.render(&FailingEngine, "hello").is_err());
+        assert_eq!(fs::read_dir(&cache.dir).unwrap().count(), 0);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn old_renderings_are_pruned() {
+        let cache = cache("stale");
+        fs::create_dir_all(&cache.dir).unwrap();
+        let stale = cache.dir.join(format!("0.1-0{}", TMP_SUFFIX));
+        let running = cache.dir.join(format!("0.1-1{}", TMP_SUFFIX));
+        fs::write(&stale, "").unwrap();
+        fs::write(&running, "").unwrap();
+        age(&stale, TMP_MAX_AGE_SECS + 60);
+        cache.render(&FakeEngine, "hello").unwrap().0.wait().unwrap();
+        assert!(!stale.exists());
+        assert!(running.exists());
+        assert!(cache.get("hello").is_some());
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
@@ -25,25 +25,80 @@
  * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  **/
 
+use rori::event::{Event, EventBus, Source};
+use rori::login::LoginState;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::thread;
 use qmlrs;
 
 
+ /**
+  * State shown by the QML interface, updated from the event bus
+  */
  pub struct SharedProp {
      pub rori_text: Arc<Mutex<String>>,
-     pub user_text: Arc<Mutex<String>>,
      pub api_text: Arc<Mutex<String>>,
      pub is_listening: Arc<Mutex<bool>>,
-     pub logged: Arc<Mutex<bool>>
+     pub logged: Arc<Mutex<bool>>,
+     bus: EventBus,
  }
 
  impl SharedProp {
+     /**
+      * Create the state and follow events from the bus
+      * @param bus to follow
+      * @return a SharedProp
+      */
+     pub fn new(bus: EventBus) -> SharedProp {
+         let shared_prop = SharedProp {
+             rori_text: Arc::new(Mutex::new(String::new())),
+             api_text: Arc::new(Mutex::new(String::new())),
+             is_listening: Arc::new(Mutex::new(false)),
+             logged: Arc::new(Mutex::new(false)),
+             bus: bus.clone(),
+         };
+         let rori_text = shared_prop.rori_text.clone();
+         let api_text = shared_prop.api_text.clone();
+         let is_listening = shared_prop.is_listening.clone();
+         let logged = shared_prop.logged.clone();
+         let events = bus.subscribe();
+         thread::spawn(move || {
+             let mut logged_profiles = HashMap::new();
+             for event in events {
+                 match event {
+                     Event::RoriSpeech(text) => *rori_text.lock().unwrap() = text,
+                     Event::UserUtterance(text, source) => {
+                         // Show what was said from other sources in the text field
+                         if source != Source::Ui {
+                             *api_text.lock().unwrap() = text;
+                         }
+                     },
+                     Event::ListeningChanged(listening) => *is_listening.lock().unwrap() = listening,
+                     Event::LoginStateChanged(profile, state) => {
+                         // Logged while at least one profile is logged
+                         logged_profiles.insert(profile, state);
+                         *logged.lock().unwrap() = logged_profiles.values().any(LoginState::is_logged);
+                     },
+                     // Already said by RORI
+                     Event::DeviceUpdate(..) => {},
+                     Event::StopSpeaking => {},
+                 }
+             }
+         });
+         shared_prop
+     }
+
      fn set_api_text(&self, text: String) {
          *self.api_text.lock().unwrap() = text;
      }
 
      fn set_user_text(&self, text: String) {
-         *self.user_text.lock().unwrap() = text;
+         self.bus.publish(Event::UserUtterance(text, Source::Ui));
+     }
+
+     fn stop_speaking(&self) {
+         self.bus.publish(Event::StopSpeaking);
      }
 
      fn get_api_text(&self) -> String {
@@ -66,6 +121,7 @@ use qmlrs;
  Q_OBJECT! { SharedProp:
      slot fn set_api_text(String);
      slot fn set_user_text(String);
+     slot fn stop_speaking();
      slot fn get_api_text();
      slot fn get_rori_text();
      slot fn get_logged();
@@ -0,0 +1,342 @@
+/**
+ * Copyright (c) 2019 Sébastien Blin <sebastien.blin@enconn.fr>
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * * Redistributions of source code must retain the above copyright
+ *  notice, this list of conditions and the following disclaimer.
+ * * Redistributions in binary form must reproduce the above copyright
+ *  notice, this list of conditions and the following disclaimer in the
+ *  documentation and/or other materials provided with the distribution.
+ * * Neither the name of the University of California, Berkeley nor the
+ *  names of its contributors may be used to endorse or promote products
+ *  derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE REGENTS AND CONTRIBUTORS ``AS IS'' AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS AND CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ **/
+
+use libc;
+use rori::event::{Event, EventBus, Source};
+use rori::login::LoginState;
+use rori::shutdown::ShutdownToken;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::mpsc::Receiver;
+
+// Lines kept in the conversation
+const HISTORY_SIZE: usize = 500;
+
+/**
+ * Put the terminal in raw mode on an alternate screen, restored when dropped
+ */
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    /**
+     * @return a RawTerminal, or an error if stdin is not a terminal
+     */
+    fn enable() -> io::Result<RawTerminal> {
+        let mut original: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        // Ctrl-C is read as a key, to restore the terminal before quitting
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        // read() returns after 100ms without key
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 1;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        print!("\x1b[?1049h");
+        Ok(RawTerminal {
+            original,
+        })
+    }
+
+    /**
+     * @return (rows, columns) of the terminal
+     */
+    fn size() -> (usize, usize) {
+        let mut ws: libc::winsize = unsafe { mem::zeroed() };
+        let res = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) };
+        if res == 0 && ws.ws_row > 0 && ws.ws_col > 0 {
+            (ws.ws_row as usize, ws.ws_col as usize)
+        } else {
+            (24, 80)
+        }
+    }
+
+    /**
+     * Read the keys pressed, waits 100ms at most
+     * @return bytes read
+     */
+    fn read_keys() -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let len = unsafe {
+            libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+        };
+        if len <= 0 {
+            return Vec::new();
+        }
+        buf[..len as usize].to_vec()
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?1049l");
+        let _ = io::stdout().flush();
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.original);
+        }
+    }
+}
+
+/**
+ * Terminal interface. Shows the same state as the QML interface
+ * with the conversation, and sends what is typed to RORI.
+ */
+pub struct Tui {
+    bus: EventBus,
+    events: Receiver<Event>,
+    rori_text: String,
+    /// Login state of each profile, "" for the top level identity
+    logged: BTreeMap<String, LoginState>,
+    is_listening: bool,
+    history: Vec<String>,
+    input: String,
+    // Incomplete UTF-8 character
+    pending: Vec<u8>,
+}
+
+impl Tui {
+    /**
+     * Follow events from the bus. Must be created before the login to see everything.
+     * @param bus to follow
+     * @return a Tui
+     */
+    pub fn new(bus: EventBus) -> Tui {
+        let events = bus.subscribe();
+        Tui {
+            bus,
+            events,
+            rori_text: String::new(),
+            logged: BTreeMap::new(),
+            is_listening: false,
+            history: Vec::new(),
+            input: String::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /**
+     * Show the interface until the user quits (Ctrl-C, Ctrl-D or /quit) or a shutdown is requested
+     * @param self
+     * @param shutdown requested when the user quits
+     */
+    pub fn run(&mut self, shutdown: ShutdownToken) {
+        let terminal = match RawTerminal::enable() {
+            Ok(terminal) => terminal,
+            Err(e) => {
+                error!("Can't start the terminal interface: {}", e);
+                shutdown.request();
+                return;
+            }
+        };
+        let mut size = (0, 0);
+        while !shutdown.is_requested() {
+            let mut changed = false;
+            for key in RawTerminal::read_keys() {
+                changed = true;
+                if !self.on_key(key) {
+                    shutdown.request();
+                }
+            }
+            while let Ok(event) = self.events.try_recv() {
+                changed = true;
+                self.on_event(event);
+            }
+            let new_size = RawTerminal::size();
+            if changed || new_size != size {
+                size = new_size;
+                self.draw(size);
+            }
+        }
+        drop(terminal);
+    }
+
+    /**
+     * Handle a key pressed
+     * @param self
+     * @param key byte read
+     * @return false if the user wants to quit
+     */
+    fn on_key(&mut self, key: u8) -> bool {
+        match key {
+            // Ctrl-C
+            3 => return false,
+            // Ctrl-D
+            4 => return !self.input.is_empty(),
+            b'\r' | b'\n' => {
+                let text = self.input.trim().to_string();
+                self.input.clear();
+                if text == "/quit" {
+                    return false;
+                }
+                if !text.is_empty() {
+                    self.bus.publish(Event::UserUtterance(text, Source::Terminal));
+                }
+            },
+            // Backspace
+            8 | 127 => {
+                self.pending.clear();
+                self.input.pop();
+            },
+            // Escape sequences (arrows...) are ignored, their content is printable
+            27 => self.pending.clear(),
+            key if key >= 0x20 => {
+                self.pending.push(key);
+                let complete = match String::from_utf8(self.pending.clone()) {
+                    Ok(c) => {
+                        self.input.push_str(&c);
+                        true
+                    },
+                    Err(_) => self.pending.len() >= 4
+                };
+                if complete {
+                    self.pending.clear();
+                }
+            },
+            _ => {}
+        }
+        true
+    }
+
+    /**
+     * Update the state from an event
+     * @param self
+     * @param event received
+     */
+    fn on_event(&mut self, event: Event) {
+        match event {
+            Event::RoriSpeech(text) => {
+                if !text.is_empty() {
+                    self.push_history(format!("RORI: {}", text));
+                }
+                self.rori_text = text;
+            },
+            Event::UserUtterance(text, _) => self.push_history(format!("You: {}", text)),
+            Event::ListeningChanged(listening) => self.is_listening = listening,
+            Event::LoginStateChanged(profile, state) => {
+                self.logged.insert(profile, state);
+            },
+            // Already said by RORI
+            Event::DeviceUpdate(..) | Event::StopSpeaking => {},
+        }
+    }
+
+    fn push_history(&mut self, line: String) {
+        self.history.push(line);
+        if self.history.len() > HISTORY_SIZE {
+            let extra = self.history.len() - HISTORY_SIZE;
+            self.history.drain(..extra);
+        }
+    }
+
+    /**
+     * Redraw the whole screen
+     * @param self
+     * @param size (rows, columns) of the terminal
+     */
+    fn draw(&self, size: (usize, usize)) {
+        let (rows, cols) = size;
+        let mut screen = String::from("\x1b[2J\x1b[H");
+        // Status bar
+        let logged = match self.logged.len() {
+            0 => String::from("not logged"),
+            1 if self.logged.contains_key("") => self.logged[""].to_string(),
+            _ => self.logged.iter()
+                .map(|(profile, state)| format!("{}: {}", if profile == "" { "default" } else { profile }, state))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        let status = format!(" RORI | {} | {}", logged,
+                             if self.is_listening { "listening..." } else { "not listening" });
+        screen += &format!("\x1b[7m{}\x1b[0m\r\n", Tui::fit(&status, cols, true));
+        // Current sentence
+        screen += &format!("\x1b[1m{}\x1b[0m\r\n", Tui::fit(&self.rori_text, cols, false));
+        screen += &format!("{}\r\n", "-".repeat(cols));
+        // Conversation, newest at the bottom
+        let height = rows.saturating_sub(4);
+        let mut lines: Vec<String> = Vec::new();
+        for entry in self.history.iter().rev() {
+            if lines.len() >= height {
+                break;
+            }
+            let mut wrapped = Tui::wrap(entry, cols);
+            wrapped.reverse();
+            lines.extend(wrapped);
+        }
+        lines.truncate(height);
+        for _ in lines.len()..height {
+            screen += "\r\n";
+        }
+        for line in lines.iter().rev() {
+            screen += &format!("{}\r\n", line);
+        }
+        // Input line, shows the end of long entries
+        let prompt = "> ";
+        let visible = cols.saturating_sub(prompt.len() + 1);
+        let count = self.input.chars().count();
+        let input: String = self.input.chars().skip(count.saturating_sub(visible)).collect();
+        screen += &format!("{}{}", prompt, input);
+        print!("{}", screen);
+        let _ = io::stdout().flush();
+    }
+
+    /**
+     * Cut or pad a line to the width of the terminal
+     * @param text to fit
+     * @param cols width
+     * @param pad if spaces must be added
+     * @return the line
+     */
+    fn fit(text: &str, cols: usize, pad: bool) -> String {
+        let mut line: String = text.chars().filter(|c| !c.is_control()).take(cols).collect();
+        if pad {
+            let len = line.chars().count();
+            line += &" ".repeat(cols - len);
+        }
+        line
+    }
+
+    /**
+     * Split a history entry in lines of the width of the terminal
+     * @param text to split
+     * @param cols width
+     * @return lines
+     */
+    fn wrap(text: &str, cols: usize) -> Vec<String> {
+        let chars: Vec<char> = text.chars().filter(|c| !c.is_control()).collect();
+        if chars.is_empty() {
+            return vec![String::new()];
+        }
+        chars.chunks(cols.max(1)).map(|c| c.iter().collect()).collect()
+    }
+}
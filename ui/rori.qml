@@ -190,6 +190,10 @@ ApplicationWindow {
         sharedprop.set_api_text("")
       }
 
+      Keys.onEscapePressed: {
+        sharedprop.stop_speaking()
+      }
+
       Keys.onReturnPressed: {
         if (!logged) {
           unshowRORIText.start()
@@ -220,7 +224,6 @@ ApplicationWindow {
           var api_text = sharedprop.get_api_text()
           if (api_text != textUser.text && api_text != "") {
             textUser.text = api_text
-            sharedprop.set_user_text(api_text)
           }
 
           is_listening = sharedprop.get_is_listening()